@@ -2,7 +2,9 @@ use futures::executor::block_on;
 use janus::app;
 use janus::app::App;
 use janus::pipeline::gbuffer::*;
-use janus::pipeline::lighting::LightingPipeline;
+use janus::pipeline::lighting::{LightingPipeline, Lights, PointLight};
+use janus::pipeline::shadow::*;
+use janus::scene::*;
 use janus::voxel_data::*;
 use janus::Context;
 use std::time::*;
@@ -19,12 +21,12 @@ struct Application {
     fin_pipe: LightingPipeline,
     gbuf: GBuffer,
     gbuf_bind: wgpu::BindGroup,
-    voxels: VoxelBuffer,
-    uniforms: Uniforms,
-    ubuf: wgpu::Buffer,
-    ubind: wgpu::BindGroup,
-    //textures: Textures,
-    tex_bind: wgpu::BindGroup,
+    shadow_pipe: ShadowPipeline,
+    shadows: ShadowMaps,
+    lights: Lights,
+    lights_bind: wgpu::BindGroup,
+    scene: Scene,
+    root: NodeId,
     last_time: Instant,
     pos: Vec3,
     is_rotating: bool,
@@ -38,31 +40,43 @@ impl Application {
         let fin_pipe = LightingPipeline::new(&ctx);
         let gbuf_pipe = GBufferPipeline::new(&ctx);
         let gbuf = GBuffer::new(&ctx.device, 1920, 1080);
+        let shadow_pipe = ShadowPipeline::new(&gbuf_pipe, &ctx);
+        let shadows = ShadowMaps::new(ShadowSettings::default(), &shadow_pipe, &ctx);
 
-        let uni = Uniforms {
-            view_proj: ultraviolet::Mat4::identity(),
-            model: ultraviolet::Mat4::identity(),
-        };
-        let ubuf = uni.buffer(&ctx.device);
-        let ubind = gbuf_pipe.bind_uniform(&ubuf, &ctx.device);
-
-        let voxels = VoxelBuffer::from_txt(include_str!("link.txt"), &ctx);
-
-        let tex_bind = gbuf_pipe.bind_textures(voxels.textures(), &ctx.device);
+        // one model drawn three times, the copies follow the root around
+        let mut scene = Scene::new();
+        let link = scene.add_model(VoxelBuffer::from_txt(include_str!("link.txt"), &ctx));
+        let root = scene.add_node(None, Transform::default(), Some(link));
+        for &x in &[-30.0, 30.0] {
+            scene.add_node(
+                Some(root),
+                Transform::from_translation(Vec3::new(x, 0.0, 0.0)),
+                Some(link),
+            );
+        }
 
         let app = Self {
             gbuf_pipe,
             gbuf_bind: fin_pipe.bind_gbuffer(&gbuf, &ctx.device),
+            lights_bind: fin_pipe.bind_lights(&shadows, &ctx.device),
+            shadow_pipe,
+            shadows,
+            // a warm lamp in front of the models
+            lights: Lights {
+                points: vec![PointLight {
+                    position: Vec3::new(0.0, 20.0, -15.0),
+                    color: Vec3::new(1.0, 0.7, 0.4),
+                    range: 40.0,
+                    ..PointLight::default()
+                }],
+                ..Lights::default()
+            },
             fin_pipe,
             gbuf,
             ctx,
-            voxels,
-            uniforms: uni,
-            ubuf,
-            ubind,
+            scene,
+            root,
             window,
-            //textures,
-            tex_bind,
             last_time: Instant::now(),
             pos: Vec3::new(0.0, 10.0, -50.0),
             is_rotating: false,
@@ -111,35 +125,28 @@ impl App for Application {
         camera.zfar = f32::MAX;
         camera.aspect = self.ctx.size().0 as f32 / self.ctx.size().1 as f32;
 
-        self.uniforms.view_proj = camera.proj();
-        self.uniforms.view_proj = self.uniforms.view_proj;
-        self.uniforms.model = Mat4::from_rotation_y(-90.0f32.to_radians());
+        let mut angle = -90.0f32.to_radians();
         if self.is_rotating {
-            self.uniforms.model =
-                Mat4::from_rotation_y(self.last_time.elapsed().as_millis() as f32 / 1_000.0)
-                    * self.uniforms.model;
+            angle += self.last_time.elapsed().as_millis() as f32 / 1_000.0;
         }
-        self.uniforms.update_buffer(&self.ubuf, &self.ctx.queue);
-
-        let (vbuf, ibuf) = self.voxels.buffers();
+        self.scene.transform_mut(self.root).rotation = Rotor3::from_euler_angles(0.0, 0.0, angle);
+        self.scene.update(camera.proj(), &self.gbuf_pipe, &self.ctx);
+        self.fin_pipe
+            .update(&self.lights, &camera, &mut self.shadows, &self.ctx);
 
         let mut encoder = self.ctx.encoder();
+        self.shadows
+            .render_scene(&self.shadow_pipe, &self.scene, &mut encoder);
         {
             let mut rpass = self.gbuf.render(&mut encoder);
-            self.gbuf_pipe.render_ind(
-                vbuf,
-                ibuf,
-                self.voxels.index_count(),
-                &self.ubind,
-                &self.tex_bind,
-                &mut rpass,
-            );
+            self.gbuf_pipe.render_scene(&self.scene, &mut rpass);
         }
 
         let frame = self.ctx.next_frame();
         {
             let mut rpass = self.ctx.render_pass(&mut encoder, &frame);
-            self.fin_pipe.render(&self.gbuf_bind, &mut rpass);
+            self.fin_pipe
+                .render(&self.gbuf_bind, &self.lights_bind, None, None, &mut rpass);
         }
         self.ctx.run_encoder(encoder);
         std::thread::sleep(Duration::from_millis(16));
@@ -3,17 +3,35 @@
 layout(location=0) out vec4 g_pos;
 layout(location=1) out vec4 g_norm;
 layout(location=2) out vec4 g_col;
+layout(location=3) out vec4 g_emit;
 
-layout(location=0) in vec2 a_uv;
-layout(location=1) in vec3 l_pos;
-layout(location=2) in mat4 mvp;
+layout(location=0) in vec3 a_uv;
+layout(location=1) in vec2 a_ao;
+layout(location=2) flat in uint a_palette;
+layout(location=3) flat in vec3 a_face;
+layout(location=4) flat in vec3 a_normal;
+layout(location=5) in vec2 a_light;
 
-layout(set=1, binding=0) uniform sampler2D diffuse;
+layout(set=1, binding=0) uniform usampler3D indices;
+layout(set=1, binding=1) uniform sampler2D palette;
+layout(set=1, binding=2) uniform usampler2D ramp_ids;
 
 void main() {
-    vec3 f_pos = vec3(floor(l_pos.x), floor(l_pos.y), floor(l_pos.z));
-    vec4 pos = mvp * vec4(f_pos, 1.0);
-    g_pos = pos;
-    g_norm = vec4(0.0, 1.0, 1.0, 0.0);
-    g_col = texture(diffuse, a_uv);
+    // baked block light and sky light ride along in the spare channels
+    g_pos = vec4(a_face, a_light.y);
+    g_norm = vec4(a_normal, a_light.x);
+    // baked occlusion goes in the alpha channel, snapped to steps if the mesh asks for it
+    float ao = a_ao.x;
+    if (a_ao.y > 0.0) {
+        ao = round(ao * a_ao.y) / a_ao.y;
+    }
+    // uv is the center of this voxel's texel, integer textures can only be fetched
+    ivec3 voxel = ivec3(a_uv * vec3(textureSize(indices, 0)));
+    uint index = texelFetch(indices, voxel, 0).r;
+    vec4 color = texelFetch(palette, ivec2(index, a_palette), 0);
+    g_col = vec4(color.rgb, ao);
+    // glowing colors keep 1 / (1 + strength) in alpha, see `Palette::texels_with`, and the
+    // ramp to shade with rides along in the spare channel
+    uint ramp = texelFetch(ramp_ids, ivec2(index, a_palette), 0).r;
+    g_emit = vec4(color.rgb * (1.0 / max(color.a, 1.0 / 255.0) - 1.0), float(ramp));
 }
@@ -1,11 +1,23 @@
 # version 450
 
 layout(location=0) in vec3 pos;
-layout(location=1) in vec2 uv;
+layout(location=1) in vec3 uv;
+layout(location=2) in vec2 ao;
+layout(location=3) in vec3 face;
+layout(location=4) in vec3 normal;
+layout(location=5) in vec2 light;
+// per instance, the matrix takes locations 6 to 9
+layout(location=6) in mat4 i_model;
+layout(location=10) in uint i_palette;
 
-layout(location=0) out vec2 a_uv;
-layout(location=1) out vec3 l_pos;
-layout(location=2) out mat4 o_mvp;
+layout(location=0) out vec3 a_uv;
+layout(location=1) out vec2 a_ao;
+layout(location=2) flat out uint a_palette;
+// every pixel of a voxel face is lit at the center of the face
+layout(location=3) flat out vec3 a_face;
+layout(location=4) flat out vec3 a_normal;
+// baked sky and block light, smooth across the face like occlusion
+layout(location=5) out vec2 a_light;
 
 layout(set=0, binding=0)
     uniform Uniforms {
@@ -15,13 +27,12 @@ layout(set=0, binding=0)
 
 void main() {
     a_uv = uv;
-    l_pos = pos;
-    o_mvp = model;
-    mat4 id = mat4(
-            vec4(1.0, 0.0, 0.0, 0.0),
-            vec4(0.0, 1.0, 0.0, 0.0),
-            vec4(0.0, 0.0, 1.0, 0.0),
-            vec4(0.0, 0.0, 0.0, 1.0)
-            );
-    gl_Position = view_proj * model * vec4(pos, 1.0) ; 
+    a_ao = ao;
+    a_light = light;
+    a_palette = i_palette;
+    mat4 world = model * i_model;
+    a_face = (world * vec4(face, 1.0)).xyz;
+    // the inverse transpose keeps normals perpendicular under non-uniform scales
+    a_normal = normalize(transpose(inverse(mat3(world))) * normal);
+    gl_Position = view_proj * world * vec4(pos, 1.0);
 }
@@ -1,31 +1,391 @@
 #version 450
 
+#define MAX_CASCADES 4
+#define MAX_SPOT_LIGHTS 4
+#define MAX_POINT_LIGHTS 16
+#define MAX_POINT_SHADOWS 4
+#define RAMP_SHADES 16
+
 layout(location=0) out vec4 o_col;
 
 layout(set=0, binding=0) uniform sampler2D g_pos;
 layout(set=0, binding=1) uniform sampler2D g_norm;
 layout(set=0, binding=2) uniform sampler2D g_col;
+layout(set=0, binding=3) uniform sampler2D g_depth;
+layout(set=0, binding=4) uniform sampler2D g_emit;
 layout(location=0) in vec2 a_pos;
 
+struct Spot {
+    mat4 view_proj;
+    // xyz position, w range
+    vec4 position;
+    // xyz direction, w cosine of the outer angle
+    vec4 direction;
+    // rgb color, w cosine of the inner angle
+    vec4 color;
+    // x layer of the shadow map, negative without shadows
+    vec4 shadow;
+};
+
+struct Point {
+    // xyz position, w range
+    vec4 position;
+    // rgb color, w slot of the shadow map, negative without shadows
+    vec4 color;
+};
+
+layout(set=1, binding=0)
+    uniform Lights {
+        mat4 inv_view_proj;
+        mat4 cascades[MAX_CASCADES];
+        // view distance where each cascade ends
+        vec4 splits;
+        // xyz camera position, w number of cascades
+        vec4 eye;
+        // rgb ambient light, w number of spot lights
+        vec4 ambient;
+        // xyz direction the sun shines, w number of point lights
+        vec4 sun_dir;
+        vec4 sun_color;
+        // x pcf radius, y depth bias, w 1 to snap lookups to voxels
+        vec4 filtering;
+        // rgb color of block light at full strength, w world units a voxel covers
+        vec4 block_color;
+        Spot spots[MAX_SPOT_LIGHTS];
+        Point points[MAX_POINT_LIGHTS];
+        // six faces per shadow slot, +x, -x, +y, -y, +z and -z
+        mat4 point_faces[MAX_POINT_SHADOWS * 6];
+    };
+layout(set=1, binding=1) uniform sampler2DArray sun_maps;
+layout(set=1, binding=2) uniform sampler2DArray spot_maps;
+layout(set=1, binding=3) uniform sampler2DArray point_maps;
+// RAMP_SHADES shades per row, row id - 1 for ramp id
+layout(set=1, binding=4) uniform sampler2D ramps;
+
+layout(set=2, binding=0)
+    uniform Volume {
+        // xyz lowest corner of the occupancy volume, w size of a cell
+        vec4 volume;
+        // x 1 to trace shadows, y number of ao rays, z ao distance, w furthest shadow ray
+        vec4 trace;
+        // x 1 to trace from voxel centers, y ao strength
+        vec4 trace_from;
+    };
+layout(set=2, binding=1) uniform usampler3D occupancy;
+
+layout(set=3, binding=0)
+    uniform Gi {
+        // xyz lowest corner of the gi volume, w size of a cell
+        vec4 gi_volume;
+        // xyz cells along each axis, w what the stored light was divided by
+        vec4 gi_dims;
+        // x number of cones, y aperture, z strength, w furthest a cone goes
+        vec4 gi_diffuse;
+        // x 1 to trace reflections, y aperture, z strength, w most samples per cone
+        vec4 gi_reflect;
+    };
+// light leaving each cell premultiplied by how solid it is, mipmapped and laid out z-major
+layout(set=3, binding=1) uniform texture3D gi_radiance;
+layout(set=3, binding=2) uniform sampler gi_sampler;
+
+#define SUN_MAP 0
+#define SPOT_MAP 1
+#define POINT_MAP 2
+
+float map_depth(int map, ivec3 texel) {
+    if (map == SPOT_MAP) {
+        return texelFetch(spot_maps, texel, 0).r;
+    } else if (map == POINT_MAP) {
+        return texelFetch(point_maps, texel, 0).r;
+    }
+    return texelFetch(sun_maps, texel, 0).r;
+}
+
+ivec2 map_size(int map) {
+    if (map == SPOT_MAP) {
+        return textureSize(spot_maps, 0).xy;
+    } else if (map == POINT_MAP) {
+        return textureSize(point_maps, 0).xy;
+    }
+    return textureSize(sun_maps, 0).xy;
+}
+
+// fraction of the texels around `clip` that see past it, clip is in normalized device space
+float sample_shadow(int map, int layer, vec3 clip) {
+    if (any(greaterThan(abs(clip.xy), vec2(1.0))) || clip.z < 0.0 || clip.z > 1.0) {
+        return 1.0;
+    }
+    ivec2 size = map_size(map);
+    // device y points up, texture rows go down
+    vec2 uv = vec2(clip.x, -clip.y) * 0.5 + 0.5;
+    ivec2 center = ivec2(uv * vec2(size));
+    int radius = int(filtering.x);
+    float lit = 0.0;
+    for (int x = -radius; x <= radius; x++) {
+        for (int y = -radius; y <= radius; y++) {
+            ivec2 texel = clamp(center + ivec2(x, y), ivec2(0), size - 1);
+            float depth = map_depth(map, ivec3(texel, layer));
+            lit += clip.z - filtering.y <= depth ? 1.0 : 0.0;
+        }
+    }
+    float taps = float((2 * radius + 1) * (2 * radius + 1));
+    return lit / taps;
+}
+
+float sun_shadow(vec3 pos) {
+    float dist = length(pos - eye.xyz);
+    for (int i = 0; i < int(eye.w); i++) {
+        if (dist < splits[i]) {
+            vec4 clip = cascades[i] * vec4(pos, 1.0);
+            return sample_shadow(SUN_MAP, i, clip.xyz / clip.w);
+        }
+    }
+    // past the last cascade
+    return 1.0;
+}
+
+bool occupied(ivec3 cell) {
+    // z-major like the models' index textures
+    ivec3 size = textureSize(occupancy, 0).zyx;
+    if (any(lessThan(cell, ivec3(0))) || any(greaterThanEqual(cell, size))) {
+        return false;
+    }
+    return texelFetch(occupancy, cell.zyx, 0).r != 0u;
+}
+
+// whether anything solid is within `dist` of `from` along `dir`, walking the occupancy volume
+// one cell at a time
+bool trace_ray(vec3 from, vec3 dir, float dist) {
+    vec3 size = vec3(textureSize(occupancy, 0).zyx);
+    vec3 start = (from - volume.xyz) / volume.w;
+    float len = dist / volume.w;
+    // keep the walk away from dividing by zero
+    dir = vec3(
+        abs(dir.x) < 1e-6 ? 1e-6 : dir.x,
+        abs(dir.y) < 1e-6 ? 1e-6 : dir.y,
+        abs(dir.z) < 1e-6 ? 1e-6 : dir.z
+    );
+    vec3 inv = 1.0 / dir;
+
+    // only the part of the ray inside the volume
+    vec3 t0 = -start * inv;
+    vec3 t1 = (size - start) * inv;
+    vec3 near = min(t0, t1);
+    vec3 far = max(t0, t1);
+    float enter = max(max(near.x, near.y), max(near.z, 0.0));
+    float exit = min(min(far.x, far.y), min(far.z, len));
+    if (enter >= exit) {
+        return false;
+    }
+
+    vec3 pos = start + dir * enter;
+    ivec3 cell = clamp(ivec3(floor(pos)), ivec3(0), ivec3(size) - 1);
+    ivec3 step = ivec3(sign(dir));
+    vec3 delta = abs(inv);
+    // distance along the ray to the next cell on every axis
+    vec3 next = (vec3(cell) + max(vec3(step), 0.0) - pos) * inv;
+    float t = enter;
+    for (int i = 0; i < 1024 && t < exit; i++) {
+        if (occupied(cell)) {
+            return true;
+        }
+        if (next.x < next.y && next.x < next.z) {
+            t = enter + next.x;
+            next.x += delta.x;
+            cell.x += step.x;
+        } else if (next.y < next.z) {
+            t = enter + next.y;
+            next.y += delta.y;
+            cell.y += step.y;
+        } else {
+            t = enter + next.z;
+            next.z += delta.z;
+            cell.z += step.z;
+        }
+    }
+    return false;
+}
+
+// whether a light at `light_pos` reaches `from`, the light's own cell doesn't block it
+bool trace_light(vec3 from, vec3 light_pos) {
+    vec3 to_light = light_pos - from;
+    float dist = length(to_light);
+    return !trace_ray(from, to_light / dist, dist - volume.w);
+}
+
+// the fraction of cosine weighted rays around the normal that get away
+float trace_ao(vec3 from, vec3 normal) {
+    int rays = int(trace.y);
+    if (rays == 0) {
+        return 1.0;
+    }
+    vec3 tangent = normalize(cross(normal, abs(normal.y) < 0.99 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0)));
+    vec3 bitangent = cross(normal, tangent);
+    float hits = 0.0;
+    for (int i = 0; i < rays; i++) {
+        // spread over the hemisphere by the golden angle
+        float u = (float(i) + 0.5) / float(rays);
+        float phi = float(i) * 2.399963;
+        float r = sqrt(u);
+        vec3 dir = tangent * cos(phi) * r + bitangent * sin(phi) * r + normal * sqrt(1.0 - u);
+        if (trace_ray(from, dir, trace.z)) {
+            hits += 1.0;
+        }
+    }
+    return 1.0 - trace_from.y * hits / float(rays);
+}
+
+// light gathered along a cone through the gi volume, sampling coarser mips as it widens
+vec3 trace_cone(vec3 from, vec3 dir, float aperture) {
+    float cell = gi_volume.w;
+    vec3 size = gi_dims.xyz * cell;
+    vec4 gathered = vec4(0.0);
+    // start a cell out so the surface doesn't see itself
+    float dist = cell;
+    for (int i = 0; i < int(gi_reflect.w); i++) {
+        if (dist > gi_diffuse.w || gathered.a > 0.95) {
+            break;
+        }
+        float diameter = max(cell, 2.0 * aperture * dist);
+        vec3 uvw = (from + dir * dist - gi_volume.xyz) / size;
+        if (any(lessThan(uvw, vec3(0.0))) || any(greaterThan(uvw, vec3(1.0)))) {
+            break;
+        }
+        vec4 sampled = textureLod(sampler3D(gi_radiance, gi_sampler), uvw.zyx, log2(diameter / cell));
+        gathered += (1.0 - gathered.a) * sampled;
+        dist += diameter * 0.5;
+    }
+    return gathered.rgb * gi_dims.w;
+}
+
+// bounce light from cones spread over the hemisphere around the normal
+vec3 trace_diffuse(vec3 from, vec3 normal) {
+    int cones = int(gi_diffuse.x);
+    if (cones == 0) {
+        return vec3(0.0);
+    }
+    vec3 tangent = normalize(cross(normal, abs(normal.y) < 0.99 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0)));
+    vec3 bitangent = cross(normal, tangent);
+    vec3 light = vec3(0.0);
+    float weight = 0.0;
+    for (int i = 0; i < cones; i++) {
+        // the first cone looks straight out, the rest lean out around it
+        vec3 dir = normal;
+        if (i > 0) {
+            float phi = float(i) * 6.2831853 / float(cones - 1);
+            dir = normalize(normal + tangent * cos(phi) + bitangent * sin(phi));
+        }
+        float cosine = dot(dir, normal);
+        light += trace_cone(from, dir, gi_diffuse.y) * cosine;
+        weight += cosine;
+    }
+    return light / weight * gi_diffuse.z;
+}
+
+// looks up the face of the cube the position falls on
+float point_shadow(int slot, vec3 light_pos, vec3 pos) {
+    vec3 dir = pos - light_pos;
+    vec3 size = abs(dir);
+    int face;
+    if (size.x >= size.y && size.x >= size.z) {
+        face = dir.x > 0.0 ? 0 : 1;
+    } else if (size.y >= size.z) {
+        face = dir.y > 0.0 ? 2 : 3;
+    } else {
+        face = dir.z > 0.0 ? 4 : 5;
+    }
+    int layer = slot * 6 + face;
+    vec4 clip = point_faces[layer] * vec4(pos, 1.0);
+    return sample_shadow(POINT_MAP, layer, clip.xyz / clip.w);
+}
+
 void main() {
-    //vec2 pos = vec2(clamp(a_pos.x, 0.0, 1.0), clamp(a_pos.y, 0.0, 1.0));
-    
-    vec3 light_pos = vec3(0.0, 0.0, -20.0);
-    vec2 pos = (a_pos + vec2(1.0, 1.0)) / 2.0;
-    pos = pos * textureSize(g_col, 0);
-    vec3 col = texture(g_col, pos).rgb;
-    vec3 lpos = texture(g_pos, pos).rgb;
-    
-    float dist = length(lpos - light_pos);
-    float power = 90000.0 / (dist * dist * dist);
-
-
-    power = power / 100;
-    //power = clamp(floor(power * 3) / 3, 0.0, 1.0);
-    //power = clamp(round(100 * power), 0, 5) / 5;
-    //power += 0.001;
-    o_col = vec4(col * power, 1.0);
-    //o_col = vec4(dist, dist, dist, 1.0);
-    //o_col = vec4((lpos-light_pos) / 20.0, 1.0);
-    //o_col = vec4(1.0, 1.0, 1.0, 0.0);
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    vec4 albedo = texelFetch(g_col, pixel, 0);
+    vec3 col = albedo.rgb * albedo.a;
+    float depth = texelFetch(g_depth, pixel, 0).r;
+    if (depth == 1.0) {
+        // nothing was drawn here, only depth tells as the color targets clear to opaque black
+        o_col = vec4(0.0, 0.0, 0.0, 1.0);
+        return;
+    }
+    // w holds the baked block light, the normal's w the baked sky light
+    vec4 face = texelFetch(g_pos, pixel, 0);
+    vec4 normal_sky = texelFetch(g_norm, pixel, 0);
+    vec3 normal = normal_sky.xyz;
+
+    // lights are aimed at the face center so every face is lit evenly, shadows and the volumes
+    // are looked up either in the open voxel in front of the face or just off the exact pixel
+    float voxel = block_color.w;
+    vec3 at_voxel = face.xyz + normal * 0.5 * voxel;
+    vec2 ndc = (gl_FragCoord.xy / vec2(textureSize(g_depth, 0))) * 2.0 - 1.0;
+    vec4 world = inv_view_proj * vec4(ndc.x, -ndc.y, depth, 1.0);
+    vec3 at_pixel = world.xyz / world.w + normal * 0.05 * voxel;
+    vec3 lookup = filtering.w > 0.0 ? at_voxel : at_pixel;
+    // rays through the occupancy volume replace the shadow maps if it traces shadows
+    bool traced = trace.x > 0.0;
+    vec3 ray_from = trace_from.x > 0.0 ? at_voxel : at_pixel;
+
+    // the sky only lights what it can reach, caves are left to block light
+    vec3 baked = ambient.rgb * normal_sky.w + block_color.rgb * face.w;
+    vec3 light = baked * trace_ao(ray_from, normal);
+    // cones start from the open voxel in front of the face so they don't begin inside it
+    vec3 cone_from = at_voxel + normal * gi_volume.w;
+    light += trace_diffuse(cone_from, normal);
+    float sun = max(dot(normal, -sun_dir.xyz), 0.0);
+    if (sun > 0.0) {
+        if (traced) {
+            sun *= trace_ray(ray_from, -sun_dir.xyz, trace.w) ? 0.0 : 1.0;
+        } else {
+            sun *= sun_shadow(lookup);
+        }
+        light += sun_color.rgb * sun;
+    }
+    for (int i = 0; i < int(ambient.w); i++) {
+        Spot spot = spots[i];
+        vec3 to_light = spot.position.xyz - face.xyz;
+        float dist = length(to_light);
+        to_light /= dist;
+        float cone = smoothstep(spot.direction.w, spot.color.w, dot(-to_light, spot.direction.xyz));
+        float falloff = clamp(1.0 - dist / spot.position.w, 0.0, 1.0);
+        float power = max(dot(normal, to_light), 0.0) * cone * falloff * falloff;
+        if (power > 0.0 && traced) {
+            power *= trace_light(ray_from, spot.position.xyz) ? 1.0 : 0.0;
+        } else if (power > 0.0 && spot.shadow.x >= 0.0) {
+            vec4 clip = spot.view_proj * vec4(lookup, 1.0);
+            power *= sample_shadow(SPOT_MAP, int(spot.shadow.x), clip.xyz / clip.w);
+        }
+        light += spot.color.rgb * power;
+    }
+    for (int i = 0; i < int(sun_dir.w); i++) {
+        Point point = points[i];
+        vec3 to_light = point.position.xyz - face.xyz;
+        float dist = length(to_light);
+        float falloff = clamp(1.0 - dist / point.position.w, 0.0, 1.0);
+        float power = max(dot(normal, to_light / dist), 0.0) * falloff * falloff;
+        if (power > 0.0 && traced) {
+            power *= trace_light(ray_from, point.position.xyz) ? 1.0 : 0.0;
+        } else if (power > 0.0 && point.color.w >= 0.0) {
+            power *= point_shadow(int(point.color.w), point.position.xyz, lookup);
+        }
+        light += point.color.rgb * power;
+    }
+    vec3 reflection = vec3(0.0);
+    if (gi_reflect.x > 0.0) {
+        vec3 view = normalize(at_pixel - eye.xyz);
+        reflection = trace_cone(cone_from, reflect(view, normal), gi_reflect.y) * gi_reflect.z;
+    }
+    // glowing voxels shine by themselves, whatever light reaches them
+    vec4 emit = texelFetch(g_emit, pixel, 0);
+    vec3 lit = col * light;
+    int ramp = int(emit.a + 0.5);
+    if (ramp > 0) {
+        // ramped colors take the shade for how much light reaches them, the light only tints it
+        vec3 reaching = light * albedo.a;
+        float strength = max(max(reaching.r, reaching.g), reaching.b);
+        int shade = clamp(int(strength * float(RAMP_SHADES)), 0, RAMP_SHADES - 1);
+        vec3 tint = reaching / max(strength, 0.0001) * max(strength, 1.0);
+        lit = texelFetch(ramps, ivec2(shade, ramp - 1), 0).rgb * tint;
+    }
+    o_col = vec4(lit + reflection * albedo.a + emit.rgb, 1.0);
 }
@@ -0,0 +1,93 @@
+#version 450
+
+// walks the voxel grid along the view ray (Amanatides & Woo) and fills the gbuffer like the
+// meshed path does
+
+layout(location=0) out vec4 g_pos;
+layout(location=1) out vec4 g_norm;
+layout(location=2) out vec4 g_col;
+layout(location=3) out vec4 g_emit;
+
+layout(location=0) in vec4 a_clip;
+layout(location=1) flat in mat4 inv_mvp;
+
+layout(set=0, binding=0)
+    uniform Uniforms {
+        mat4 view_proj;
+        mat4 model;
+    };
+
+layout(set=1, binding=0) uniform usampler3D indices;
+layout(set=1, binding=1) uniform sampler2D palette;
+layout(set=1, binding=2) uniform usampler2D ramp_ids;
+
+void main() {
+    ivec3 dims = textureSize(indices, 0).zyx;
+
+    // the view ray in voxel space, unprojected from the near plane and a point past it so
+    // infinite far planes work too
+    vec2 ndc = a_clip.xy / a_clip.w;
+    vec4 near = inv_mvp * vec4(ndc, 0.0, 1.0);
+    vec4 mid = inv_mvp * vec4(ndc, 0.5, 1.0);
+    vec3 origin = near.xyz / near.w;
+    vec3 dir = normalize(mid.xyz / mid.w - origin);
+    dir = mix(dir, vec3(1e-6), equal(dir, vec3(0.0)));
+    vec3 inv_dir = 1.0 / dir;
+
+    // where the ray enters and leaves the model's bounds
+    vec3 t0 = -origin * inv_dir;
+    vec3 t1 = (vec3(dims) - origin) * inv_dir;
+    vec3 t_min = min(t0, t1);
+    vec3 t_max = max(t0, t1);
+    float enter = max(max(t_min.x, t_min.y), max(t_min.z, 0.0));
+    float leave = min(min(t_max.x, t_max.y), t_max.z);
+    if (enter > leave) {
+        discard;
+    }
+
+    vec3 start = origin + dir * enter;
+    ivec3 voxel = clamp(ivec3(floor(start)), ivec3(0), dims - 1);
+    ivec3 step = ivec3(sign(dir));
+    vec3 delta = abs(inv_dir);
+    // distance along the ray to the next voxel boundary on each axis
+    vec3 side = (vec3(voxel) + max(vec3(step), 0.0) - origin) * inv_dir;
+    // the face the ray came in through
+    int axis = t_min.x > t_min.y ? (t_min.x > t_min.z ? 0 : 2) : (t_min.y > t_min.z ? 1 : 2);
+    float t = enter;
+
+    for (int i = 0; i < dims.x + dims.y + dims.z; i++) {
+        uint index = texelFetch(indices, voxel.zyx, 0).r;
+        // invisible colors have an alpha of 0, like the meshed path skips them
+        vec4 color = texelFetch(palette, ivec2(index, 0), 0);
+        if (color.a > 0.0) {
+            vec3 normal = vec3(0.0);
+            normal[axis] = -float(step[axis]);
+            vec4 clip = view_proj * model * vec4(origin + dir * t, 1.0);
+            gl_FragDepth = clip.z / clip.w;
+            // lit at the center of the face like the meshed path
+            // under open sky with no block light, like meshes outside a world
+            g_pos = vec4((model * vec4(vec3(voxel) + 0.5 + normal * 0.5, 1.0)).xyz, 0.0);
+            g_norm = vec4(normalize(transpose(inverse(mat3(model))) * normal), 1.0);
+            g_col = vec4(color.rgb, 1.0);
+            // glowing colors keep 1 / (1 + strength) in alpha, see `Palette::texels_with`
+            uint ramp = texelFetch(ramp_ids, ivec2(index, 0), 0).r;
+            g_emit = vec4(color.rgb * (1.0 / max(color.a, 1.0 / 255.0) - 1.0), float(ramp));
+            return;
+        }
+
+        if (side.x < side.y && side.x < side.z) {
+            axis = 0;
+        } else if (side.y < side.z) {
+            axis = 1;
+        } else {
+            axis = 2;
+        }
+        voxel[axis] += step[axis];
+        t = side[axis];
+        side[axis] += delta[axis];
+        if (voxel[axis] < 0 || voxel[axis] >= dims[axis]) {
+            break;
+        }
+    }
+    discard;
+}
@@ -0,0 +1,24 @@
+#version 450
+
+// a corner of the unit cube, stretched over the model's bounds
+layout(location=0) in vec3 pos;
+
+layout(location=0) out vec4 a_clip;
+layout(location=1) flat out mat4 inv_mvp;
+
+layout(set=0, binding=0)
+    uniform Uniforms {
+        mat4 view_proj;
+        mat4 model;
+    };
+
+layout(set=1, binding=0) uniform usampler3D indices;
+
+void main() {
+    // the texture is z-major, see `VoxelBuffer`
+    vec3 dims = vec3(textureSize(indices, 0).zyx);
+    mat4 mvp = view_proj * model;
+    gl_Position = mvp * vec4(pos * dims, 1.0);
+    a_clip = gl_Position;
+    inv_mvp = inverse(mvp);
+}
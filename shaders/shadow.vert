@@ -0,0 +1,22 @@
+#version 450
+
+layout(location=0) in vec3 pos;
+// per instance, the matrix takes locations 6 to 9
+layout(location=6) in mat4 i_model;
+
+// the gbuffer's uniforms, only the model matrix is used here
+layout(set=0, binding=0)
+    uniform Uniforms {
+        mat4 view_proj;
+        mat4 model;
+    };
+
+// the light's view and projection
+layout(set=1, binding=0)
+    uniform Pass {
+        mat4 light_view_proj;
+    };
+
+void main() {
+    gl_Position = light_view_proj * model * i_model * vec4(pos, 1.0);
+}
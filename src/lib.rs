@@ -3,7 +3,10 @@ pub mod app;
 mod camera;
 mod context;
 pub mod pipeline;
+pub mod rig;
+pub mod scene;
 pub mod voxel_data;
+pub mod world;
 
 pub use camera::*;
 pub use context::*;
@@ -1,6 +1,12 @@
 pub mod deferred;
 pub mod gbuffer;
+pub mod gi;
+pub mod gpu_mesh;
+pub mod grid;
 pub mod lighting;
+pub mod occupancy;
+pub mod raymarch;
+pub mod shadow;
 
 // ok here is my thoughts on the pipeline
 //
@@ -1,4 +1,8 @@
 use crate::include_shader;
+use crate::pipeline::gpu_mesh::GpuMesh;
+use crate::scene::Scene;
+use crate::voxel_data::ramp::RAMP_SHADES;
+use crate::voxel_data::VoxelBuffer;
 use std::mem;
 use ultraviolet::*;
 use wgpu::util::DeviceExt;
@@ -11,6 +15,9 @@ pub struct GBuffer {
     pub normals_tex: Texture,
     pub color_view: TextureView,
     pub color_tex: Texture,
+    /// Light glowing voxels give off by themselves
+    pub emission_view: TextureView,
+    pub emission_tex: Texture,
     pub depth_tex: Texture,
     pub depth_view: TextureView,
 }
@@ -20,6 +27,7 @@ impl GBuffer {
         let (position_tex, position_view) = create_tex(device, width, height);
         let (normals_tex, normals_view) = create_tex(device, width, height);
         let (color_tex, color_view) = create_tex(device, width, height);
+        let (emission_tex, emission_view) = create_tex(device, width, height);
         let (depth_tex, depth_view) = create_depth_tex(device, width, height);
 
         Self {
@@ -29,6 +37,8 @@ impl GBuffer {
             normals_view,
             color_tex,
             color_view,
+            emission_tex,
+            emission_view,
             depth_tex,
             depth_view,
         }
@@ -61,6 +71,14 @@ impl GBuffer {
                         store: true,
                     },
                 },
+                RenderPassColorAttachmentDescriptor {
+                    attachment: &self.emission_view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(Color::BLACK),
+                        store: true,
+                    },
+                },
             ],
             depth_stencil_attachment: Some(RenderPassDepthStencilAttachmentDescriptor {
                 attachment: &self.depth_view,
@@ -74,20 +92,85 @@ impl GBuffer {
     }
 }
 
+/// Textures a model is drawn with, a volume of palette indices and the palette they index
 pub struct Textures {
-    pub diffuse_view: TextureView,
-    pub diffuse_tex: Texture,
+    pub index_view: TextureView,
+    pub index_tex: Texture,
+    pub palette_view: TextureView,
+    pub palette_tex: Texture,
+    pub ramp_view: TextureView,
+    pub ramp_tex: Texture,
 }
 
 #[derive(Debug)]
 pub struct TextureData<'a> {
-    pub texels: &'a [u8],
-    pub dim: [u32; 2],
+    texels: &'a [u8],
+    dim: [u32; 3],
+    format: TextureFormat,
+    dimension: TextureDimension,
 }
 
 impl<'a> TextureData<'a> {
-    pub fn new(texels: &'a [u8], dim: [u32; 2]) -> Self {
-        Self { texels, dim }
+    // only the formats `texel_size` knows about are reachable, through the constructors below
+    fn new(
+        texels: &'a [u8],
+        dim: [u32; 3],
+        format: TextureFormat,
+        dimension: TextureDimension,
+    ) -> Self {
+        Self {
+            texels,
+            dim,
+            format,
+            dimension,
+        }
+    }
+
+    /// A volume of palette indices
+    pub fn indices(texels: &'a [u8], dim: [u32; 3]) -> Self {
+        Self::new(texels, dim, TextureFormat::R8Uint, TextureDimension::D3)
+    }
+
+    /// Rows of 256 rgba palette colors, one per palette an instance can pick
+    pub fn palette(texels: &'a [u8], rows: u32) -> Self {
+        Self::new(
+            texels,
+            [256, rows, 1],
+            TextureFormat::Rgba8UnormSrgb,
+            TextureDimension::D2,
+        )
+    }
+
+    /// Rows of 256 ramp ids, one per palette row, see `Ramps::id`
+    pub fn ramp_ids(texels: &'a [u8], rows: u32) -> Self {
+        Self::new(
+            texels,
+            [256, rows, 1],
+            TextureFormat::R8Uint,
+            TextureDimension::D2,
+        )
+    }
+
+    /// Rows of `RAMP_SHADES` rgba shades, one per ramp, see `Ramps::texels`
+    pub fn ramps(texels: &'a [u8], rows: u32) -> Self {
+        Self::new(
+            texels,
+            [RAMP_SHADES as u32, rows, 1],
+            TextureFormat::Rgba8UnormSrgb,
+            TextureDimension::D2,
+        )
+    }
+
+    /// A volume of linear rgba colors
+    pub fn radiance(texels: &'a [u8], dim: [u32; 3]) -> Self {
+        Self::new(texels, dim, TextureFormat::Rgba8Unorm, TextureDimension::D3)
+    }
+
+    fn texel_size(&self) -> u32 {
+        match self.format {
+            TextureFormat::R8Uint => 1,
+            _ => 4,
+        }
     }
 
     pub fn create(&self, ctx: &crate::Context) -> (Texture, TextureView) {
@@ -96,45 +179,70 @@ impl<'a> TextureData<'a> {
             size: Extent3d {
                 width: self.dim[0],
                 height: self.dim[1],
-                depth: 1,
+                depth: self.dim[2],
             },
             mip_level_count: 1,
             sample_count: 1,
-            dimension: TextureDimension::D2,
-            format: TextureFormat::Rgba8UnormSrgb,
+            dimension: self.dimension,
+            format: self.format,
             usage: TextureUsage::SAMPLED | TextureUsage::COPY_DST,
         });
 
         let view = tex.create_view(&TextureViewDescriptor::default());
 
+        self.write(&tex, [0, 0, 0], ctx);
+        (tex, view)
+    }
+
+    /// Writes these texels into a region of an existing texture starting at `origin`
+    pub fn write(&self, tex: &Texture, origin: [u32; 3], ctx: &crate::Context) {
+        self.write_mip(tex, 0, origin, ctx);
+    }
+
+    /// Like `write` but into a smaller mip level of the texture
+    pub fn write_mip(&self, tex: &Texture, level: u32, origin: [u32; 3], ctx: &crate::Context) {
         ctx.queue.write_texture(
             TextureCopyView {
-                texture: &tex,
-                mip_level: 0,
-                origin: Origin3d::ZERO,
+                texture: tex,
+                mip_level: level,
+                origin: Origin3d {
+                    x: origin[0],
+                    y: origin[1],
+                    z: origin[2],
+                },
             },
-            &self.texels,
+            self.texels,
             TextureDataLayout {
                 offset: 0,
-                bytes_per_row: 4 * self.dim[0],
+                bytes_per_row: self.texel_size() * self.dim[0],
                 rows_per_image: self.dim[1],
             },
             Extent3d {
                 width: self.dim[0],
                 height: self.dim[1],
-                depth: 1,
+                depth: self.dim[2],
             },
         );
-        (tex, view)
     }
 }
 
 impl Textures {
-    pub fn new<'a>(diffuse: TextureData<'a>, ctx: &crate::Context) -> Textures {
-        let (diffuse_tex, diffuse_view) = diffuse.create(ctx);
+    pub fn new<'a>(
+        indices: TextureData<'a>,
+        palette: TextureData<'a>,
+        ramp_ids: TextureData<'a>,
+        ctx: &crate::Context,
+    ) -> Textures {
+        let (index_tex, index_view) = indices.create(ctx);
+        let (palette_tex, palette_view) = palette.create(ctx);
+        let (ramp_tex, ramp_view) = ramp_ids.create(ctx);
         Self {
-            diffuse_tex,
-            diffuse_view,
+            index_tex,
+            index_view,
+            palette_tex,
+            palette_view,
+            ramp_tex,
+            ramp_view,
         }
     }
 }
@@ -143,6 +251,8 @@ pub struct GBufferPipeline {
     pub pipeline: RenderPipeline,
     uniform_layout: BindGroupLayout,
     tex_layout: BindGroupLayout,
+    // a single untransformed instance for the non-instanced draws
+    identity: InstanceBuffer,
 }
 
 impl GBufferPipeline {
@@ -155,9 +265,10 @@ impl GBufferPipeline {
         rpass: &mut RenderPass<'a>,
     ) {
         rpass.set_pipeline(&self.pipeline);
-        rpass.set_bind_group(0, uniforms, &[]);
+        rpass.set_bind_group(0, uniforms, &[0]);
         rpass.set_bind_group(1, textures, &[]);
         rpass.set_vertex_buffer(0, vbuf.slice(..));
+        rpass.set_vertex_buffer(1, self.identity.buffer.slice(..));
         rpass.draw(0..vcnt, 0..1);
     }
 
@@ -169,13 +280,105 @@ impl GBufferPipeline {
         uniforms: &'a BindGroup,
         textures: &'a BindGroup,
         rpass: &mut RenderPass<'a>,
+    ) {
+        let identity = &self.identity;
+        self.render_ind_instanced(vbuf, ibuf, icnt, identity, uniforms, textures, rpass);
+    }
+
+    /// Draws a mesh once per instance in a single draw call, each instance is placed by its own
+    /// matrix on top of the uniform model matrix
+    #[allow(clippy::too_many_arguments)]
+    pub fn render_ind_instanced<'a>(
+        &'a self,
+        vbuf: &'a Buffer,
+        ibuf: &'a Buffer,
+        icnt: u32,
+        instances: &'a InstanceBuffer,
+        uniforms: &'a BindGroup,
+        textures: &'a BindGroup,
+        rpass: &mut RenderPass<'a>,
     ) {
         rpass.set_pipeline(&self.pipeline);
-        rpass.set_bind_group(0, uniforms, &[]);
+        rpass.set_bind_group(0, uniforms, &[0]);
         rpass.set_bind_group(1, textures, &[]);
         rpass.set_index_buffer(ibuf.slice(..));
         rpass.set_vertex_buffer(0, vbuf.slice(..));
-        rpass.draw_indexed(0..icnt, 0, 0..1);
+        rpass.set_vertex_buffer(1, instances.buffer.slice(..));
+        rpass.draw_indexed(0..icnt, 0, 0..instances.len());
+    }
+
+    pub fn render_voxels<'a>(
+        &'a mut self,
+        voxels: &'a VoxelBuffer,
+        uniforms: &'a BindGroup,
+        textures: &'a BindGroup,
+        rpass: &mut RenderPass<'a>,
+    ) {
+        let identity = &self.identity;
+        self.render_voxels_instanced(voxels, identity, uniforms, textures, rpass);
+    }
+
+    /// Draws every instance of a model with one draw call per sub-chunk
+    pub fn render_voxels_instanced<'a>(
+        &'a self,
+        voxels: &'a VoxelBuffer,
+        instances: &'a InstanceBuffer,
+        uniforms: &'a BindGroup,
+        textures: &'a BindGroup,
+        rpass: &mut RenderPass<'a>,
+    ) {
+        if instances.is_empty() {
+            return;
+        }
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, uniforms, &[0]);
+        rpass.set_bind_group(1, textures, &[]);
+        rpass.set_vertex_buffer(1, instances.buffer.slice(..));
+        for (vbuf, ibuf, icnt) in voxels.meshes() {
+            rpass.set_index_buffer(ibuf.slice(..));
+            rpass.set_vertex_buffer(0, vbuf.slice(..));
+            rpass.draw_indexed(0..icnt, 0, 0..instances.len());
+        }
+    }
+
+    /// Draws a model meshed by the compute shader, with as many vertices as it found
+    pub fn render_gpu_mesh<'a>(
+        &'a self,
+        mesh: &'a GpuMesh,
+        uniforms: &'a BindGroup,
+        textures: &'a BindGroup,
+        rpass: &mut RenderPass<'a>,
+    ) {
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, uniforms, &[0]);
+        rpass.set_bind_group(1, textures, &[]);
+        rpass.set_vertex_buffer(0, mesh.vertices().slice(..));
+        rpass.set_vertex_buffer(1, self.identity.buffer.slice(..));
+        rpass.draw_indirect(mesh.args(), 0);
+    }
+
+    /// Draws every node of a scene as of its last `Scene::update`
+    pub fn render_scene<'a>(&'a mut self, scene: &'a Scene, rpass: &mut RenderPass<'a>) {
+        let (uniforms, draws) = match scene.draws() {
+            Some(draws) => draws,
+            None => return,
+        };
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_vertex_buffer(1, self.identity.buffer.slice(..));
+        let mut bound: Option<&BindGroup> = None;
+        for (voxels, textures, offset) in draws {
+            // draws are grouped by model so textures only change between models
+            if bound.map_or(true, |b| !std::ptr::eq(b, textures)) {
+                rpass.set_bind_group(1, textures, &[]);
+                bound = Some(textures);
+            }
+            rpass.set_bind_group(0, uniforms, &[offset]);
+            for (vbuf, ibuf, icnt) in voxels.meshes() {
+                rpass.set_index_buffer(ibuf.slice(..));
+                rpass.set_vertex_buffer(0, vbuf.slice(..));
+                rpass.draw_indexed(0..icnt, 0, 0..1);
+            }
+        }
     }
 
     pub fn new(ctx: &crate::Context) -> Self {
@@ -185,16 +388,41 @@ impl GBufferPipeline {
             .device
             .create_bind_group_layout(&BindGroupLayoutDescriptor {
                 label: None,
-                entries: &[BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStage::FRAGMENT,
-                    ty: BindingType::SampledTexture {
-                        dimension: TextureViewDimension::D2,
-                        component_type: TextureComponentType::Float,
-                        multisampled: false,
+                entries: &[
+                    // palette indices, the raymarcher sizes its bounds from them
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStage::VERTEX | ShaderStage::FRAGMENT,
+                        ty: BindingType::SampledTexture {
+                            dimension: TextureViewDimension::D3,
+                            component_type: TextureComponentType::Uint,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    // palette
+                    BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: ShaderStage::FRAGMENT,
+                        ty: BindingType::SampledTexture {
+                            dimension: TextureViewDimension::D2,
+                            component_type: TextureComponentType::Float,
+                            multisampled: false,
+                        },
+                        count: None,
                     },
-                    count: None,
-                }],
+                    // ramp ids
+                    BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: ShaderStage::FRAGMENT,
+                        ty: BindingType::SampledTexture {
+                            dimension: TextureViewDimension::D2,
+                            component_type: TextureComponentType::Uint,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                ],
             });
 
         let layout = ctx
@@ -246,6 +474,13 @@ impl GBufferPipeline {
                         color_blend: BlendDescriptor::REPLACE,
                         write_mask: ColorWrite::ALL,
                     },
+                    // emission
+                    ColorStateDescriptor {
+                        format: TextureFormat::Rgba16Float,
+                        alpha_blend: BlendDescriptor::REPLACE,
+                        color_blend: BlendDescriptor::REPLACE,
+                        write_mask: ColorWrite::ALL,
+                    },
                 ],
                 depth_stencil_state: Some(DepthStencilStateDescriptor {
                     format: TextureFormat::Depth32Float,
@@ -255,7 +490,7 @@ impl GBufferPipeline {
                 }),
                 vertex_state: VertexStateDescriptor {
                     index_format: IndexFormat::Uint16,
-                    vertex_buffers: &[Vertex::desc()],
+                    vertex_buffers: &[Vertex::desc(), Instance::desc()],
                 },
                 sample_count: 1,
                 sample_mask: !0,
@@ -265,16 +500,22 @@ impl GBufferPipeline {
             pipeline,
             uniform_layout,
             tex_layout,
+            identity: InstanceBuffer::new(&[Instance::default()], ctx),
         }
     }
 
+    // uniform and texture layouts, shared with other pipelines drawing into the gbuffer
+    pub(crate) fn layouts(&self) -> (&BindGroupLayout, &BindGroupLayout) {
+        (&self.uniform_layout, &self.tex_layout)
+    }
+
     pub fn bind_uniform(&self, uniforms: &Buffer, device: &Device) -> BindGroup {
         device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &self.uniform_layout,
             entries: &[BindGroupEntry {
                 binding: 0,
-                resource: BindingResource::Buffer(uniforms.slice(..)),
+                resource: BindingResource::Buffer(uniforms.slice(..Uniforms::size().get())),
             }],
         })
     }
@@ -283,10 +524,20 @@ impl GBufferPipeline {
         device.create_bind_group(&BindGroupDescriptor {
             label: Some("gbuffer bind group"),
             layout: &self.tex_layout,
-            entries: &[BindGroupEntry {
-                binding: 0,
-                resource: BindingResource::TextureView(&textures.diffuse_view),
-            }],
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&textures.index_view),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(&textures.palette_view),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::TextureView(&textures.ramp_view),
+                },
+            ],
         })
     }
 }
@@ -294,14 +545,26 @@ impl GBufferPipeline {
 #[derive(Debug)]
 pub struct Vertex {
     pub pos: Vec3,
-    pub uv: Vec2,
+    pub uv: Vec3,
+    /// Baked ambient occlusion, x is the light reaching this corner and y is the number of steps
+    /// to quantize it to, 0 for smooth
+    pub ao: Vec2,
+    /// Center of the voxel face this corner belongs to, where the face gets lit
+    pub face: Vec3,
+    pub normal: Vec3,
+    /// Sky and block light baked in by a `VoxelWorld` from 0 to 1, open sky everywhere else
+    pub light: Vec2,
 }
 
 impl Vertex {
-    pub const fn new(x: f32, y: f32, z: f32, u: f32, v: f32) -> Self {
+    pub const fn new(x: f32, y: f32, z: f32, u: f32, v: f32, w: f32) -> Self {
         Self {
             pos: Vec3::new(x, y, z),
-            uv: Vec2::new(u, v),
+            uv: Vec3::new(u, v, w),
+            ao: Vec2::new(1.0, 0.0),
+            face: Vec3::new(x, y, z),
+            normal: Vec3::new(0.0, 0.0, 1.0),
+            light: Vec2::new(1.0, 0.0),
         }
     }
 
@@ -317,9 +580,29 @@ impl Vertex {
                 },
                 VertexAttributeDescriptor {
                     offset: mem::size_of::<Vec3>() as BufferAddress,
-                    format: VertexFormat::Float2,
+                    format: VertexFormat::Float3,
                     shader_location: 1,
                 },
+                VertexAttributeDescriptor {
+                    offset: (mem::size_of::<Vec3>() * 2) as BufferAddress,
+                    format: VertexFormat::Float2,
+                    shader_location: 2,
+                },
+                VertexAttributeDescriptor {
+                    offset: (mem::size_of::<Vec3>() * 2 + mem::size_of::<Vec2>()) as BufferAddress,
+                    format: VertexFormat::Float3,
+                    shader_location: 3,
+                },
+                VertexAttributeDescriptor {
+                    offset: (mem::size_of::<Vec3>() * 3 + mem::size_of::<Vec2>()) as BufferAddress,
+                    format: VertexFormat::Float3,
+                    shader_location: 4,
+                },
+                VertexAttributeDescriptor {
+                    offset: (mem::size_of::<Vec3>() * 4 + mem::size_of::<Vec2>()) as BufferAddress,
+                    format: VertexFormat::Float2,
+                    shader_location: 5,
+                },
             ],
         }
     }
@@ -328,6 +611,10 @@ impl Vertex {
         let mut data = vec![];
         data.extend_from_slice(self.pos.as_byte_slice());
         data.extend_from_slice(self.uv.as_byte_slice());
+        data.extend_from_slice(self.ao.as_byte_slice());
+        data.extend_from_slice(self.face.as_byte_slice());
+        data.extend_from_slice(self.normal.as_byte_slice());
+        data.extend_from_slice(self.light.as_byte_slice());
         data
     }
 
@@ -349,6 +636,125 @@ impl Vertex {
     }
 }
 
+/// One copy of a mesh drawn by the instanced render methods
+#[derive(Debug, Copy, Clone)]
+pub struct Instance {
+    /// Placed after the uniform model matrix
+    pub model: Mat4,
+    /// Row of the palette texture to color with, 0 is the model's own palette and higher ones
+    /// are the variants added with `VoxelBuffer::add_variant`
+    pub palette: u32,
+}
+
+impl Default for Instance {
+    fn default() -> Self {
+        Self {
+            model: Mat4::identity(),
+            palette: 0,
+        }
+    }
+}
+
+impl Instance {
+    pub const fn desc() -> VertexBufferDescriptor<'static> {
+        const COLUMN: BufferAddress = mem::size_of::<Vec4>() as BufferAddress;
+        VertexBufferDescriptor {
+            stride: mem::size_of::<Self>() as BufferAddress,
+            step_mode: InputStepMode::Instance,
+            // a mat4 takes up one location per column, after the locations of `Vertex`
+            attributes: &[
+                VertexAttributeDescriptor {
+                    offset: 0,
+                    format: VertexFormat::Float4,
+                    shader_location: 6,
+                },
+                VertexAttributeDescriptor {
+                    offset: COLUMN,
+                    format: VertexFormat::Float4,
+                    shader_location: 7,
+                },
+                VertexAttributeDescriptor {
+                    offset: COLUMN * 2,
+                    format: VertexFormat::Float4,
+                    shader_location: 8,
+                },
+                VertexAttributeDescriptor {
+                    offset: COLUMN * 3,
+                    format: VertexFormat::Float4,
+                    shader_location: 9,
+                },
+                VertexAttributeDescriptor {
+                    offset: COLUMN * 4,
+                    format: VertexFormat::Uint,
+                    shader_location: 10,
+                },
+            ],
+        }
+    }
+
+    pub fn data(&self) -> Vec<u8> {
+        let mut data = vec![];
+        data.extend_from_slice(self.model.as_byte_slice());
+        data.extend_from_slice(&self.palette.to_le_bytes());
+        data
+    }
+}
+
+/// Per-instance data on the gpu, meant to be refilled every frame
+pub struct InstanceBuffer {
+    buffer: Buffer,
+    // in instances
+    capacity: usize,
+    count: u32,
+}
+
+impl InstanceBuffer {
+    pub fn new(instances: &[Instance], ctx: &crate::Context) -> Self {
+        let capacity = instances.len().next_power_of_two();
+        let mut buffer = Self {
+            buffer: Self::create(capacity, &ctx.device),
+            capacity,
+            count: 0,
+        };
+        buffer.update(instances, ctx);
+        buffer
+    }
+
+    fn create(capacity: usize, device: &Device) -> Buffer {
+        device.create_buffer(&BufferDescriptor {
+            label: Some("instances"),
+            size: (capacity * mem::size_of::<Instance>()) as BufferAddress,
+            usage: BufferUsage::VERTEX | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Replaces all instances, the buffer is only reallocated when it has to grow
+    pub fn update(&mut self, instances: &[Instance], ctx: &crate::Context) {
+        if instances.len() > self.capacity {
+            self.capacity = instances.len().next_power_of_two();
+            self.buffer = Self::create(self.capacity, &ctx.device);
+        }
+        self.count = instances.len() as u32;
+        if !instances.is_empty() {
+            let data: Vec<u8> = instances.iter().flat_map(|i| i.data()).collect();
+            ctx.queue.write_buffer(&self.buffer, 0, &data);
+        }
+    }
+
+    pub(crate) fn buffer(&self) -> &Buffer {
+        &self.buffer
+    }
+
+    pub fn len(&self) -> u32 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
 pub struct Uniforms {
     pub view_proj: Mat4,
     pub model: Mat4,
@@ -359,7 +765,7 @@ impl Uniforms {
         unsafe { std::num::NonZeroU64::new_unchecked(std::mem::size_of::<Self>() as u64) }
     }
 
-    fn data(&self) -> Vec<u8> {
+    pub(crate) fn data(&self) -> Vec<u8> {
         let mut data = vec![];
         data.extend_from_slice(self.view_proj.as_byte_slice());
         data.extend_from_slice(self.model.as_byte_slice());
@@ -371,9 +777,10 @@ impl Uniforms {
             label: Some("Gbuffer Uniform"),
             entries: &[BindGroupLayoutEntry {
                 binding: 0,
-                visibility: ShaderStage::VERTEX,
+                visibility: ShaderStage::VERTEX | ShaderStage::FRAGMENT,
+                // dynamic so a whole scene can share one buffer, see `Scene`
                 ty: BindingType::UniformBuffer {
-                    dynamic: false,
+                    dynamic: true,
                     min_binding_size: Some(Self::size()),
                 },
                 count: None,
@@ -441,3 +848,27 @@ fn create_depth_tex(device: &Device, width: u32, height: u32) -> (Texture, Textu
     let view = tex.create_view(&TextureViewDescriptor::default());
     (tex, view)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instances_match_their_vertex_layout() {
+        let instance = Instance {
+            model: Mat4::from_translation(Vec3::new(1.0, 2.0, 3.0)),
+            palette: 7,
+        };
+        let data = instance.data();
+        let desc = Instance::desc();
+        assert_eq!(data.len(), 68);
+        assert_eq!(desc.stride, 68);
+
+        // the matrix columns come first, then the palette row
+        assert_eq!(&data[..64], instance.model.as_byte_slice());
+        let palette = desc.attributes.last().unwrap();
+        assert_eq!(palette.offset, 64);
+        assert_eq!(palette.format, VertexFormat::Uint);
+        assert_eq!(&data[64..], &7u32.to_le_bytes());
+    }
+}
@@ -0,0 +1,419 @@
+use super::gbuffer::TextureData;
+use super::grid::WorldGrid;
+use super::lighting::Lights;
+use crate::scene::Scene;
+use crate::voxel_data::{Color, Emitters, VoxelData};
+use ultraviolet::*;
+use wgpu::util::DeviceExt;
+use wgpu::*;
+
+// the volume and cone settings, see the Gi block in lighting.frag
+pub(crate) const GI_UNIFORM_SIZE: BufferAddress = 4 * 16;
+
+// radiance is stored divided by this so light brighter than white fits in 8 bits
+const RADIANCE_SCALE: f32 = 4.0;
+
+/// Presets trading indirect light quality for speed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GiQuality {
+    /// One wide cone of bounce light and no reflections
+    Low,
+    Medium,
+    /// Many narrow cones that go far, and sharp reflections
+    High,
+}
+
+/// How the lighting pass cone traces a `GiVolume`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GiSettings {
+    /// Cones traced around the normal for bounce light, 0 for none
+    pub diffuse_cones: u32,
+    /// How fast the diffuse cones widen, the tangent of their half angle
+    pub diffuse_aperture: f32,
+    pub diffuse_strength: f32,
+    /// Trace a cone along the view reflected by the surface for glossy reflections
+    pub reflections: bool,
+    /// How fast the reflection cone widens, narrower is sharper
+    pub reflection_aperture: f32,
+    pub reflection_strength: f32,
+    /// Furthest a cone goes, in voxels of the volume
+    pub max_distance: f32,
+    /// Most samples a cone takes before giving up
+    pub max_steps: u32,
+}
+
+impl GiSettings {
+    pub fn preset(quality: GiQuality) -> Self {
+        match quality {
+            GiQuality::Low => Self {
+                diffuse_cones: 1,
+                diffuse_aperture: 1.0,
+                diffuse_strength: 1.0,
+                reflections: false,
+                reflection_aperture: 0.3,
+                reflection_strength: 0.0,
+                max_distance: 24.0,
+                max_steps: 12,
+            },
+            GiQuality::Medium => Self {
+                diffuse_cones: 5,
+                diffuse_aperture: 0.6,
+                diffuse_strength: 1.0,
+                reflections: true,
+                reflection_aperture: 0.2,
+                reflection_strength: 0.3,
+                max_distance: 48.0,
+                max_steps: 24,
+            },
+            GiQuality::High => Self {
+                diffuse_cones: 9,
+                diffuse_aperture: 0.45,
+                diffuse_strength: 1.0,
+                reflections: true,
+                reflection_aperture: 0.08,
+                reflection_strength: 0.3,
+                max_distance: 96.0,
+                max_steps: 64,
+            },
+        }
+    }
+}
+
+impl Default for GiSettings {
+    fn default() -> Self {
+        Self::preset(GiQuality::Medium)
+    }
+}
+
+/// The color and glow of each cell of an axis aligned box of the world
+pub type GiGrid = WorldGrid<(Color, f32)>;
+
+impl GiGrid {
+    /// The color of the cell holding a world position, `Color::CLEAR` outside the grid
+    pub fn get(&self, pos: Vec3) -> Color {
+        self.value(pos).0
+    }
+
+    /// Sets the cell holding a world position to a color glowing with `emission` times that
+    /// color, positions outside the grid are ignored
+    pub fn set(&mut self, pos: Vec3, color: Color, emission: f32) {
+        self.set_value(pos, (color, emission));
+    }
+
+    /// Colors every cell whose center lies inside a solid voxel of a model placed by `model`,
+    /// the same matrix the model is drawn with
+    pub fn add_model(&mut self, data: &VoxelData, model: Mat4, emitters: &Emitters) {
+        self.add_model_with(data, model, |data, pos| glow(data, pos, emitters));
+    }
+
+    /// Clears the grid and adds every model drawn by a scene as of its last `Scene::update`
+    pub fn fill_from_scene(&mut self, scene: &Scene, emitters: &Emitters) {
+        self.fill_from_scene_with(scene, |data, pos| glow(data, pos, emitters));
+    }
+
+    fn is_solid(&self, pos: Vec3) -> bool {
+        self.get(pos).is_visible()
+    }
+
+    // light reaching a cell straight from the lights, the sun is blocked by other cells
+    fn direct_light(&self, cell: [u32; 3], lights: &Lights) -> Vec3 {
+        let pos = self.center(cell);
+        let mut light = lights.ambient;
+        if let Some(sun) = lights.sun {
+            // march out of the grid towards the sun half a cell at a time
+            let step = -sun.direction.normalized() * self.voxel_size() * 0.5;
+            let mut at = pos + step * 2.0;
+            while self.cell(at).is_some() && !self.is_solid(at) {
+                at += step;
+            }
+            if self.cell(at).is_none() {
+                light += sun.color;
+            }
+        }
+        for spot in &lights.spots {
+            let to_light = spot.position - pos;
+            let dist = to_light.mag();
+            let cos = (-to_light / dist).dot(spot.direction.normalized());
+            let (outer, inner) = (spot.outer_angle.cos(), spot.inner_angle.cos());
+            let cone = ((cos - outer) / (inner - outer).max(1e-4)).clamp(0.0, 1.0);
+            let falloff = (1.0 - dist / spot.range).max(0.0);
+            light += spot.color * cone * falloff * falloff;
+        }
+        for point in &lights.points {
+            let falloff = (1.0 - (point.position - pos).mag() / point.range).max(0.0);
+            light += point.color * falloff * falloff;
+        }
+        light
+    }
+
+    // light leaving every cell in the layout of the cells, premultiplied by how much of the
+    // cell is solid in w
+    fn radiance(&self, lights: &Lights) -> Vec<Vec4> {
+        let [w, h, d] = self.dims();
+        let mut radiance = Vec::with_capacity(self.cells().len());
+        for x in 0..w {
+            for y in 0..h {
+                for z in 0..d {
+                    let (color, emission) = self.cells()[self.index([x, y, z])];
+                    if !color.is_visible() {
+                        radiance.push(Vec4::zero());
+                        continue;
+                    }
+                    let [r, g, b] = color.rgb();
+                    let albedo = Vec3::new(r as f32, g as f32, b as f32) / 255.0;
+                    let light = self.direct_light([x, y, z], lights) + Vec3::broadcast(emission);
+                    radiance.push((albedo * light).into_homogeneous_point());
+                }
+            }
+        }
+        radiance
+    }
+}
+
+// visible voxels color their cells and glow as much as their color's emitter
+fn glow(data: &VoxelData, [x, y, z]: [u32; 3], emitters: &Emitters) -> Option<(Color, f32)> {
+    let color = data.get(x, y, z);
+    if color.is_visible() {
+        Some((color, emitters.get(color)))
+    } else {
+        None
+    }
+}
+
+fn mip_levels(dims: [u32; 3]) -> u32 {
+    let largest = dims.iter().copied().max().unwrap_or(1).max(1);
+    32 - largest.leading_zeros()
+}
+
+// every mip level of a volume laid out like `VoxelData`, each averaging 2x2x2 cells of the last
+fn mip_chain(dims: [u32; 3], level0: Vec<Vec4>) -> Vec<([u32; 3], Vec<Vec4>)> {
+    let mut levels = vec![(dims, level0)];
+    loop {
+        let ([w, h, d], last) = levels.last().unwrap();
+        let (w, h, d) = (*w, *h, *d);
+        if w == 1 && h == 1 && d == 1 {
+            return levels;
+        }
+        let next = [(w / 2).max(1), (h / 2).max(1), (d / 2).max(1)];
+        let mut cells = Vec::with_capacity((next[0] * next[1] * next[2]) as usize);
+        for x in 0..next[0] {
+            for y in 0..next[1] {
+                for z in 0..next[2] {
+                    let mut sum = Vec4::zero();
+                    let mut count = 0.0;
+                    for cx in (x * 2)..(x * 2 + 2).min(w) {
+                        for cy in (y * 2)..(y * 2 + 2).min(h) {
+                            for cz in (z * 2)..(z * 2 + 2).min(d) {
+                                sum += last[(cx * h * d + cy * d + cz) as usize];
+                                count += 1.0;
+                            }
+                        }
+                    }
+                    cells.push(sum / count);
+                }
+            }
+        }
+        levels.push((next, cells));
+    }
+}
+
+/// A `GiGrid` lit and mipmapped on the gpu, for cone tracing bounce light and reflections in
+/// the lighting pass.
+///
+/// Light is baked into the volume when it is uploaded, so call `relight` after moving lights
+/// and edit the grid through `grid_mut`. `update` uploads it again and
+/// `LightingPipeline::bind_gi` binds it.
+pub struct GiVolume {
+    grid: GiGrid,
+    settings: GiSettings,
+    dirty: bool,
+    tex: Texture,
+    pub(crate) view: TextureView,
+    pub(crate) sampler: Sampler,
+    pub(crate) uniform: Buffer,
+}
+
+impl GiVolume {
+    /// The volume stays dark until the first `update`
+    pub fn new(grid: GiGrid, settings: GiSettings, ctx: &crate::Context) -> Self {
+        let [w, h, d] = grid.dims();
+        let tex = ctx.device.create_texture(&TextureDescriptor {
+            label: Some("gi volume"),
+            // z-major like the index textures
+            size: Extent3d {
+                width: d,
+                height: h,
+                depth: w,
+            },
+            mip_level_count: mip_levels(grid.dims()),
+            sample_count: 1,
+            dimension: TextureDimension::D3,
+            format: TextureFormat::Rgba8Unorm,
+            usage: TextureUsage::SAMPLED | TextureUsage::COPY_DST,
+        });
+        let view = tex.create_view(&TextureViewDescriptor::default());
+        let sampler = create_sampler(&ctx.device);
+        let uniform = ctx.device.create_buffer_init(&util::BufferInitDescriptor {
+            label: Some("gi volume uniform"),
+            contents: &gi_data(Some((&grid, &settings))),
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+        });
+        Self {
+            grid,
+            settings,
+            dirty: true,
+            tex,
+            view,
+            sampler,
+            uniform,
+        }
+    }
+
+    pub fn grid(&self) -> &GiGrid {
+        &self.grid
+    }
+
+    /// Edits made through this are lit and uploaded on the next call to `update`
+    pub fn grid_mut(&mut self) -> &mut GiGrid {
+        self.dirty = true;
+        &mut self.grid
+    }
+
+    /// Lights the volume again on the next call to `update`, for when lights changed
+    pub fn relight(&mut self) {
+        self.dirty = true;
+    }
+
+    pub fn settings(&self) -> &GiSettings {
+        &self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: GiSettings, ctx: &crate::Context) {
+        self.settings = settings;
+        let data = gi_data(Some((&self.grid, &self.settings)));
+        ctx.queue.write_buffer(&self.uniform, 0, &data);
+    }
+
+    /// Lights the grid and uploads every mip level if it was edited or relit since the last
+    /// update
+    pub fn update(&mut self, lights: &Lights, ctx: &crate::Context) {
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+        let radiance = self.grid.radiance(lights);
+        for (level, ([w, h, d], cells)) in mip_chain(self.grid.dims(), radiance).iter().enumerate()
+        {
+            let texels: Vec<u8> = cells
+                .iter()
+                .flat_map(|c| {
+                    let unorm = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+                    let rgb = c.xyz() / RADIANCE_SCALE;
+                    vec![unorm(rgb.x), unorm(rgb.y), unorm(rgb.z), unorm(c.w)]
+                })
+                .collect();
+            let data = TextureData::radiance(&texels, [*d, *h, *w]);
+            data.write_mip(&self.tex, level as u32, [0, 0, 0], ctx);
+        }
+    }
+}
+
+// trilinear between cells and mip levels, nothing past the edges
+pub(crate) fn create_sampler(device: &Device) -> Sampler {
+    device.create_sampler(&SamplerDescriptor {
+        label: Some("gi sampler"),
+        address_mode_u: AddressMode::ClampToEdge,
+        address_mode_v: AddressMode::ClampToEdge,
+        address_mode_w: AddressMode::ClampToEdge,
+        mag_filter: FilterMode::Linear,
+        min_filter: FilterMode::Linear,
+        mipmap_filter: FilterMode::Linear,
+        ..Default::default()
+    })
+}
+
+// the Gi block of lighting.frag, no volume turns cone tracing off
+pub(crate) fn gi_data(volume: Option<(&GiGrid, &GiSettings)>) -> Vec<u8> {
+    let mut data = vec![];
+    let mut vec4 = |v: Vec3, w: f32| {
+        data.extend_from_slice(v.as_byte_slice());
+        data.extend_from_slice(&w.to_le_bytes());
+    };
+    match volume {
+        Some((grid, settings)) => {
+            let size = grid.voxel_size();
+            let [w, h, d] = grid.dims();
+            vec4(grid.origin(), size);
+            vec4(Vec3::new(w as f32, h as f32, d as f32), RADIANCE_SCALE);
+            let diffuse = Vec3::new(
+                settings.diffuse_cones as f32,
+                settings.diffuse_aperture,
+                settings.diffuse_strength,
+            );
+            vec4(diffuse, settings.max_distance * size);
+            let reflection = Vec3::new(
+                settings.reflections as u32 as f32,
+                settings.reflection_aperture,
+                settings.reflection_strength,
+            );
+            vec4(reflection, settings.max_steps as f32);
+        }
+        None => {
+            vec4(Vec3::zero(), 1.0);
+            vec4(Vec3::one(), RADIANCE_SCALE);
+            vec4(Vec3::zero(), 0.0);
+            vec4(Vec3::zero(), 0.0);
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::lighting::DirectionalLight;
+
+    #[test]
+    fn mips_average_down_to_one_cell() {
+        let mut cells = vec![Vec4::zero(); 8];
+        cells[0] = Vec4::one();
+        cells[7] = Vec4::new(2.0, 2.0, 2.0, 1.0);
+        let levels = mip_chain([4, 2, 1], cells);
+        let dims: Vec<_> = levels.iter().map(|(dims, _)| *dims).collect();
+        assert_eq!(dims, vec![[4, 2, 1], [2, 1, 1], [1, 1, 1]]);
+        assert_eq!(mip_levels([4, 2, 1]), 3);
+        assert_eq!(levels[1].1[0], Vec4::new(0.25, 0.25, 0.25, 0.25));
+        assert_eq!(levels[2].1[0], Vec4::new(0.375, 0.375, 0.375, 0.25));
+    }
+
+    #[test]
+    fn cells_glow_and_catch_the_sun() {
+        let lamp = Color::new(255, 0, 0);
+        let stone = Color::new(255, 255, 255);
+        let mut data = VoxelData::empty(3, 3, 1);
+        data.set(0, 0, 0, stone);
+        data.set(2, 0, 0, stone);
+        data.set(2, 2, 0, stone);
+        data.set(1, 1, 0, lamp);
+        let mut emitters = Emitters::new();
+        emitters.set(lamp, 2.0);
+
+        let mut grid = GiGrid::new(Vec3::zero(), [3, 3, 1], 1.0);
+        grid.add_model(&data, Mat4::identity(), &emitters);
+        let lights = Lights {
+            ambient: Vec3::zero(),
+            sun: Some(DirectionalLight {
+                direction: -Vec3::unit_y(),
+                color: Vec3::one(),
+            }),
+            ..Lights::default()
+        };
+        let radiance = grid.radiance(&lights);
+        let at = |x: u32, y: u32| radiance[grid.index([x, y, 0])];
+        // open to the sky, under a voxel, and lit by itself
+        assert_eq!(at(0, 0), Vec4::one());
+        assert_eq!(at(2, 0), Vec4::new(0.0, 0.0, 0.0, 1.0));
+        assert_eq!(at(1, 1), Vec4::new(3.0, 0.0, 0.0, 1.0));
+        assert_eq!(at(1, 0), Vec4::zero());
+    }
+}
@@ -0,0 +1,284 @@
+use crate::include_shader;
+use crate::pipeline::gbuffer::{Textures, Vertex};
+use crate::voxel_data::{MeshOptions, VoxelBuffer, VoxelData};
+use std::mem;
+use wgpu::*;
+
+// matches local_size in mesh.comp
+const WORKGROUP_SIZE: u32 = 4;
+// the four draw_indirect arguments followed by the face counter
+const ARGS_SIZE: BufferAddress = 5 * 4;
+// dims, max_faces, ao_strength, ao_levels and ao, padded to 16 bytes
+const PARAMS_SIZE: BufferAddress = 8 * 4;
+
+/// Runs the compute shader that meshes a `GpuMesh`
+pub struct GpuMeshPipeline {
+    pipeline: ComputePipeline,
+    layout: BindGroupLayout,
+}
+
+impl GpuMeshPipeline {
+    pub fn new(ctx: &crate::Context) -> Self {
+        let storage = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStage::COMPUTE,
+            ty: BindingType::StorageBuffer {
+                dynamic: false,
+                min_binding_size: None,
+                readonly: false,
+            },
+            count: None,
+        };
+        let layout = ctx
+            .device
+            .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("gpu mesh layout"),
+                entries: &[
+                    // palette indices
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStage::COMPUTE,
+                        ty: BindingType::SampledTexture {
+                            dimension: TextureViewDimension::D3,
+                            component_type: TextureComponentType::Uint,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    // vertices
+                    storage(1),
+                    // draw arguments
+                    storage(2),
+                    BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: ShaderStage::COMPUTE,
+                        ty: BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: BufferSize::new(PARAMS_SIZE),
+                        },
+                        count: None,
+                    },
+                    // palette, to tell visible voxels apart like the cpu mesher does
+                    BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: ShaderStage::COMPUTE,
+                        ty: BindingType::SampledTexture {
+                            dimension: TextureViewDimension::D2,
+                            component_type: TextureComponentType::Float,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+
+        let pipeline_layout = ctx
+            .device
+            .create_pipeline_layout(&PipelineLayoutDescriptor {
+                label: Some("gpu mesh pipeline layout"),
+                bind_group_layouts: &[&layout],
+                push_constant_ranges: &[],
+            });
+        let pipeline = ctx
+            .device
+            .create_compute_pipeline(&ComputePipelineDescriptor {
+                label: Some("gpu mesh pipeline"),
+                layout: Some(&pipeline_layout),
+                compute_stage: ProgrammableStageDescriptor {
+                    module: &ctx
+                        .device
+                        .create_shader_module(include_shader!("mesh.comp.spv")),
+                    entry_point: "main",
+                },
+            });
+        Self { pipeline, layout }
+    }
+
+    fn bind(
+        &self,
+        textures: &Textures,
+        vertices: &Buffer,
+        args: &Buffer,
+        params: &Buffer,
+        device: &Device,
+    ) -> BindGroup {
+        device.create_bind_group(&BindGroupDescriptor {
+            label: Some("gpu mesh bind group"),
+            layout: &self.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&textures.index_view),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::Buffer(vertices.slice(..)),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::Buffer(args.slice(..)),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: BindingResource::Buffer(params.slice(..)),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: BindingResource::TextureView(&textures.palette_view),
+                },
+            ],
+        })
+    }
+}
+
+/// A model meshed entirely on the gpu, for chunks that are edited all the time.
+///
+/// Edits only upload the changed palette indices, then a compute shader re-meshes the whole
+/// model from its index texture and writes the vertex count for an indirect draw, so nothing is
+/// ever meshed or read back on the cpu. Faces past `max_faces` aren't drawn. Draw it with
+/// `GBufferPipeline::render_gpu_mesh`.
+pub struct GpuMesh {
+    voxels: VoxelBuffer,
+    options: MeshOptions,
+    max_faces: u32,
+    vertices: Buffer,
+    args: Buffer,
+    params: Buffer,
+    bind: BindGroup,
+    // the mesh doesn't match the data yet
+    stale: bool,
+}
+
+impl GpuMesh {
+    pub fn new(
+        data: VoxelData,
+        options: MeshOptions,
+        max_faces: u32,
+        pipeline: &GpuMeshPipeline,
+        ctx: &crate::Context,
+    ) -> Self {
+        let voxels = VoxelBuffer::unmeshed(data, ctx);
+        let vertices = ctx.device.create_buffer(&BufferDescriptor {
+            label: Some("gpu mesh vertices"),
+            size: (max_faces.max(1) as usize * 6 * mem::size_of::<Vertex>()) as BufferAddress,
+            usage: BufferUsage::STORAGE | BufferUsage::VERTEX,
+            mapped_at_creation: false,
+        });
+        let args = ctx.device.create_buffer(&BufferDescriptor {
+            label: Some("gpu mesh draw args"),
+            size: ARGS_SIZE,
+            usage: BufferUsage::STORAGE | BufferUsage::INDIRECT | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let params = ctx.device.create_buffer(&BufferDescriptor {
+            label: Some("gpu mesh params"),
+            size: PARAMS_SIZE,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind = pipeline.bind(voxels.textures(), &vertices, &args, &params, &ctx.device);
+        Self {
+            voxels,
+            options,
+            max_faces,
+            vertices,
+            args,
+            params,
+            bind,
+            stale: true,
+        }
+    }
+
+    pub fn data(&self) -> &VoxelData {
+        self.voxels.data()
+    }
+
+    /// Edits made through this are re-meshed on the next call to `update`
+    pub fn data_mut(&mut self) -> &mut VoxelData {
+        self.voxels.data_mut()
+    }
+
+    pub fn options(&self) -> &MeshOptions {
+        &self.options
+    }
+
+    pub fn set_options(&mut self, options: MeshOptions) {
+        self.stale |= options != self.options;
+        self.options = options;
+    }
+
+    pub fn max_faces(&self) -> u32 {
+        self.max_faces
+    }
+
+    /// Bind these with `GBufferPipeline::bind_textures` to draw the mesh
+    pub fn textures(&self) -> &Textures {
+        self.voxels.textures()
+    }
+
+    /// Uploads edits and records a re-mesh into `encoder` if anything changed. Returns `true`
+    /// if the textures were recreated, in which case bind groups made from `textures()` have to
+    /// be rebuilt.
+    pub fn update(
+        &mut self,
+        pipeline: &GpuMeshPipeline,
+        encoder: &mut CommandEncoder,
+        ctx: &crate::Context,
+    ) -> bool {
+        self.stale |= self.voxels.data().is_dirty();
+        let rebind = self.voxels.update(ctx);
+        if rebind {
+            self.bind = pipeline.bind(
+                self.voxels.textures(),
+                &self.vertices,
+                &self.args,
+                &self.params,
+                &ctx.device,
+            );
+        }
+        if self.stale {
+            self.stale = false;
+            self.dispatch(pipeline, encoder, ctx);
+        }
+        rebind
+    }
+
+    fn dispatch(
+        &self,
+        pipeline: &GpuMeshPipeline,
+        encoder: &mut CommandEncoder,
+        ctx: &crate::Context,
+    ) {
+        let dims = self.data().dims();
+        let mut params = vec![];
+        for word in &[dims[0], dims[1], dims[2], self.max_faces] {
+            params.extend_from_slice(&word.to_le_bytes());
+        }
+        params.extend_from_slice(&self.options.ao_strength.to_le_bytes());
+        params.extend_from_slice(&(self.options.ao_levels as f32).to_le_bytes());
+        params.extend_from_slice(&(self.options.ao as u32).to_le_bytes());
+        params.resize(PARAMS_SIZE as usize, 0);
+        ctx.queue.write_buffer(&self.params, 0, &params);
+        // no vertices and one instance, the shader counts the vertices up
+        let args: [u32; 5] = [0, 1, 0, 0, 0];
+        ctx.queue
+            .write_buffer(&self.args, 0, bytemuck::cast_slice(&args));
+
+        if dims.contains(&0) {
+            return;
+        }
+        let groups = |n: u32| n.div_ceil(WORKGROUP_SIZE);
+        let mut cpass = encoder.begin_compute_pass();
+        cpass.set_pipeline(&pipeline.pipeline);
+        cpass.set_bind_group(0, &self.bind, &[]);
+        cpass.dispatch(groups(dims[0]), groups(dims[1]), groups(dims[2]));
+    }
+
+    pub(crate) fn vertices(&self) -> &Buffer {
+        &self.vertices
+    }
+
+    pub(crate) fn args(&self) -> &Buffer {
+        &self.args
+    }
+}
@@ -0,0 +1,152 @@
+use crate::scene::Scene;
+use crate::voxel_data::VoxelData;
+use ultraviolet::*;
+
+/// A value for each cell of an axis aligned box of the world, looked up by world position.
+///
+/// Positions outside the box read as the default value and writes to them are ignored.
+#[derive(Debug, Clone)]
+pub struct WorldGrid<T> {
+    origin: Vec3,
+    voxel_size: f32,
+    dims: [u32; 3],
+    // indexed like `VoxelData`, x * h * d + y * d + z
+    cells: Vec<T>,
+}
+
+impl<T: Copy + Default> WorldGrid<T> {
+    /// An empty grid of `dims` cells of `voxel_size` world units, with its lowest corner at
+    /// `origin`
+    pub fn new(origin: Vec3, dims: [u32; 3], voxel_size: f32) -> Self {
+        Self {
+            origin,
+            voxel_size,
+            dims,
+            cells: vec![T::default(); (dims[0] * dims[1] * dims[2]) as usize],
+        }
+    }
+
+    pub fn origin(&self) -> Vec3 {
+        self.origin
+    }
+
+    pub fn voxel_size(&self) -> f32 {
+        self.voxel_size
+    }
+
+    pub fn dims(&self) -> [u32; 3] {
+        self.dims
+    }
+
+    pub(crate) fn cells(&self) -> &[T] {
+        &self.cells
+    }
+
+    pub(crate) fn index(&self, cell: [u32; 3]) -> usize {
+        let [_, h, d] = self.dims;
+        (cell[0] * h * d + cell[1] * d + cell[2]) as usize
+    }
+
+    // the cell a world position falls in, if it is inside the grid
+    pub(crate) fn cell(&self, pos: Vec3) -> Option<[u32; 3]> {
+        let local = (pos - self.origin) / self.voxel_size;
+        let mut cell = [0; 3];
+        for axis in 0..3 {
+            let c = local[axis].floor();
+            if c < 0.0 || c >= self.dims[axis] as f32 {
+                return None;
+            }
+            cell[axis] = c as u32;
+        }
+        Some(cell)
+    }
+
+    pub(crate) fn center(&self, cell: [u32; 3]) -> Vec3 {
+        let cell = Vec3::new(cell[0] as f32, cell[1] as f32, cell[2] as f32);
+        self.origin + (cell + Vec3::broadcast(0.5)) * self.voxel_size
+    }
+
+    /// The value of the cell holding a world position
+    pub fn value(&self, pos: Vec3) -> T {
+        self.cell(pos)
+            .map_or_else(T::default, |c| self.cells[self.index(c)])
+    }
+
+    pub fn set_value(&mut self, pos: Vec3, value: T) {
+        if let Some(cell) = self.cell(pos) {
+            let index = self.index(cell);
+            self.cells[index] = value;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.iter_mut().for_each(|c| *c = T::default());
+    }
+
+    /// Sets every cell whose center lies inside a voxel of a model placed by `model`, the same
+    /// matrix the model is drawn with. `value` picks what a voxel of the model turns its cells
+    /// into, `None` leaves them alone.
+    pub fn add_model_with(
+        &mut self,
+        data: &VoxelData,
+        model: Mat4,
+        mut value: impl FnMut(&VoxelData, [u32; 3]) -> Option<T>,
+    ) {
+        let [w, h, d] = data.dims();
+        let size = Vec3::new(w as f32, h as f32, d as f32);
+        // the cells covered by the model's bounds, wherever they end up in the world
+        let mut min = Vec3::broadcast(f32::MAX);
+        let mut max = Vec3::broadcast(f32::MIN);
+        for i in 0..8 {
+            let corner = Vec3::new(
+                (i & 1) as f32 * size.x,
+                ((i >> 1) & 1) as f32 * size.y,
+                (i >> 2) as f32 * size.z,
+            );
+            let corner = model.transform_point3(corner);
+            min = min.min_by_component(corner);
+            max = max.max_by_component(corner);
+        }
+        let first = (min - self.origin) / self.voxel_size;
+        let last = (max - self.origin) / self.voxel_size;
+        let dims = self.dims;
+        let range = |axis: usize| {
+            let start = first[axis].floor().max(0.0) as u32;
+            let end = (last[axis].ceil().max(0.0) as u32).min(dims[axis]);
+            start..end
+        };
+
+        let inverse = model.inversed();
+        for x in range(0) {
+            for y in range(1) {
+                for z in range(2) {
+                    let local = inverse.transform_point3(self.center([x, y, z]));
+                    if local.x < 0.0 || local.y < 0.0 || local.z < 0.0 {
+                        continue;
+                    }
+                    let (vx, vy, vz) = (local.x as u32, local.y as u32, local.z as u32);
+                    if !data.in_bounds(vx, vy, vz) {
+                        continue;
+                    }
+                    if let Some(value) = value(data, [vx, vy, vz]) {
+                        let index = self.index([x, y, z]);
+                        self.cells[index] = value;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Clears the grid and adds every model drawn by a scene as of its last `Scene::update`,
+    /// see `add_model_with`
+    pub fn fill_from_scene_with(
+        &mut self,
+        scene: &Scene,
+        mut value: impl FnMut(&VoxelData, [u32; 3]) -> Option<T>,
+    ) {
+        self.clear();
+        for (buffer, world) in scene.placed_models() {
+            self.add_model_with(buffer.data(), world, &mut value);
+        }
+    }
+}
@@ -1,23 +1,192 @@
-use super::gbuffer::GBuffer;
+use super::gbuffer::{GBuffer, TextureData};
+use super::gi::{create_sampler, gi_data, GiVolume, GI_UNIFORM_SIZE};
+use super::occupancy::{volume_data, OccupancyVolume, VOLUME_UNIFORM_SIZE};
+use super::shadow::{ShadowMaps, MAX_CASCADES, MAX_POINT_SHADOWS};
 use crate::include_shader;
+use crate::voxel_data::ramp::{MAX_RAMPS, RAMP_SHADES};
+use crate::voxel_data::{EmissiveCluster, Ramps};
+use crate::Camera;
 use std::mem;
 use ultraviolet::*;
 use wgpu::util::DeviceExt;
 use wgpu::*;
 
+/// Most spot lights lit at once, later ones in `Lights::spots` are ignored
+pub const MAX_SPOT_LIGHTS: usize = 4;
+
+/// Most point lights lit at once, later ones in `Lights::points` are ignored
+pub const MAX_POINT_LIGHTS: usize = 16;
+
+// how far a single voxel glowing at strength 1 reaches, in voxels
+const EMISSIVE_RANGE: f32 = 6.0;
+
+// see the Lights block in lighting.frag
+const SPOT_SIZE: usize = 64 + 4 * 16;
+const POINT_SIZE: usize = 2 * 16;
+const LIGHTS_SIZE: usize = 64
+    + MAX_CASCADES * 64
+    + 7 * 16
+    + MAX_SPOT_LIGHTS * SPOT_SIZE
+    + MAX_POINT_LIGHTS * POINT_SIZE
+    + MAX_POINT_SHADOWS * 6 * 64;
+
+/// Light falling evenly from one direction, like the sun
+#[derive(Debug, Copy, Clone)]
+pub struct DirectionalLight {
+    /// The way the light travels, not the way to the light
+    pub direction: Vec3,
+    pub color: Vec3,
+}
+
+impl Default for DirectionalLight {
+    fn default() -> Self {
+        Self {
+            direction: Vec3::new(0.3, -1.0, 0.4).normalized(),
+            color: Vec3::one(),
+        }
+    }
+}
+
+/// A cone of light shining from a point
+#[derive(Debug, Copy, Clone)]
+pub struct SpotLight {
+    pub position: Vec3,
+    pub direction: Vec3,
+    pub color: Vec3,
+    /// Distance where the light has faded out completely
+    pub range: f32,
+    /// Angle from the direction where the light starts to fade, in radians
+    pub inner_angle: f32,
+    /// Angle from the direction where the light is gone, in radians
+    pub outer_angle: f32,
+    pub shadows: bool,
+}
+
+impl Default for SpotLight {
+    fn default() -> Self {
+        Self {
+            position: Vec3::zero(),
+            direction: -Vec3::unit_y(),
+            color: Vec3::one(),
+            range: 50.0,
+            inner_angle: 20f32.to_radians(),
+            outer_angle: 30f32.to_radians(),
+            shadows: true,
+        }
+    }
+}
+
+/// Light shining every way from a point, like a torch or a lamp
+#[derive(Debug, Copy, Clone)]
+pub struct PointLight {
+    pub position: Vec3,
+    pub color: Vec3,
+    /// Distance where the light has faded out completely
+    pub range: f32,
+    /// Whether the light may cast shadows at all
+    pub shadows: bool,
+    /// How much this light wants one of the few shadow maps, see `ShadowSettings::point_shadows`.
+    /// It is divided down the further the camera is outside of the light's range, so nearby
+    /// lights win over far away ones of the same priority. 0 never casts shadows.
+    pub priority: f32,
+}
+
+impl Default for PointLight {
+    fn default() -> Self {
+        Self {
+            position: Vec3::zero(),
+            color: Vec3::one(),
+            range: 20.0,
+            shadows: true,
+            priority: 1.0,
+        }
+    }
+}
+
+impl PointLight {
+    /// A light in place of a cluster of glowing voxels of a model placed by `model`. It shines
+    /// in their average color and reaches further the more of them there are, without shadows.
+    pub fn from_cluster(cluster: &EmissiveCluster, model: Mat4) -> Self {
+        // ranges grow with the model, going by the length of its x axis
+        let scale = model.transform_vec3(Vec3::unit_x()).mag();
+        Self {
+            position: model.transform_point3(cluster.center),
+            color: cluster.color * cluster.strength / cluster.voxels.max(1) as f32,
+            range: EMISSIVE_RANGE * cluster.strength.sqrt() * scale,
+            shadows: false,
+            priority: 0.0,
+        }
+    }
+}
+
+/// Every light the lighting pass shades with
+#[derive(Debug, Clone)]
+pub struct Lights {
+    /// Reaches everything evenly, shadowed or not, except where a `VoxelWorld` has baked in
+    /// that the sky can't reach
+    pub ambient: Vec3,
+    /// Color of the light baked in around emitting voxels of a `VoxelWorld`, at full strength
+    pub block_light: Vec3,
+    pub sun: Option<DirectionalLight>,
+    /// Only the first `MAX_SPOT_LIGHTS` are lit
+    pub spots: Vec<SpotLight>,
+    /// Only the first `MAX_POINT_LIGHTS` are lit
+    pub points: Vec<PointLight>,
+    /// The same registry given to `VoxelBuffer::set_ramps`, to shade ramped colors with
+    pub ramps: Ramps,
+    /// World units a voxel covers, for scenes of scaled models. Shadows and the occupancy and
+    /// gi volumes are looked up this far off the surface.
+    pub voxel_size: f32,
+}
+
+impl Default for Lights {
+    fn default() -> Self {
+        Self {
+            ambient: Vec3::broadcast(0.2),
+            block_light: Vec3::new(1.0, 0.85, 0.6),
+            sun: Some(DirectionalLight::default()),
+            spots: vec![],
+            points: vec![],
+            ramps: Ramps::new(),
+            voxel_size: 1.0,
+        }
+    }
+}
+
 // This will be the final deferred stage
 pub struct LightingPipeline {
     pub pipeline: RenderPipeline,
     tex_layout: BindGroupLayout,
+    light_layout: BindGroupLayout,
+    lights: Buffer,
+    // every ramp of `Lights::ramps`, room for `MAX_RAMPS`
+    ramps: (Texture, TextureView),
+    volume_layout: BindGroupLayout,
+    // an empty volume with tracing turned off, bound when there is no occupancy volume
+    no_volume: (Texture, Buffer, BindGroup),
+    gi_layout: BindGroupLayout,
+    // a black volume with cone tracing turned off, bound when there is no gi volume
+    no_gi: (Texture, Buffer, BindGroup),
     vbuf: Buffer,
 }
 
 impl LightingPipeline {
-    // handle renderpass
-    pub fn render<'a>(&'a mut self, gbuffer: &'a BindGroup, rpass: &mut RenderPass<'a>) {
+    /// Shades the gbuffer, tracing shadows and occlusion through `occupancy` if it is given, see
+    /// `bind_occupancy`, and cone tracing bounce light through `gi` if it is given, see `bind_gi`
+    pub fn render<'a>(
+        &'a mut self,
+        gbuffer: &'a BindGroup,
+        lights: &'a BindGroup,
+        occupancy: Option<&'a BindGroup>,
+        gi: Option<&'a BindGroup>,
+        rpass: &mut RenderPass<'a>,
+    ) {
         rpass.set_pipeline(&self.pipeline);
         // render a full screen tri
         rpass.set_bind_group(0, gbuffer, &[]);
+        rpass.set_bind_group(1, lights, &[]);
+        rpass.set_bind_group(2, occupancy.unwrap_or(&self.no_volume.2), &[]);
+        rpass.set_bind_group(3, gi.unwrap_or(&self.no_gi.2), &[]);
         rpass.set_vertex_buffer(0, self.vbuf.slice(..));
         rpass.draw(0..3, 0..1);
     }
@@ -33,6 +202,17 @@ impl LightingPipeline {
             &ctx.device,
         );
 
+        let texture = |binding, dimension| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStage::FRAGMENT,
+            ty: BindingType::SampledTexture {
+                dimension,
+                component_type: TextureComponentType::Float,
+                multisampled: false,
+            },
+            count: None,
+        };
+
         // create bind group for g-buffer
         let tex_layout = ctx
             .device
@@ -69,13 +249,119 @@ impl LightingPipeline {
                         },
                         count: None,
                     },
+                    // depth, to find the exact position of pixels
+                    texture(3, TextureViewDimension::D2),
+                    // emission
+                    texture(4, TextureViewDimension::D2),
+                ],
+            });
+        let light_layout = ctx
+            .device
+            .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("lights bind group layout"),
+                entries: &[
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStage::FRAGMENT,
+                        ty: BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: BufferSize::new(LIGHTS_SIZE as BufferAddress),
+                        },
+                        count: None,
+                    },
+                    // the sun's cascades
+                    texture(1, TextureViewDimension::D2Array),
+                    // spot lights
+                    texture(2, TextureViewDimension::D2Array),
+                    // point light faces
+                    texture(3, TextureViewDimension::D2Array),
+                    // ramps
+                    texture(4, TextureViewDimension::D2),
+                ],
+            });
+        let volume_layout = ctx
+            .device
+            .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("occupancy bind group layout"),
+                entries: &[
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStage::FRAGMENT,
+                        ty: BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: BufferSize::new(VOLUME_UNIFORM_SIZE),
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: ShaderStage::FRAGMENT,
+                        ty: BindingType::SampledTexture {
+                            dimension: TextureViewDimension::D3,
+                            component_type: TextureComponentType::Uint,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+        let no_volume = {
+            let (tex, view) = TextureData::indices(&[0], [1, 1, 1]).create(ctx);
+            let uniform = ctx.device.create_buffer_init(&util::BufferInitDescriptor {
+                label: Some("no occupancy volume"),
+                contents: &volume_data(None),
+                usage: BufferUsage::UNIFORM,
+            });
+            let bind = bind_volume(&volume_layout, &uniform, &view, &ctx.device);
+            (tex, uniform, bind)
+        };
+        let gi_layout = ctx
+            .device
+            .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("gi bind group layout"),
+                entries: &[
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStage::FRAGMENT,
+                        ty: BindingType::UniformBuffer {
+                            dynamic: false,
+                            min_binding_size: BufferSize::new(GI_UNIFORM_SIZE),
+                        },
+                        count: None,
+                    },
+                    texture(1, TextureViewDimension::D3),
+                    BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: ShaderStage::FRAGMENT,
+                        ty: BindingType::Sampler { comparison: false },
+                        count: None,
+                    },
                 ],
             });
+        let no_gi = {
+            let (tex, view) = TextureData::radiance(&[0; 4], [1, 1, 1]).create(ctx);
+            let uniform = ctx.device.create_buffer_init(&util::BufferInitDescriptor {
+                label: Some("no gi volume"),
+                contents: &gi_data(None),
+                usage: BufferUsage::UNIFORM,
+            });
+            let sampler = create_sampler(&ctx.device);
+            let bind = bind_gi(&gi_layout, &uniform, &view, &sampler, &ctx.device);
+            (tex, uniform, bind)
+        };
+        let lights = ctx.device.create_buffer(&BufferDescriptor {
+            label: Some("lights"),
+            size: LIGHTS_SIZE as BufferAddress,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let ramps =
+            TextureData::ramps(&[0; RAMP_SHADES * MAX_RAMPS * 4], MAX_RAMPS as u32).create(ctx);
         let layout = ctx
             .device
             .create_pipeline_layout(&PipelineLayoutDescriptor {
                 label: Some("lighting pipeline layout"),
-                bind_group_layouts: &[&tex_layout],
+                bind_group_layouts: &[&tex_layout, &light_layout, &volume_layout, &gi_layout],
                 push_constant_ranges: &[],
             });
         let pipeline = ctx
@@ -110,10 +396,87 @@ impl LightingPipeline {
         Self {
             pipeline,
             tex_layout,
+            light_layout,
+            lights,
+            ramps,
+            volume_layout,
+            no_volume,
+            gi_layout,
+            no_gi,
             vbuf,
         }
     }
 
+    /// Binds a volume to trace shadows and occlusion through, pass it to `render`. Has to be
+    /// bound again when the volume is recreated, not when it is edited.
+    pub fn bind_occupancy(&self, volume: &OccupancyVolume, device: &Device) -> BindGroup {
+        bind_volume(&self.volume_layout, &volume.uniform, &volume.view, device)
+    }
+
+    /// Binds a volume to cone trace bounce light and reflections through, pass it to `render`.
+    /// Has to be bound again when the volume is recreated, not when it is updated.
+    pub fn bind_gi(&self, volume: &GiVolume, device: &Device) -> BindGroup {
+        bind_gi(
+            &self.gi_layout,
+            &volume.uniform,
+            &volume.view,
+            &volume.sampler,
+            device,
+        )
+    }
+
+    /// Fits the shadow maps to the camera and uploads the lights, call once a frame before
+    /// drawing into the shadow maps
+    pub fn update(
+        &self,
+        lights: &Lights,
+        camera: &Camera,
+        shadows: &mut ShadowMaps,
+        ctx: &crate::Context,
+    ) {
+        shadows.update(lights, camera, ctx);
+        let data = light_data(lights, camera, shadows);
+        ctx.queue.write_buffer(&self.lights, 0, &data);
+        // only the rows in use, the others are never looked up
+        if !lights.ramps.is_empty() {
+            let texels = lights.ramps.texels();
+            TextureData::ramps(&texels, lights.ramps.len() as u32).write(
+                &self.ramps.0,
+                [0, 0, 0],
+                ctx,
+            );
+        }
+    }
+
+    pub fn bind_lights(&self, shadows: &ShadowMaps, device: &Device) -> BindGroup {
+        device.create_bind_group(&BindGroupDescriptor {
+            label: Some("lights bind group"),
+            layout: &self.light_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::Buffer(self.lights.slice(..)),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(&shadows.sun_view),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::TextureView(&shadows.spot_view),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: BindingResource::TextureView(&shadows.point_view),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: BindingResource::TextureView(&self.ramps.1),
+                },
+            ],
+        })
+    }
+
     pub fn bind_gbuffer(&self, gbuffer: &GBuffer, device: &Device) -> BindGroup {
         device.create_bind_group(&BindGroupDescriptor {
             label: Some("gbuffer bind group"),
@@ -131,11 +494,143 @@ impl LightingPipeline {
                     binding: 2,
                     resource: BindingResource::TextureView(&gbuffer.color_view),
                 },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: BindingResource::TextureView(&gbuffer.depth_view),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: BindingResource::TextureView(&gbuffer.emission_view),
+                },
             ],
         })
     }
 }
 
+fn bind_volume(
+    layout: &BindGroupLayout,
+    uniform: &Buffer,
+    view: &TextureView,
+    device: &Device,
+) -> BindGroup {
+    device.create_bind_group(&BindGroupDescriptor {
+        label: Some("occupancy bind group"),
+        layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::Buffer(uniform.slice(..)),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::TextureView(view),
+            },
+        ],
+    })
+}
+
+fn bind_gi(
+    layout: &BindGroupLayout,
+    uniform: &Buffer,
+    view: &TextureView,
+    sampler: &Sampler,
+    device: &Device,
+) -> BindGroup {
+    device.create_bind_group(&BindGroupDescriptor {
+        label: Some("gi bind group"),
+        layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::Buffer(uniform.slice(..)),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::TextureView(view),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: BindingResource::Sampler(sampler),
+            },
+        ],
+    })
+}
+
+// the Lights block of lighting.frag, std140
+fn light_data(lights: &Lights, camera: &Camera, shadows: &ShadowMaps) -> Vec<u8> {
+    fn vec4(data: &mut Vec<u8>, v: Vec3, w: f32) {
+        data.extend_from_slice(v.as_byte_slice());
+        data.extend_from_slice(&w.to_le_bytes());
+    }
+
+    let settings = shadows.settings();
+    let cascades = shadows.cascades();
+    let spots = &lights.spots[..lights.spots.len().min(MAX_SPOT_LIGHTS)];
+    let points = &lights.points[..lights.points.len().min(MAX_POINT_LIGHTS)];
+    // no sun is a black one
+    let sun = lights.sun.unwrap_or(DirectionalLight {
+        direction: -Vec3::unit_y(),
+        color: Vec3::zero(),
+    });
+
+    let mut data = vec![];
+    data.extend_from_slice(camera.proj().inversed().as_byte_slice());
+    for i in 0..MAX_CASCADES {
+        let matrix = cascades.get(i).map_or(Mat4::identity(), |(_, m)| *m);
+        data.extend_from_slice(matrix.as_byte_slice());
+    }
+    let mut splits = [0.0; MAX_CASCADES];
+    for (split, (far, _)) in splits.iter_mut().zip(cascades) {
+        *split = *far;
+    }
+    vec4(
+        &mut data,
+        Vec3::new(splits[0], splits[1], splits[2]),
+        splits[3],
+    );
+    vec4(&mut data, camera.eye, cascades.len() as f32);
+    vec4(&mut data, lights.ambient, spots.len() as f32);
+    vec4(&mut data, sun.direction.normalized(), points.len() as f32);
+    vec4(&mut data, sun.color, 0.0);
+    let filter = Vec3::new(settings.pcf_radius as f32, settings.depth_bias, 0.0);
+    vec4(&mut data, filter, settings.snap_to_voxels as u32 as f32);
+    vec4(&mut data, lights.block_light, lights.voxel_size);
+
+    for (i, spot) in spots.iter().enumerate() {
+        let matrix = shadows.spots().get(i).copied().flatten();
+        data.extend_from_slice(matrix.unwrap_or_else(Mat4::identity).as_byte_slice());
+        vec4(&mut data, spot.position, spot.range);
+        vec4(
+            &mut data,
+            spot.direction.normalized(),
+            spot.outer_angle.cos(),
+        );
+        vec4(&mut data, spot.color, spot.inner_angle.cos());
+        // the layer of the spot's shadow map, negative without shadows
+        let layer = if matrix.is_some() { i as f32 } else { -1.0 };
+        vec4(&mut data, Vec3::zero(), layer);
+    }
+    data.resize(
+        LIGHTS_SIZE - (MAX_POINT_LIGHTS * POINT_SIZE + MAX_POINT_SHADOWS * 6 * 64),
+        0,
+    );
+
+    for (i, point) in points.iter().enumerate() {
+        // the slot of the point's shadow map, negative without shadows
+        let slot = shadows.points().iter().position(|(light, _)| *light == i);
+        vec4(&mut data, point.position, point.range);
+        vec4(&mut data, point.color, slot.map_or(-1.0, |s| s as f32));
+    }
+    data.resize(LIGHTS_SIZE - MAX_POINT_SHADOWS * 6 * 64, 0);
+    for (_, faces) in shadows.points() {
+        for face in faces {
+            data.extend_from_slice(face.as_byte_slice());
+        }
+    }
+    data.resize(LIGHTS_SIZE, 0);
+    data
+}
+
 struct Vertex {
     pos: Vec2,
 }
@@ -0,0 +1,220 @@
+use super::gbuffer::TextureData;
+use super::grid::WorldGrid;
+use crate::scene::Scene;
+use crate::voxel_data::VoxelData;
+use ultraviolet::*;
+use wgpu::util::DeviceExt;
+use wgpu::*;
+
+// origin and voxel size, trace settings and how they are looked up, see lighting.frag
+pub(crate) const VOLUME_UNIFORM_SIZE: BufferAddress = 3 * 16;
+
+/// What the lighting pass traces through an `OccupancyVolume`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TraceSettings {
+    /// Trace a ray to every light instead of looking it up in the shadow maps. Lights outside
+    /// the volume still cast shadows from everything inside it.
+    pub shadows: bool,
+    /// Furthest a shadow ray goes towards the sun, in voxels of the volume
+    pub max_distance: f32,
+    /// Short rays around the normal that darken the ambient light, 0 for none
+    pub ao_rays: u32,
+    /// How far occlusion rays look, in voxels of the volume
+    pub ao_distance: f32,
+    /// How much light fully occluded places lose, from 0 to 1
+    pub ao_strength: f32,
+    /// Trace once per voxel face from the center of the open voxel in front of it instead of
+    /// once per pixel, so every face is evenly lit like the rest of the shading
+    pub per_voxel: bool,
+}
+
+impl Default for TraceSettings {
+    fn default() -> Self {
+        Self {
+            shadows: true,
+            max_distance: 256.0,
+            ao_rays: 8,
+            ao_distance: 4.0,
+            ao_strength: 0.8,
+            per_voxel: true,
+        }
+    }
+}
+
+/// Which cells of an axis aligned box of the world are solid, 1 for solid and 0 for empty
+pub type OccupancyGrid = WorldGrid<u8>;
+
+impl OccupancyGrid {
+    /// Whether the cell holding a world position is solid, nothing outside the grid is
+    pub fn is_solid(&self, pos: Vec3) -> bool {
+        self.value(pos) != 0
+    }
+
+    /// Marks the cell holding a world position, positions outside the grid are ignored
+    pub fn set_solid(&mut self, pos: Vec3, solid: bool) {
+        self.set_value(pos, solid as u8);
+    }
+
+    /// Marks every cell whose center lies inside a solid voxel of a model placed by `model`,
+    /// the same matrix the model is drawn with
+    pub fn add_model(&mut self, data: &VoxelData, model: Mat4) {
+        self.add_model_with(data, model, solid);
+    }
+
+    /// Clears the grid and adds every model drawn by a scene as of its last `Scene::update`
+    pub fn fill_from_scene(&mut self, scene: &Scene) {
+        self.fill_from_scene_with(scene, solid);
+    }
+}
+
+fn solid(data: &VoxelData, [x, y, z]: [u32; 3]) -> Option<u8> {
+    if data.get(x, y, z).is_visible() {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+/// An `OccupancyGrid` on the gpu, for tracing shadow and occlusion rays in the lighting pass.
+///
+/// Edit the grid through `grid_mut`, `update` uploads it again and
+/// `LightingPipeline::bind_occupancy` binds it.
+pub struct OccupancyVolume {
+    grid: OccupancyGrid,
+    settings: TraceSettings,
+    dirty: bool,
+    tex: Texture,
+    pub(crate) view: TextureView,
+    pub(crate) uniform: Buffer,
+}
+
+impl OccupancyVolume {
+    pub fn new(grid: OccupancyGrid, settings: TraceSettings, ctx: &crate::Context) -> Self {
+        let [w, h, d] = grid.dims();
+        let (tex, view) = TextureData::indices(grid.cells(), [d, h, w]).create(ctx);
+        let uniform = ctx.device.create_buffer_init(&util::BufferInitDescriptor {
+            label: Some("occupancy volume uniform"),
+            contents: &volume_data(Some((&grid, &settings))),
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+        });
+        Self {
+            grid,
+            settings,
+            dirty: false,
+            tex,
+            view,
+            uniform,
+        }
+    }
+
+    pub fn grid(&self) -> &OccupancyGrid {
+        &self.grid
+    }
+
+    /// Edits made through this are uploaded on the next call to `update`
+    pub fn grid_mut(&mut self) -> &mut OccupancyGrid {
+        self.dirty = true;
+        &mut self.grid
+    }
+
+    pub fn settings(&self) -> &TraceSettings {
+        &self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: TraceSettings, ctx: &crate::Context) {
+        self.settings = settings;
+        let data = volume_data(Some((&self.grid, &self.settings)));
+        ctx.queue.write_buffer(&self.uniform, 0, &data);
+    }
+
+    /// Uploads the grid if it was edited since the last update
+    pub fn update(&mut self, ctx: &crate::Context) {
+        if self.dirty {
+            self.dirty = false;
+            let [w, h, d] = self.grid.dims();
+            TextureData::indices(self.grid.cells(), [d, h, w]).write(&self.tex, [0, 0, 0], ctx);
+        }
+    }
+}
+
+// the Volume block of lighting.frag, no volume turns all tracing off
+pub(crate) fn volume_data(volume: Option<(&OccupancyGrid, &TraceSettings)>) -> Vec<u8> {
+    let mut data = vec![];
+    let mut vec4 = |v: Vec3, w: f32| {
+        data.extend_from_slice(v.as_byte_slice());
+        data.extend_from_slice(&w.to_le_bytes());
+    };
+    match volume {
+        Some((grid, settings)) => {
+            let size = grid.voxel_size();
+            vec4(grid.origin(), size);
+            let trace = Vec3::new(
+                settings.shadows as u32 as f32,
+                settings.ao_rays as f32,
+                settings.ao_distance * size,
+            );
+            vec4(trace, settings.max_distance * size);
+            let lookup = Vec3::new(settings.per_voxel as u32 as f32, settings.ao_strength, 0.0);
+            vec4(lookup, 0.0);
+        }
+        None => {
+            vec4(Vec3::zero(), 1.0);
+            vec4(Vec3::zero(), 0.0);
+            vec4(Vec3::zero(), 0.0);
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxel_data::Color;
+
+    #[test]
+    fn models_fill_the_cells_they_cover() {
+        let mut data = VoxelData::empty(4, 1, 1);
+        data.set(0, 0, 0, Color::new(10, 10, 10));
+        data.set(3, 0, 0, Color::new(10, 10, 10));
+
+        let mut grid = OccupancyGrid::new(Vec3::new(-8.0, -8.0, -8.0), [16, 16, 16], 1.0);
+        grid.add_model(&data, Mat4::identity());
+        assert!(grid.is_solid(Vec3::new(0.5, 0.5, 0.5)));
+        assert!(!grid.is_solid(Vec3::new(1.5, 0.5, 0.5)));
+        assert!(grid.is_solid(Vec3::new(3.5, 0.5, 0.5)));
+
+        // a quarter turn around y swings +x over to -z
+        grid.clear();
+        let turn = Rotor3::from_rotation_xz(90f32.to_radians()).into_matrix();
+        grid.add_model(&data, turn.into_homogeneous());
+        let end = turn * Vec3::new(3.5, 0.5, 0.5);
+        assert!(grid.is_solid(end));
+        assert!(!grid.is_solid(Vec3::new(3.5, 0.5, 0.5)));
+        assert!(!grid.is_solid(turn * Vec3::new(1.5, 0.5, 0.5)));
+    }
+
+    #[test]
+    fn invisible_colors_are_not_solid() {
+        let mut data = VoxelData::empty(2, 1, 1);
+        data.set(0, 0, 0, Color::new(10, 10, 10));
+        data.set(1, 0, 0, Color::new(20, 20, 20));
+        let hidden = data.get_index(1, 0, 0);
+        data.palette_mut().set(hidden, Color::CLEAR);
+
+        let mut grid = OccupancyGrid::new(Vec3::zero(), [2, 1, 1], 1.0);
+        grid.add_model(&data, Mat4::identity());
+        assert!(grid.is_solid(Vec3::new(0.5, 0.5, 0.5)));
+        assert!(!grid.is_solid(Vec3::new(1.5, 0.5, 0.5)));
+    }
+
+    #[test]
+    fn nothing_outside_is_solid() {
+        let mut grid = OccupancyGrid::new(Vec3::zero(), [2, 2, 2], 0.5);
+        grid.set_solid(Vec3::new(0.9, 0.1, 0.1), true);
+        grid.set_solid(Vec3::new(5.0, 0.0, 0.0), true);
+        assert!(grid.is_solid(Vec3::new(0.6, 0.4, 0.4)));
+        assert!(!grid.is_solid(Vec3::new(0.4, 0.4, 0.4)));
+        assert!(!grid.is_solid(Vec3::new(5.0, 0.0, 0.0)));
+        assert_eq!(grid.cells().iter().filter(|&&c| c != 0).count(), 1);
+    }
+}
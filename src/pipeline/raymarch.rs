@@ -0,0 +1,126 @@
+use super::gbuffer::GBufferPipeline;
+use crate::include_shader;
+use std::mem;
+use ultraviolet::*;
+use wgpu::util::DeviceExt;
+use wgpu::*;
+
+/// Draws models by raymarching their index texture instead of meshing them.
+///
+/// Only the model's bounding box is rasterized, the fragment shader walks the voxels behind each
+/// pixel and writes the gbuffer and depth like a mesh would, so both paths mix in one frame and
+/// the lighting pass can't tell them apart. Meant for dense models that are expensive to mesh,
+/// pair it with `VoxelBuffer::unmeshed`.
+pub struct RaymarchPipeline {
+    pub pipeline: RenderPipeline,
+    cube: Buffer,
+}
+
+impl RaymarchPipeline {
+    /// Shares the uniform and texture layouts of `gbuffer`, so bind groups made by it work
+    /// here too
+    pub fn new(gbuffer: &GBufferPipeline, ctx: &crate::Context) -> Self {
+        let cube = ctx.device.create_buffer_init(&util::BufferInitDescriptor {
+            label: Some("raymarch bounds"),
+            contents: &cube(),
+            usage: BufferUsage::VERTEX,
+        });
+
+        let (uniform_layout, tex_layout) = gbuffer.layouts();
+        let layout = ctx
+            .device
+            .create_pipeline_layout(&PipelineLayoutDescriptor {
+                label: Some("raymarch pipeline layout"),
+                bind_group_layouts: &[uniform_layout, tex_layout],
+                push_constant_ranges: &[],
+            });
+
+        let target = |format| ColorStateDescriptor {
+            format,
+            alpha_blend: BlendDescriptor::REPLACE,
+            color_blend: BlendDescriptor::REPLACE,
+            write_mask: ColorWrite::ALL,
+        };
+        let pipeline = ctx
+            .device
+            .create_render_pipeline(&RenderPipelineDescriptor {
+                label: Some("raymarch pipeline"),
+                layout: Some(&layout),
+                vertex_stage: ProgrammableStageDescriptor {
+                    module: &ctx
+                        .device
+                        .create_shader_module(include_shader!("raymarch.vert.spv")),
+                    entry_point: "main",
+                },
+                fragment_stage: Some(ProgrammableStageDescriptor {
+                    module: &ctx
+                        .device
+                        .create_shader_module(include_shader!("raymarch.frag.spv")),
+                    entry_point: "main",
+                }),
+                // no culling, back faces are what's left when the camera is inside the bounds
+                rasterization_state: None,
+                primitive_topology: PrimitiveTopology::TriangleList,
+                // position, normals, albedo and emission like the gbuffer pipeline
+                color_states: &[
+                    target(TextureFormat::Rgba16Float),
+                    target(TextureFormat::Rgba16Float),
+                    target(TextureFormat::Rgba16Float),
+                    target(TextureFormat::Rgba16Float),
+                ],
+                depth_stencil_state: Some(DepthStencilStateDescriptor {
+                    format: TextureFormat::Depth32Float,
+                    depth_write_enabled: true,
+                    depth_compare: CompareFunction::Less,
+                    stencil: StencilStateDescriptor::default(),
+                }),
+                vertex_state: VertexStateDescriptor {
+                    index_format: IndexFormat::Uint16,
+                    vertex_buffers: &[VertexBufferDescriptor {
+                        stride: mem::size_of::<Vec3>() as BufferAddress,
+                        step_mode: InputStepMode::Vertex,
+                        attributes: &[VertexAttributeDescriptor {
+                            offset: 0,
+                            format: VertexFormat::Float3,
+                            shader_location: 0,
+                        }],
+                    }],
+                },
+                sample_count: 1,
+                sample_mask: !0,
+                alpha_to_coverage_enabled: false,
+            });
+        Self { pipeline, cube }
+    }
+
+    /// Draws a model with uniforms from `GBufferPipeline::bind_uniform` and textures from
+    /// `GBufferPipeline::bind_textures`, into a pass from `GBuffer::render`
+    pub fn render<'a>(
+        &'a self,
+        uniforms: &'a BindGroup,
+        textures: &'a BindGroup,
+        rpass: &mut RenderPass<'a>,
+    ) {
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, uniforms, &[0]);
+        rpass.set_bind_group(1, textures, &[]);
+        rpass.set_vertex_buffer(0, self.cube.slice(..));
+        rpass.draw(0..36, 0..1);
+    }
+}
+
+// the 12 triangles of the unit cube
+fn cube() -> Vec<u8> {
+    let corner = |i: usize| Vec3::new((i & 1) as f32, ((i >> 1) & 1) as f32, (i >> 2) as f32);
+    #[rustfmt::skip]
+    let faces = [
+        [0, 2, 6, 4], [1, 5, 7, 3], // -x, +x
+        [0, 4, 5, 1], [2, 3, 7, 6], // -y, +y
+        [0, 1, 3, 2], [4, 6, 7, 5], // -z, +z
+    ];
+    faces
+        .iter()
+        .flat_map(|f| [f[0], f[1], f[2], f[0], f[2], f[3]])
+        .flat_map(|i| corner(i).as_byte_slice().to_vec())
+        .collect()
+}
@@ -0,0 +1,671 @@
+use super::gbuffer::{GBufferPipeline, InstanceBuffer};
+use super::lighting::{Lights, PointLight, SpotLight, MAX_POINT_LIGHTS, MAX_SPOT_LIGHTS};
+use crate::include_shader;
+use crate::scene::Scene;
+use crate::voxel_data::VoxelBuffer;
+use crate::Camera;
+use std::num::NonZeroU32;
+use ultraviolet::*;
+use wgpu::*;
+
+/// Most cascades the sun can split its shadows into
+pub const MAX_CASCADES: usize = 4;
+
+/// Most point lights that can cast shadows in one frame
+pub const MAX_POINT_SHADOWS: usize = 4;
+
+// a light's view and projection
+const PASS_UNIFORM_SIZE: BufferAddress = 64;
+
+/// How shadows are rendered and looked up
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ShadowSettings {
+    /// Width and height of the sun's and the spot lights' shadow maps
+    pub resolution: u32,
+    /// Width and height of each of the six faces of a point light's shadow map
+    pub point_resolution: u32,
+    /// Number of point lights that get shadows each frame, at most `MAX_POINT_SHADOWS`. Every
+    /// one of them costs six passes, see `PointLight::priority` for how they are picked.
+    pub point_shadows: u32,
+    /// Number of maps the sun's shadows are split into along the view, at most `MAX_CASCADES`.
+    /// Near cascades cover less ground so they get more texels per voxel.
+    pub cascades: u32,
+    /// How far from the camera the sun still casts shadows
+    pub distance: f32,
+    /// How far past the visible area things still cast shadows from the sun
+    pub caster_distance: f32,
+    /// Texels sampled in every direction to soften edges, 0 for hard edges
+    pub pcf_radius: u32,
+    /// Depth offset against shadow acne, in the depth units of the map
+    pub depth_bias: f32,
+    /// Look shadows up once per voxel face, at the center of the open voxel in front of it,
+    /// instead of once per pixel. Every face is then either lit or shadowed, which keeps shadow
+    /// edges as blocky as the models casting them.
+    pub snap_to_voxels: bool,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 2048,
+            point_resolution: 512,
+            point_shadows: 2,
+            cascades: 3,
+            distance: 200.0,
+            caster_distance: 100.0,
+            pcf_radius: 1,
+            depth_bias: 0.001,
+            snap_to_voxels: true,
+        }
+    }
+}
+
+/// Draws models into shadow maps, depth only and from the view of a light
+pub struct ShadowPipeline {
+    pub pipeline: RenderPipeline,
+    pass_layout: BindGroupLayout,
+    // a single untransformed instance for the non-instanced draws
+    identity: InstanceBuffer,
+}
+
+impl ShadowPipeline {
+    /// Shares the uniform layout of `gbuffer`, so scenes and uniforms made for it draw here too.
+    /// Only the model matrix is used, the light's view replaces the camera's.
+    pub fn new(gbuffer: &GBufferPipeline, ctx: &crate::Context) -> Self {
+        let pass_layout = ctx
+            .device
+            .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("shadow pass layout"),
+                entries: &[BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStage::VERTEX,
+                    ty: BindingType::UniformBuffer {
+                        dynamic: false,
+                        min_binding_size: BufferSize::new(PASS_UNIFORM_SIZE),
+                    },
+                    count: None,
+                }],
+            });
+
+        let (uniform_layout, _) = gbuffer.layouts();
+        let layout = ctx
+            .device
+            .create_pipeline_layout(&PipelineLayoutDescriptor {
+                label: Some("shadow pipeline layout"),
+                bind_group_layouts: &[uniform_layout, &pass_layout],
+                push_constant_ranges: &[],
+            });
+        let pipeline = ctx
+            .device
+            .create_render_pipeline(&RenderPipelineDescriptor {
+                label: Some("shadow pipeline"),
+                layout: Some(&layout),
+                vertex_stage: ProgrammableStageDescriptor {
+                    module: &ctx
+                        .device
+                        .create_shader_module(include_shader!("shadow.vert.spv")),
+                    entry_point: "main",
+                },
+                fragment_stage: None,
+                rasterization_state: Some(RasterizationStateDescriptor {
+                    front_face: FrontFace::Ccw,
+                    cull_mode: CullMode::None,
+                    clamp_depth: false,
+                    // slopes get more bias than faces looking straight at the light
+                    depth_bias: 2,
+                    depth_bias_slope_scale: 2.0,
+                    depth_bias_clamp: 0.0,
+                }),
+                primitive_topology: PrimitiveTopology::TriangleList,
+                color_states: &[],
+                depth_stencil_state: Some(DepthStencilStateDescriptor {
+                    format: TextureFormat::Depth32Float,
+                    depth_write_enabled: true,
+                    depth_compare: CompareFunction::Less,
+                    stencil: StencilStateDescriptor::default(),
+                }),
+                vertex_state: VertexStateDescriptor {
+                    index_format: IndexFormat::Uint16,
+                    vertex_buffers: &[
+                        super::gbuffer::Vertex::desc(),
+                        super::gbuffer::Instance::desc(),
+                    ],
+                },
+                sample_count: 1,
+                sample_mask: !0,
+                alpha_to_coverage_enabled: false,
+            });
+        Self {
+            pipeline,
+            pass_layout,
+            identity: InstanceBuffer::new(&[Default::default()], ctx),
+        }
+    }
+
+    pub fn render_voxels<'a>(
+        &'a self,
+        voxels: &'a VoxelBuffer,
+        uniforms: &'a BindGroup,
+        pass: &'a ShadowPass,
+        rpass: &mut RenderPass<'a>,
+    ) {
+        self.render_voxels_instanced(voxels, &self.identity, uniforms, pass, rpass);
+    }
+
+    /// Draws every instance of a model, like `GBufferPipeline::render_voxels_instanced`
+    pub fn render_voxels_instanced<'a>(
+        &'a self,
+        voxels: &'a VoxelBuffer,
+        instances: &'a InstanceBuffer,
+        uniforms: &'a BindGroup,
+        pass: &'a ShadowPass,
+        rpass: &mut RenderPass<'a>,
+    ) {
+        if instances.is_empty() {
+            return;
+        }
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, uniforms, &[0]);
+        rpass.set_bind_group(1, &pass.bind, &[]);
+        rpass.set_vertex_buffer(1, instances.buffer().slice(..));
+        for (vbuf, ibuf, icnt) in voxels.meshes() {
+            rpass.set_index_buffer(ibuf.slice(..));
+            rpass.set_vertex_buffer(0, vbuf.slice(..));
+            rpass.draw_indexed(0..icnt, 0, 0..instances.len());
+        }
+    }
+
+    /// Draws every node of a scene as of its last `Scene::update`
+    pub fn render_scene<'a>(
+        &'a self,
+        scene: &'a Scene,
+        pass: &'a ShadowPass,
+        rpass: &mut RenderPass<'a>,
+    ) {
+        let (uniforms, draws) = match scene.draws() {
+            Some(draws) => draws,
+            None => return,
+        };
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(1, &pass.bind, &[]);
+        rpass.set_vertex_buffer(1, self.identity.buffer().slice(..));
+        for (voxels, _, offset) in draws {
+            rpass.set_bind_group(0, uniforms, &[offset]);
+            for (vbuf, ibuf, icnt) in voxels.meshes() {
+                rpass.set_index_buffer(ibuf.slice(..));
+                rpass.set_vertex_buffer(0, vbuf.slice(..));
+                rpass.draw_indexed(0..icnt, 0, 0..1);
+            }
+        }
+    }
+}
+
+/// One layer of a shadow map along with the light view it is drawn from
+pub struct ShadowPass {
+    view: TextureView,
+    uniform: Buffer,
+    bind: BindGroup,
+}
+
+impl ShadowPass {
+    /// Starts a depth only pass into this map, draw into it with `ShadowPipeline`
+    pub fn render<'a>(&'a self, encoder: &'a mut CommandEncoder) -> RenderPass<'a> {
+        encoder.begin_render_pass(&RenderPassDescriptor {
+            color_attachments: &[],
+            depth_stencil_attachment: Some(RenderPassDepthStencilAttachmentDescriptor {
+                attachment: &self.view,
+                depth_ops: Some(Operations {
+                    load: LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        })
+    }
+}
+
+/// Shadow maps of the sun's cascades, of every shadowed spot light and of the point lights
+/// picked to cast shadows this frame.
+///
+/// Each kind lives in the layers of one texture array so the lighting pass binds them all at
+/// once. Call `LightingPipeline::update` every frame to fit the maps to the camera and the
+/// lights, then draw casters into every active pass, for example with `render_scene`.
+pub struct ShadowMaps {
+    settings: ShadowSettings,
+    pub sun_tex: Texture,
+    pub sun_view: TextureView,
+    pub spot_tex: Texture,
+    pub spot_view: TextureView,
+    /// Six faces for every point light, in the order of `CUBE_FACES`
+    pub point_tex: Texture,
+    pub point_view: TextureView,
+    // the sun's cascades, then the spot lights, then the point light faces
+    passes: Vec<ShadowPass>,
+    // the matrix of every pass that is drawn this frame
+    matrices: Vec<Option<Mat4>>,
+    // view distance where every cascade ends and the matrix it was drawn with
+    cascades: Vec<(f32, Mat4)>,
+    // matrix of every lit spot in `Lights::spots`, `None` if it doesn't cast shadows
+    spots: Vec<Option<Mat4>>,
+    // index in `Lights::points` and face matrices of every point light given a shadow map
+    points: Vec<(usize, [Mat4; 6])>,
+}
+
+impl ShadowMaps {
+    pub fn new(settings: ShadowSettings, pipeline: &ShadowPipeline, ctx: &crate::Context) -> Self {
+        check_limits(&settings);
+        let (sun_tex, sun_view) = create_maps(settings.resolution, MAX_CASCADES, &ctx.device);
+        let (spot_tex, spot_view) = create_maps(settings.resolution, MAX_SPOT_LIGHTS, &ctx.device);
+        let point_faces = MAX_POINT_SHADOWS * 6;
+        let (point_tex, point_view) =
+            create_maps(settings.point_resolution, point_faces, &ctx.device);
+        let layer_view = |tex: &Texture, layer| {
+            tex.create_view(&TextureViewDescriptor {
+                label: Some("shadow map layer"),
+                format: Some(TextureFormat::Depth32Float),
+                dimension: Some(TextureViewDimension::D2),
+                aspect: TextureAspect::All,
+                base_mip_level: 0,
+                level_count: None,
+                base_array_layer: layer as u32,
+                array_layer_count: NonZeroU32::new(1),
+            })
+        };
+        let passes = (0..MAX_CASCADES)
+            .map(|layer| layer_view(&sun_tex, layer))
+            .chain((0..MAX_SPOT_LIGHTS).map(|layer| layer_view(&spot_tex, layer)))
+            .chain((0..point_faces).map(|layer| layer_view(&point_tex, layer)))
+            .map(|view| {
+                let uniform = ctx.device.create_buffer(&BufferDescriptor {
+                    label: Some("shadow pass uniform"),
+                    size: PASS_UNIFORM_SIZE,
+                    usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                let bind = ctx.device.create_bind_group(&BindGroupDescriptor {
+                    label: Some("shadow pass bind group"),
+                    layout: &pipeline.pass_layout,
+                    entries: &[BindGroupEntry {
+                        binding: 0,
+                        resource: BindingResource::Buffer(uniform.slice(..)),
+                    }],
+                });
+                ShadowPass {
+                    view,
+                    uniform,
+                    bind,
+                }
+            })
+            .collect();
+        Self {
+            settings,
+            sun_tex,
+            sun_view,
+            spot_tex,
+            spot_view,
+            point_tex,
+            point_view,
+            passes,
+            matrices: vec![],
+            cascades: vec![],
+            spots: vec![],
+            points: vec![],
+        }
+    }
+
+    pub fn settings(&self) -> &ShadowSettings {
+        &self.settings
+    }
+
+    /// Returns `true` if the maps were recreated, which happens when a resolution changes.
+    /// Bind groups made from them with `LightingPipeline::bind_lights` have to be rebuilt then.
+    pub fn set_settings(
+        &mut self,
+        settings: ShadowSettings,
+        pipeline: &ShadowPipeline,
+        ctx: &crate::Context,
+    ) -> bool {
+        if settings.resolution != self.settings.resolution
+            || settings.point_resolution != self.settings.point_resolution
+        {
+            *self = Self::new(settings, pipeline, ctx);
+            return true;
+        }
+        check_limits(&settings);
+        self.settings = settings;
+        false
+    }
+
+    /// Fits the maps to the camera and the lights and uploads their matrices
+    pub(crate) fn update(&mut self, lights: &Lights, camera: &Camera, ctx: &crate::Context) {
+        self.cascades.clear();
+        if let Some(sun) = &lights.sun {
+            let splits = cascade_splits(
+                camera.znear,
+                self.settings.distance,
+                self.settings.cascades as usize,
+            );
+            let mut near = camera.znear;
+            for far in splits {
+                let matrix = cascade_matrix(
+                    camera,
+                    sun.direction,
+                    near,
+                    far,
+                    self.settings.resolution,
+                    self.settings.caster_distance,
+                );
+                self.cascades.push((far, matrix));
+                near = far;
+            }
+        }
+        self.spots = lights
+            .spots
+            .iter()
+            .take(MAX_SPOT_LIGHTS)
+            .map(|spot| Some(spot_matrix(spot)).filter(|_| spot.shadows))
+            .collect();
+
+        let budget = self.settings.point_shadows as usize;
+        let points = &lights.points[..lights.points.len().min(MAX_POINT_LIGHTS)];
+        self.points = pick_point_shadows(points, camera.eye, budget)
+            .into_iter()
+            .map(|i| (i, point_matrices(&points[i])))
+            .collect();
+
+        let mut matrices = vec![None; self.passes.len()];
+        for (i, (_, matrix)) in self.cascades.iter().enumerate() {
+            matrices[i] = Some(*matrix);
+        }
+        for (i, matrix) in self.spots.iter().enumerate() {
+            matrices[MAX_CASCADES + i] = *matrix;
+        }
+        for (slot, (_, faces)) in self.points.iter().enumerate() {
+            for (face, matrix) in faces.iter().enumerate() {
+                matrices[MAX_CASCADES + MAX_SPOT_LIGHTS + slot * 6 + face] = Some(*matrix);
+            }
+        }
+        for (pass, matrix) in self.passes.iter().zip(&matrices) {
+            if let Some(matrix) = matrix {
+                ctx.queue
+                    .write_buffer(&pass.uniform, 0, matrix.as_byte_slice());
+            }
+        }
+        self.matrices = matrices;
+    }
+
+    /// The passes that have to be drawn this frame, as of the last update
+    pub fn passes(&self) -> impl Iterator<Item = &ShadowPass> {
+        self.passes
+            .iter()
+            .zip(&self.matrices)
+            .filter(|(_, matrix)| matrix.is_some())
+            .map(|(pass, _)| pass)
+    }
+
+    /// Draws a scene into every active pass
+    pub fn render_scene(
+        &self,
+        pipeline: &ShadowPipeline,
+        scene: &Scene,
+        encoder: &mut CommandEncoder,
+    ) {
+        for pass in self.passes() {
+            let mut rpass = pass.render(encoder);
+            pipeline.render_scene(scene, pass, &mut rpass);
+        }
+    }
+
+    pub(crate) fn cascades(&self) -> &[(f32, Mat4)] {
+        &self.cascades
+    }
+
+    pub(crate) fn spots(&self) -> &[Option<Mat4>] {
+        &self.spots
+    }
+
+    pub(crate) fn points(&self) -> &[(usize, [Mat4; 6])] {
+        &self.points
+    }
+}
+
+fn check_limits(settings: &ShadowSettings) {
+    assert!(
+        settings.cascades as usize <= MAX_CASCADES,
+        "the sun has at most {} cascades",
+        MAX_CASCADES
+    );
+    assert!(
+        settings.point_shadows as usize <= MAX_POINT_SHADOWS,
+        "at most {} point lights can cast shadows",
+        MAX_POINT_SHADOWS
+    );
+}
+
+fn create_maps(resolution: u32, layers: usize, device: &Device) -> (Texture, TextureView) {
+    let tex = device.create_texture(&TextureDescriptor {
+        label: Some("shadow maps"),
+        size: Extent3d {
+            width: resolution,
+            height: resolution,
+            depth: layers as u32,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format: TextureFormat::Depth32Float,
+        usage: TextureUsage::OUTPUT_ATTACHMENT | TextureUsage::SAMPLED,
+    });
+    let view = tex.create_view(&TextureViewDescriptor {
+        label: Some("shadow maps"),
+        format: Some(TextureFormat::Depth32Float),
+        dimension: Some(TextureViewDimension::D2Array),
+        aspect: TextureAspect::All,
+        base_mip_level: 0,
+        level_count: None,
+        base_array_layer: 0,
+        array_layer_count: NonZeroU32::new(layers as u32),
+    });
+    (tex, view)
+}
+
+// view distance where each cascade ends, halfway between even and logarithmic steps so near
+// cascades stay small without the far ones getting huge
+fn cascade_splits(near: f32, distance: f32, count: usize) -> Vec<f32> {
+    (1..=count)
+        .map(|i| {
+            let t = i as f32 / count as f32;
+            let even = near + (distance - near) * t;
+            let log = near * (distance / near).powf(t);
+            (even + log) / 2.0
+        })
+        .collect()
+}
+
+// an orthographic view along `direction` around the part of the view between `near` and
+// `far`, moved in whole texels so shadow edges don't crawl as the camera moves
+fn cascade_matrix(
+    camera: &Camera,
+    direction: Vec3,
+    near: f32,
+    far: f32,
+    resolution: u32,
+    caster_distance: f32,
+) -> Mat4 {
+    let forward = (camera.target - camera.eye).normalized();
+    let right = forward.cross(camera.up).normalized();
+    let up = right.cross(forward);
+    let tan = (camera.fovy / 2.0).tan();
+    let corners: Vec<Vec3> = [near, far]
+        .iter()
+        .flat_map(|&d| {
+            let (h, w) = (d * tan, d * tan * camera.aspect);
+            let center = camera.eye + forward * d;
+            vec![
+                center - right * w - up * h,
+                center + right * w - up * h,
+                center + right * w + up * h,
+                center - right * w + up * h,
+            ]
+        })
+        .collect();
+    let center = corners.iter().fold(Vec3::zero(), |sum, &c| sum + c) / 8.0;
+    // a bounding sphere keeps the size the same however the camera turns
+    let radius = corners
+        .iter()
+        .map(|&c| (c - center).mag())
+        .fold(0.0, f32::max);
+    let radius = (radius * 16.0).ceil() / 16.0;
+
+    let direction = direction.normalized();
+    let light_up = if direction.y.abs() > 0.99 {
+        Vec3::unit_z()
+    } else {
+        Vec3::unit_y()
+    };
+    let rotation = Mat4::look_at(Vec3::zero(), direction, light_up);
+    let texel = radius * 2.0 / resolution as f32;
+    let mut snapped = rotation.transform_point3(center);
+    snapped.x = (snapped.x / texel).floor() * texel;
+    snapped.y = (snapped.y / texel).floor() * texel;
+    let center = rotation.inversed().transform_point3(snapped);
+
+    let back = radius + caster_distance;
+    let view = Mat4::look_at(center - direction * back, center, light_up);
+    let proj =
+        projection::orthographic_wgpu_dx(-radius, radius, -radius, radius, 0.0, back + radius);
+    proj * view
+}
+
+fn spot_matrix(spot: &SpotLight) -> Mat4 {
+    let direction = spot.direction.normalized();
+    let up = if direction.y.abs() > 0.99 {
+        Vec3::unit_z()
+    } else {
+        Vec3::unit_y()
+    };
+    let view = Mat4::look_at(spot.position, spot.position + direction, up);
+    let proj = projection::perspective_wgpu_dx(spot.outer_angle * 2.0, 1.0, 0.1, spot.range);
+    proj * view
+}
+
+/// The way every face of a point light's shadow map looks, +x, -x, +y, -y, +z and -z
+pub const CUBE_FACES: [Vec3; 6] = [
+    Vec3::new(1.0, 0.0, 0.0),
+    Vec3::new(-1.0, 0.0, 0.0),
+    Vec3::new(0.0, 1.0, 0.0),
+    Vec3::new(0.0, -1.0, 0.0),
+    Vec3::new(0.0, 0.0, 1.0),
+    Vec3::new(0.0, 0.0, -1.0),
+];
+
+// a quarter turn wide view down every face of the cube
+fn point_matrices(point: &PointLight) -> [Mat4; 6] {
+    let proj = projection::perspective_wgpu_dx(90f32.to_radians(), 1.0, 0.1, point.range);
+    let mut faces = [Mat4::identity(); 6];
+    for (matrix, &direction) in faces.iter_mut().zip(&CUBE_FACES) {
+        let up = if direction.y != 0.0 {
+            Vec3::unit_z()
+        } else {
+            Vec3::unit_y()
+        };
+        *matrix = proj * Mat4::look_at(point.position, point.position + direction, up);
+    }
+    faces
+}
+
+// the point lights that get shadows, most important first
+fn pick_point_shadows(points: &[PointLight], eye: Vec3, budget: usize) -> Vec<usize> {
+    let mut picked: Vec<(usize, f32)> = points
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| p.shadows && p.priority > 0.0 && p.range > 0.0)
+        .map(|(i, p)| {
+            // lights the camera is inside of keep their full priority
+            let outside = ((p.position - eye).mag() - p.range).max(0.0);
+            (i, p.priority / (1.0 + outside / p.range))
+        })
+        .collect();
+    picked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    picked.truncate(budget);
+    picked.into_iter().map(|(i, _)| i).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inside(matrix: Mat4, point: Vec3) -> bool {
+        let clip = matrix * point.into_homogeneous_point();
+        let ndc = clip.xyz() / clip.w;
+        ndc.x.abs() <= 1.0 && ndc.y.abs() <= 1.0 && (0.0..=1.0).contains(&ndc.z)
+    }
+
+    #[test]
+    fn cascades_cover_the_view() {
+        let splits = cascade_splits(0.1, 200.0, 3);
+        assert!(splits.windows(2).all(|w| w[0] < w[1]));
+        assert!((splits[2] - 200.0).abs() < 1e-3);
+
+        let camera = Camera::new(Vec3::new(0.0, 10.0, -50.0), Vec3::zero());
+        let forward = (camera.target - camera.eye).normalized();
+        let sun = Vec3::new(0.3, -1.0, 0.2);
+        let mut near = camera.znear;
+        for far in splits {
+            let matrix = cascade_matrix(&camera, sun, near, far, 1024, 100.0);
+            let mid = camera.eye + forward * (near + far) / 2.0;
+            assert!(inside(matrix, mid));
+            // casters between the sun and the view still land in the map
+            assert!(inside(matrix, mid - sun.normalized() * 90.0));
+            near = far;
+        }
+    }
+
+    #[test]
+    fn spots_see_down_their_cone() {
+        let spot = SpotLight {
+            position: Vec3::new(0.0, 20.0, 0.0),
+            direction: -Vec3::unit_y(),
+            range: 50.0,
+            ..SpotLight::default()
+        };
+        let matrix = spot_matrix(&spot);
+        assert!(inside(matrix, Vec3::zero()));
+        assert!(!inside(matrix, Vec3::new(0.0, 30.0, 0.0)));
+        assert!(!inside(matrix, Vec3::new(0.0, -40.0, 0.0)));
+    }
+
+    #[test]
+    fn point_faces_see_every_way() {
+        let point = PointLight {
+            position: Vec3::new(5.0, 5.0, 5.0),
+            range: 20.0,
+            ..PointLight::default()
+        };
+        let faces = point_matrices(&point);
+        for (matrix, &direction) in faces.iter().zip(&CUBE_FACES) {
+            assert!(inside(*matrix, point.position + direction * 10.0));
+            assert!(!inside(*matrix, point.position - direction * 10.0));
+        }
+    }
+
+    #[test]
+    fn point_shadows_go_to_the_most_important() {
+        let light = |x: f32, priority: f32| PointLight {
+            position: Vec3::new(x, 0.0, 0.0),
+            range: 10.0,
+            priority,
+            ..PointLight::default()
+        };
+        let mut points = vec![
+            light(0.0, 1.0),
+            light(100.0, 1.0),
+            light(100.0, 50.0),
+            light(5.0, 1.0),
+        ];
+        points[3].shadows = false;
+        assert_eq!(pick_point_shadows(&points, Vec3::zero(), 2), vec![2, 0]);
+        assert_eq!(pick_point_shadows(&points, Vec3::zero(), 8), vec![2, 0, 1]);
+        assert!(pick_point_shadows(&points, Vec3::zero(), 0).is_empty());
+    }
+}
@@ -0,0 +1,327 @@
+use crate::scene::{ModelId, NodeId, Scene, Transform};
+use crate::voxel_data::{Region, VoxelBuffer, VoxelData};
+use ultraviolet::*;
+
+mod format;
+
+pub use format::RigError;
+
+/// A named piece of a model that moves on its own
+#[derive(Debug, Clone, PartialEq)]
+pub struct Part {
+    pub name: String,
+    /// Index of the part this one hangs off, always before it in the rig
+    pub parent: Option<usize>,
+    /// The voxels of the model belonging to this part
+    pub region: Region,
+    /// Point the part rotates and scales around, in voxels of the whole model
+    pub pivot: Vec3,
+}
+
+/// A model split into parts, each posed relative to its parent.
+///
+/// A pose is one `Transform` per part applied around its pivot, the default transform leaves a
+/// part where it sits in the model.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rig {
+    parts: Vec<Part>,
+}
+
+impl Rig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a part and returns its index, the parent has to be added first
+    pub fn add_part(
+        &mut self,
+        name: &str,
+        parent: Option<&str>,
+        region: Region,
+        pivot: Vec3,
+    ) -> usize {
+        assert!(self.find(name).is_none(), "duplicate part '{}'", name);
+        let parent = parent.map(|p| {
+            self.find(p)
+                .unwrap_or_else(|| panic!("unknown parent part '{}'", p))
+        });
+        self.parts.push(Part {
+            name: name.to_string(),
+            parent,
+            region,
+            pivot,
+        });
+        self.parts.len() - 1
+    }
+
+    pub fn parts(&self) -> &[Part] {
+        &self.parts
+    }
+
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.parts.iter().position(|p| p.name == name)
+    }
+
+    /// Cuts a model into one model per part
+    pub fn split(&self, data: &VoxelData) -> Vec<VoxelData> {
+        self.parts.iter().map(|p| data.cropped(p.region)).collect()
+    }
+
+    /// Samples a clip at `time` seconds, parts without a track keep their rest pose
+    pub fn pose(&self, clip: &Clip, time: f32) -> Vec<Transform> {
+        let mut pose = vec![Transform::default(); self.parts.len()];
+        for (name, transform) in clip.sample(time) {
+            if let Some(part) = self.find(name) {
+                pose[part] = transform;
+            }
+        }
+        pose
+    }
+
+    // where a part's pivot sits relative to its parent's pivot
+    fn joint_offset(&self, part: &Part) -> Vec3 {
+        match part.parent {
+            Some(parent) => part.pivot - self.parts[parent].pivot,
+            None => part.pivot,
+        }
+    }
+
+    fn joint(&self, part: &Part, transform: &Transform) -> Transform {
+        Transform {
+            translation: self.joint_offset(part) + transform.translation,
+            ..*transform
+        }
+    }
+
+    // places a part's cropped model relative to its joint
+    fn mesh_offset(part: &Part) -> Vec3 {
+        let min = part.region.min;
+        Vec3::new(min[0] as f32, min[1] as f32, min[2] as f32) - part.pivot
+    }
+
+    /// The model matrix of every part's cropped model for a pose, relative to the whole model
+    pub fn matrices(&self, pose: &[Transform]) -> Vec<Mat4> {
+        let mut joints: Vec<Mat4> = Vec::with_capacity(self.parts.len());
+        for (part, transform) in self.parts.iter().zip(pose) {
+            let local = self.joint(part, transform).matrix();
+            let joint = match part.parent {
+                Some(parent) => joints[parent] * local,
+                None => local,
+            };
+            joints.push(joint);
+        }
+        self.parts
+            .iter()
+            .zip(joints)
+            .map(|(part, joint)| joint * Mat4::from_translation(Self::mesh_offset(part)))
+            .collect()
+    }
+
+    /// Splits a model and adds every part to a scene, returns the models in part order for
+    /// `spawn`
+    pub fn add_models(
+        &self,
+        data: &VoxelData,
+        scene: &mut Scene,
+        ctx: &crate::Context,
+    ) -> Vec<ModelId> {
+        self.split(data)
+            .into_iter()
+            .map(|part| scene.add_model(VoxelBuffer::from_data(part, ctx)))
+            .collect()
+    }
+
+    /// Adds a posable copy of the rig to a scene, `transform` places the whole model
+    pub fn spawn(
+        &self,
+        scene: &mut Scene,
+        models: &[ModelId],
+        parent: Option<NodeId>,
+        transform: Transform,
+    ) -> RigInstance {
+        assert_eq!(models.len(), self.parts.len(), "one model per part");
+        let root = scene.add_node(parent, transform, None);
+        let mut joints: Vec<NodeId> = Vec::with_capacity(self.parts.len());
+        for (part, &model) in self.parts.iter().zip(models) {
+            let parent = part.parent.map_or(root, |p| joints[p]);
+            let joint = scene.add_node(Some(parent), self.joint(part, &Transform::default()), None);
+            scene.add_node(
+                Some(joint),
+                Transform::from_translation(Self::mesh_offset(part)),
+                Some(model),
+            );
+            joints.push(joint);
+        }
+        RigInstance { root, joints }
+    }
+}
+
+/// A rig placed in a scene, see `Rig::spawn`
+#[derive(Debug, Clone)]
+pub struct RigInstance {
+    root: NodeId,
+    // one node per part, the part's model hangs off it
+    joints: Vec<NodeId>,
+}
+
+impl RigInstance {
+    /// The node placing the whole model, move this one around
+    pub fn root(&self) -> NodeId {
+        self.root
+    }
+
+    /// The node a part rotates around, for attaching things like a held item
+    pub fn joint(&self, part: usize) -> NodeId {
+        self.joints[part]
+    }
+
+    /// Poses the parts, see `Rig::pose`
+    pub fn apply(&self, scene: &mut Scene, rig: &Rig, pose: &[Transform]) {
+        for ((part, transform), &joint) in rig.parts.iter().zip(pose).zip(&self.joints) {
+            *scene.transform_mut(joint) = rig.joint(part, transform);
+        }
+    }
+
+    /// Removes the rig's nodes from the scene, the models stay
+    pub fn remove(self, scene: &mut Scene) {
+        scene.remove_node(self.root);
+    }
+}
+
+/// A part's transform at a point in time
+#[derive(Debug, Copy, Clone)]
+pub struct Keyframe {
+    pub time: f32,
+    pub transform: Transform,
+}
+
+/// The keyframes of one part, sorted by time
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub part: String,
+    pub keys: Vec<Keyframe>,
+}
+
+impl Track {
+    /// Interpolates between the keys around `time`, holding the first and last key outside them
+    pub fn sample(&self, time: f32) -> Transform {
+        let next = self.keys.iter().position(|k| k.time > time);
+        match next {
+            None => self
+                .keys
+                .last()
+                .map_or_else(Transform::default, |k| k.transform),
+            Some(0) => self.keys[0].transform,
+            Some(i) => {
+                let (a, b) = (&self.keys[i - 1], &self.keys[i]);
+                let t = (time - a.time) / (b.time - a.time);
+                a.transform.lerp(&b.transform, t)
+            }
+        }
+    }
+}
+
+/// A named animation of some parts of a rig, see `Clip::parse` for the file format
+#[derive(Debug, Clone)]
+pub struct Clip {
+    pub name: String,
+    /// Length in seconds
+    pub duration: f32,
+    /// Whether playback wraps around after `duration` or holds the last pose
+    pub looping: bool,
+    pub tracks: Vec<Track>,
+}
+
+impl Clip {
+    /// The transform of every animated part at `time` seconds into the clip
+    pub fn sample(&self, time: f32) -> impl Iterator<Item = (&str, Transform)> {
+        let time = if self.looping && self.duration > 0.0 {
+            time.rem_euclid(self.duration)
+        } else {
+            time.clamp(0.0, self.duration)
+        };
+        self.tracks
+            .iter()
+            .map(move |t| (t.part.as_str(), t.sample(time)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).mag() < 1e-4
+    }
+
+    fn arm() -> Rig {
+        let mut rig = Rig::new();
+        rig.add_part(
+            "body",
+            None,
+            Region::new([0, 0, 0], [4, 8, 4]),
+            Vec3::new(2.0, 0.0, 2.0),
+        );
+        rig.add_part(
+            "arm",
+            Some("body"),
+            Region::new([4, 6, 0], [10, 8, 2]),
+            Vec3::new(4.0, 7.0, 1.0),
+        );
+        rig
+    }
+
+    #[test]
+    fn rest_pose_keeps_parts_in_place() {
+        let rig = arm();
+        let matrices = rig.matrices(&[Transform::default(); 2]);
+        let origin = |m: &Mat4| m.transform_point3(Vec3::zero());
+        assert!(close(origin(&matrices[0]), Vec3::zero()));
+        assert!(close(origin(&matrices[1]), Vec3::new(4.0, 6.0, 0.0)));
+    }
+
+    #[test]
+    fn parts_rotate_around_pivots() {
+        let rig = arm();
+        let mut pose = vec![Transform::default(); 2];
+        // raise the arm a quarter turn around z at the shoulder
+        pose[1].rotation = Rotor3::from_rotation_xy(90f32.to_radians());
+        let matrices = rig.matrices(&pose);
+        let hand = matrices[1].transform_point3(Vec3::new(6.0, 1.0, 1.0));
+        assert!(close(hand, Vec3::new(4.0, 13.0, 1.0)));
+
+        // moving the body carries the arm along
+        pose[0].translation = Vec3::unit_x() * 5.0;
+        let moved = rig.matrices(&pose)[1].transform_point3(Vec3::new(6.0, 1.0, 1.0));
+        assert!(close(moved, hand + Vec3::unit_x() * 5.0));
+    }
+
+    #[test]
+    fn clips_interpolate() {
+        let key = |time, x| Keyframe {
+            time,
+            transform: Transform::from_translation(Vec3::unit_x() * x),
+        };
+        let mut clip = Clip {
+            name: "slide".to_string(),
+            duration: 2.0,
+            looping: false,
+            tracks: vec![Track {
+                part: "arm".to_string(),
+                keys: vec![key(0.0, 0.0), key(1.0, 4.0)],
+            }],
+        };
+        let pose = arm().pose(&clip, 0.25);
+        assert!(close(pose[0].translation, Vec3::zero()));
+        assert!(close(pose[1].translation, Vec3::unit_x()));
+        assert!(close(
+            arm().pose(&clip, 5.0)[1].translation,
+            Vec3::unit_x() * 4.0
+        ));
+        clip.looping = true;
+        assert!(close(
+            arm().pose(&clip, 2.5)[1].translation,
+            Vec3::unit_x() * 2.0
+        ));
+    }
+}
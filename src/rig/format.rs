@@ -0,0 +1,364 @@
+use super::{Clip, Keyframe, Rig, Track};
+use crate::scene::Transform;
+use crate::voxel_data::Region;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use ultraviolet::{Rotor3, Vec3};
+
+/// Why a rig or clip file couldn't be read
+#[derive(Debug)]
+pub enum RigError {
+    Io(io::Error),
+    /// A line that doesn't make sense, lines are counted from 1
+    Parse {
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for RigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RigError::Io(err) => write!(f, "{}", err),
+            RigError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for RigError {}
+
+impl From<io::Error> for RigError {
+    fn from(err: io::Error) -> Self {
+        RigError::Io(err)
+    }
+}
+
+fn parse_error(line: usize, message: impl Into<String>) -> RigError {
+    RigError::Parse {
+        line: line + 1,
+        message: message.into(),
+    }
+}
+
+// the words of a line after its keyword, consumed front to back
+struct Words<'a> {
+    line: usize,
+    words: std::str::SplitWhitespace<'a>,
+}
+
+impl<'a> Words<'a> {
+    fn next(&mut self) -> Option<&'a str> {
+        self.words.next()
+    }
+
+    fn word(&mut self, what: &str) -> Result<&'a str, RigError> {
+        self.next()
+            .ok_or_else(|| parse_error(self.line, format!("expected {}", what)))
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, what: &str) -> Result<T, RigError> {
+        let word = self.word(what)?;
+        word.parse()
+            .map_err(|_| parse_error(self.line, format!("'{}' is not a valid {}", word, what)))
+    }
+
+    fn vec3(&mut self, what: &str) -> Result<Vec3, RigError> {
+        Ok(Vec3::new(
+            self.number(what)?,
+            self.number(what)?,
+            self.number(what)?,
+        ))
+    }
+}
+
+// non-empty lines without comments, split into their keyword and the rest
+fn lines(text: &str) -> impl Iterator<Item = (&str, Words<'_>)> {
+    text.lines().enumerate().filter_map(|(line, text)| {
+        let text = text.split('#').next().unwrap_or("");
+        let mut words = text.split_whitespace();
+        let keyword = words.next()?;
+        Some((keyword, Words { line, words }))
+    })
+}
+
+impl Rig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, RigError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), RigError> {
+        std::fs::write(path, self.to_text())?;
+        Ok(())
+    }
+
+    /// Reads one part per line, parents before their children. Everything after `#` is a
+    /// comment.
+    ///
+    /// ```text
+    /// # name   parent        region corners          pivot
+    /// part body               region 0 0 0 8 10 4    pivot 4 5 2
+    /// part head parent body   region 1 10 0 7 16 4   pivot 4 10 2
+    /// ```
+    pub fn parse(text: &str) -> Result<Self, RigError> {
+        let mut rig = Rig::new();
+        for (keyword, mut words) in lines(text) {
+            if keyword != "part" {
+                return Err(parse_error(
+                    words.line,
+                    format!("expected 'part', found '{}'", keyword),
+                ));
+            }
+            let name = words.word("a part name")?;
+            if rig.find(name).is_some() {
+                return Err(parse_error(
+                    words.line,
+                    format!("duplicate part '{}'", name),
+                ));
+            }
+            let (mut parent, mut region, mut pivot) = (None, None, None);
+            while let Some(field) = words.next() {
+                match field {
+                    "parent" => {
+                        let name = words.word("a parent name")?;
+                        parent = Some(name);
+                        if rig.find(name).is_none() {
+                            return Err(parse_error(
+                                words.line,
+                                format!("parent '{}' has to be defined first", name),
+                            ));
+                        }
+                    }
+                    "region" => {
+                        let mut corner = || -> Result<[u32; 3], RigError> {
+                            Ok([
+                                words.number("voxel coordinate")?,
+                                words.number("voxel coordinate")?,
+                                words.number("voxel coordinate")?,
+                            ])
+                        };
+                        region = Some(Region::new(corner()?, corner()?));
+                    }
+                    "pivot" => pivot = Some(words.vec3("pivot coordinate")?),
+                    field => {
+                        return Err(parse_error(
+                            words.line,
+                            format!("unknown field '{}'", field),
+                        ))
+                    }
+                }
+            }
+            let region = region.ok_or_else(|| parse_error(words.line, "missing region"))?;
+            rig.add_part(name, parent, region, pivot.unwrap_or_else(Vec3::zero));
+        }
+        Ok(rig)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for part in &self.parts {
+            text += &format!("part {}", part.name);
+            if let Some(parent) = part.parent {
+                text += &format!(" parent {}", self.parts[parent].name);
+            }
+            let [min, max] = [part.region.min, part.region.max];
+            text += &format!(
+                " region {} {} {} {} {} {} pivot {} {} {}\n",
+                min[0],
+                min[1],
+                min[2],
+                max[0],
+                max[1],
+                max[2],
+                part.pivot.x,
+                part.pivot.y,
+                part.pivot.z
+            );
+        }
+        text
+    }
+}
+
+impl Clip {
+    /// Loads every clip in a file, see `parse`
+    pub fn load(path: impl AsRef<Path>) -> Result<Vec<Self>, RigError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Reads any number of clips. A clip has a name, a duration in seconds and optionally
+    /// `loop`, followed by a track per animated part. Keys have a time and any of `translate`
+    /// in voxels, `rotate` as roll, pitch and yaw in degrees and `scale` as one or three
+    /// factors. Everything after `#` is a comment.
+    ///
+    /// ```text
+    /// clip wave 1.5 loop
+    /// track arm_left
+    /// key 0    rotate 0 0 0
+    /// key 0.75 rotate 0 90 0 translate 0 1 0
+    /// key 1.5  rotate 0 0 0
+    /// ```
+    pub fn parse(text: &str) -> Result<Vec<Self>, RigError> {
+        let mut clips: Vec<Clip> = vec![];
+        for (keyword, mut words) in lines(text) {
+            let line = words.line;
+            match keyword {
+                "clip" => {
+                    let name = words.word("a clip name")?.to_string();
+                    let duration: f32 = words.number("duration")?;
+                    if !duration.is_finite() || duration < 0.0 {
+                        return Err(parse_error(
+                            line,
+                            format!("duration {} has to be a number of seconds", duration),
+                        ));
+                    }
+                    let looping = match words.next() {
+                        None => false,
+                        Some("loop") => true,
+                        Some(word) => {
+                            return Err(parse_error(
+                                line,
+                                format!("expected 'loop', found '{}'", word),
+                            ))
+                        }
+                    };
+                    clips.push(Clip {
+                        name,
+                        duration,
+                        looping,
+                        tracks: vec![],
+                    });
+                }
+                "track" => {
+                    let clip = clips
+                        .last_mut()
+                        .ok_or_else(|| parse_error(line, "track outside of a clip"))?;
+                    clip.tracks.push(Track {
+                        part: words.word("a part name")?.to_string(),
+                        keys: vec![],
+                    });
+                }
+                "key" => {
+                    let track = clips
+                        .last_mut()
+                        .and_then(|c| c.tracks.last_mut())
+                        .ok_or_else(|| parse_error(line, "key outside of a track"))?;
+                    let key = parse_key(&mut words)?;
+                    track.keys.push(key);
+                }
+                keyword => return Err(parse_error(line, format!("unknown keyword '{}'", keyword))),
+            }
+        }
+        for track in clips.iter_mut().flat_map(|c| &mut c.tracks) {
+            track.keys.sort_by(|a, b| a.time.total_cmp(&b.time));
+        }
+        Ok(clips)
+    }
+}
+
+fn parse_key(words: &mut Words) -> Result<Keyframe, RigError> {
+    let time: f32 = words.number("time")?;
+    if !time.is_finite() {
+        return Err(parse_error(
+            words.line,
+            format!("key time {} has to be a number", time),
+        ));
+    }
+    let mut transform = Transform::default();
+    while let Some(field) = words.next() {
+        match field {
+            "translate" => transform.translation = words.vec3("translation")?,
+            "rotate" => {
+                let angles = words.vec3("angle")?;
+                transform.rotation = Rotor3::from_euler_angles(
+                    angles.x.to_radians(),
+                    angles.y.to_radians(),
+                    angles.z.to_radians(),
+                );
+            }
+            "scale" => {
+                let scale = words.number("scale")?;
+                // one factor scales evenly, three scale each axis
+                let rest = words.words.clone().take(2);
+                transform.scale = match rest.map(str::parse::<f32>).collect::<Vec<_>>()[..] {
+                    [Ok(y), Ok(z)] => {
+                        words.next();
+                        words.next();
+                        Vec3::new(scale, y, z)
+                    }
+                    _ => Vec3::broadcast(scale),
+                };
+            }
+            field => {
+                return Err(parse_error(
+                    words.line,
+                    format!("unknown field '{}'", field),
+                ))
+            }
+        }
+    }
+    Ok(Keyframe { time, transform })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RIG: &str = "
+        # the link model
+        part body region 0 0 0 8 10 4 pivot 4 5 2
+        part head parent body region 1 10 0 7 16 4 pivot 4 10 2
+    ";
+
+    const CLIPS: &str = "
+        clip nod 1 loop
+        track head
+        key 0.5 rotate 0 30 0   # halfway
+        key 0 scale 1
+        key 1 scale 1 2 1 translate 0 1 0
+
+        clip idle 2
+    ";
+
+    #[test]
+    fn files_parse() {
+        let rig = Rig::parse(RIG).unwrap();
+        assert_eq!(rig.parts().len(), 2);
+        assert_eq!(rig.parts()[1].parent, Some(0));
+        assert_eq!(rig.parts()[1].region, Region::new([1, 10, 0], [7, 16, 4]));
+        assert_eq!(Rig::parse(&rig.to_text()).unwrap(), rig);
+
+        let clips = Clip::parse(CLIPS).unwrap();
+        assert_eq!(clips.len(), 2);
+        assert!(clips[0].looping && !clips[1].looping);
+        let keys = &clips[0].tracks[0].keys;
+        assert_eq!(
+            keys.iter().map(|k| k.time).collect::<Vec<_>>(),
+            [0.0, 0.5, 1.0]
+        );
+        assert_eq!(keys[2].transform.scale, Vec3::new(1.0, 2.0, 1.0));
+        assert_eq!(keys[2].transform.translation, Vec3::unit_y());
+        assert_eq!(keys[0].transform.scale, Vec3::one());
+    }
+
+    #[test]
+    fn bad_files_are_errors() {
+        match Rig::parse("part a region 0 0 0 1 1 1\npart b parent c region 0 0 0 1 1 1") {
+            Err(RigError::Parse { line: 2, .. }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(Rig::parse("part a pivot 0 0 0").is_err());
+        assert!(Clip::parse("track head").is_err());
+        assert!(Clip::parse("clip a 1\ntrack b\nkey 0 spin 1").is_err());
+        for bad in &[
+            "clip a -1",
+            "clip a NaN",
+            "clip a inf",
+            "clip a 1\ntrack b\nkey NaN",
+        ] {
+            match Clip::parse(bad) {
+                Err(RigError::Parse { .. }) => {}
+                other => panic!("unexpected {:?} for {:?}", other, bad),
+            }
+        }
+    }
+}
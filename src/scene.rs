@@ -0,0 +1,426 @@
+use crate::pipeline::gbuffer::{GBufferPipeline, Uniforms};
+use crate::pipeline::lighting::PointLight;
+use crate::voxel_data::{EmissiveCluster, Emitters, VoxelBuffer};
+use ultraviolet::*;
+use wgpu::*;
+
+// every drawn node gets a slot in one uniform buffer, slots have to be aligned for dynamic offsets
+const SLOT_SIZE: BufferAddress = BIND_BUFFER_ALIGNMENT;
+
+/// Position, rotation and scale of a node relative to its parent
+#[derive(Debug, Copy, Clone)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Rotor3,
+    pub scale: Vec3,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: Vec3::zero(),
+            rotation: Rotor3::identity(),
+            scale: Vec3::one(),
+        }
+    }
+}
+
+impl Transform {
+    pub fn from_translation(translation: Vec3) -> Self {
+        Self {
+            translation,
+            ..Self::default()
+        }
+    }
+
+    /// Scales, then rotates, then translates
+    pub fn matrix(&self) -> Mat4 {
+        Mat4::from_translation(self.translation)
+            * self.rotation.into_matrix().into_homogeneous()
+            * Mat4::from_nonuniform_scale(self.scale)
+    }
+
+    /// Blends towards `other` by `t` from 0 to 1, rotations take the shorter way around
+    pub fn lerp(&self, other: &Self, t: f32) -> Self {
+        let mut rotation = other.rotation;
+        if self.rotation.dot(rotation) < 0.0 {
+            rotation *= -1.0;
+        }
+        Self {
+            translation: self.translation.lerp(other.translation, t),
+            rotation: self.rotation.slerp(rotation, t).normalized(),
+            scale: self.scale.lerp(other.scale, t),
+        }
+    }
+}
+
+/// A node of a `Scene`, ids of removed nodes get reused
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+/// A model added to a `Scene`, any number of nodes can draw it
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ModelId(usize);
+
+struct Node {
+    transform: Transform,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    model: Option<ModelId>,
+    world: Mat4,
+}
+
+struct Model {
+    buffer: VoxelBuffer,
+    // created on the first update and whenever the model's textures are recreated
+    textures: Option<BindGroup>,
+    // the most lights the buffer's glowing colors may become, see `set_emitters`
+    max_lights: usize,
+    // in model space, redone whenever the model is edited
+    clusters: Vec<EmissiveCluster>,
+}
+
+impl Model {
+    fn cluster(&mut self) {
+        let data = self.buffer.data();
+        self.clusters = self.buffer.emitters().clusters(data, self.max_lights);
+    }
+}
+
+struct Uniform {
+    buffer: Buffer,
+    bind: BindGroup,
+    slots: usize,
+}
+
+/// A hierarchy of transformed nodes drawing shared voxel models.
+///
+/// The whole scene renders with one uniform bind group, every node's matrices live at their own
+/// dynamic offset in it. Models are only bound once for all the nodes that draw them.
+pub struct Scene {
+    nodes: Vec<Option<Node>>,
+    free: Vec<usize>,
+    models: Vec<Model>,
+    uniform: Option<Uniform>,
+    // model and uniform offset of every node drawn, grouped by model
+    draws: Vec<(ModelId, DynamicOffset)>,
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scene {
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![],
+            free: vec![],
+            models: vec![],
+            uniform: None,
+            draws: vec![],
+        }
+    }
+
+    pub fn add_model(&mut self, buffer: VoxelBuffer) -> ModelId {
+        self.models.push(Model {
+            buffer,
+            textures: None,
+            max_lights: 0,
+            clusters: vec![],
+        });
+        ModelId(self.models.len() - 1)
+    }
+
+    pub fn model(&self, id: ModelId) -> &VoxelBuffer {
+        &self.models[id.0].buffer
+    }
+
+    /// Edits made through this are uploaded on the next call to `update`
+    pub fn model_mut(&mut self, id: ModelId) -> &mut VoxelBuffer {
+        &mut self.models[id.0].buffer
+    }
+
+    /// Makes voxels of a model glow and light up their surroundings, grouped into at most
+    /// `max_lights` point lights for every node that draws it, see `emissive_lights`. `None`
+    /// turns them off again.
+    pub fn set_emitters(&mut self, id: ModelId, emitters: Option<Emitters>, max_lights: usize) {
+        let model = &mut self.models[id.0];
+        model.max_lights = if emitters.is_some() { max_lights } else { 0 };
+        model.buffer.set_emitters(emitters.unwrap_or_default());
+        model.cluster();
+    }
+
+    fn node(&self, id: NodeId) -> &Node {
+        self.nodes[id.0].as_ref().expect("node was removed")
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        self.nodes[id.0].as_mut().expect("node was removed")
+    }
+
+    /// Adds a node under `parent`, or at the root of the scene. Nodes without a model only
+    /// group and move their children.
+    pub fn add_node(
+        &mut self,
+        parent: Option<NodeId>,
+        transform: Transform,
+        model: Option<ModelId>,
+    ) -> NodeId {
+        let node = Node {
+            transform,
+            parent,
+            children: vec![],
+            model,
+            world: Mat4::identity(),
+        };
+        let id = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = Some(node);
+                NodeId(index)
+            }
+            None => {
+                self.nodes.push(Some(node));
+                NodeId(self.nodes.len() - 1)
+            }
+        };
+        if let Some(parent) = parent {
+            self.node_mut(parent).children.push(id);
+        }
+        id
+    }
+
+    /// Removes a node along with all of its children
+    pub fn remove_node(&mut self, id: NodeId) {
+        if let Some(parent) = self.node(id).parent {
+            self.node_mut(parent).children.retain(|&c| c != id);
+        }
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            if let Some(node) = self.nodes[id.0].take() {
+                stack.extend(node.children);
+                self.free.push(id.0);
+            }
+        }
+    }
+
+    /// Moves a node and its children under another parent, keeping its local transform
+    pub fn set_parent(&mut self, id: NodeId, parent: Option<NodeId>) {
+        let mut ancestor = parent;
+        while let Some(a) = ancestor {
+            assert!(a != id, "a node can't be its own ancestor");
+            ancestor = self.node(a).parent;
+        }
+        if let Some(old) = self.node(id).parent {
+            self.node_mut(old).children.retain(|&c| c != id);
+        }
+        if let Some(parent) = parent {
+            self.node_mut(parent).children.push(id);
+        }
+        self.node_mut(id).parent = parent;
+    }
+
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.node(id).parent
+    }
+
+    pub fn children(&self, id: NodeId) -> &[NodeId] {
+        &self.node(id).children
+    }
+
+    pub fn transform(&self, id: NodeId) -> &Transform {
+        &self.node(id).transform
+    }
+
+    pub fn transform_mut(&mut self, id: NodeId) -> &mut Transform {
+        &mut self.node_mut(id).transform
+    }
+
+    pub fn set_model(&mut self, id: NodeId, model: Option<ModelId>) {
+        self.node_mut(id).model = model;
+    }
+
+    /// The transform of a node in world space, including all of its parents
+    pub fn world_matrix(&self, id: NodeId) -> Mat4 {
+        let node = self.node(id);
+        let local = node.transform.matrix();
+        match node.parent {
+            Some(parent) => self.world_matrix(parent) * local,
+            None => local,
+        }
+    }
+
+    // recomputes world matrices parents first
+    fn update_world(&mut self) {
+        let mut stack: Vec<(usize, Mat4)> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| n.as_ref().is_some_and(|n| n.parent.is_none()))
+            .map(|(i, _)| (i, Mat4::identity()))
+            .collect();
+        while let Some((index, parent)) = stack.pop() {
+            let node = self.nodes[index].as_mut().unwrap();
+            node.world = parent * node.transform.matrix();
+            stack.extend(node.children.iter().map(|c| (c.0, node.world)));
+        }
+    }
+
+    /// Uploads model edits and the matrices of every node, call once a frame before rendering
+    pub fn update(&mut self, view_proj: Mat4, pipeline: &GBufferPipeline, ctx: &crate::Context) {
+        for model in &mut self.models {
+            let edited = model.buffer.data().is_dirty();
+            if model.buffer.update(ctx) || model.textures.is_none() {
+                model.textures = Some(pipeline.bind_textures(model.buffer.textures(), &ctx.device));
+            }
+            if edited && model.max_lights > 0 {
+                model.cluster();
+            }
+        }
+
+        self.update_world();
+        let mut drawn: Vec<_> = self
+            .nodes
+            .iter()
+            .flatten()
+            .filter_map(|n| n.model.map(|m| (m, n.world)))
+            .collect();
+        drawn.sort_by_key(|(model, _)| model.0);
+
+        let slots = drawn.len().max(1);
+        if self.uniform.as_ref().map_or(true, |u| u.slots < slots) {
+            let slots = slots.next_power_of_two().max(16);
+            let buffer = ctx.device.create_buffer(&BufferDescriptor {
+                label: Some("scene uniforms"),
+                size: slots as BufferAddress * SLOT_SIZE,
+                usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+                mapped_at_creation: false,
+            });
+            let bind = pipeline.bind_uniform(&buffer, &ctx.device);
+            self.uniform = Some(Uniform {
+                buffer,
+                bind,
+                slots,
+            });
+        }
+
+        let mut data = vec![0; drawn.len() * SLOT_SIZE as usize];
+        self.draws.clear();
+        for (i, &(model, world)) in drawn.iter().enumerate() {
+            let offset = i * SLOT_SIZE as usize;
+            let uniforms = Uniforms {
+                view_proj,
+                model: world,
+            }
+            .data();
+            data[offset..offset + uniforms.len()].copy_from_slice(&uniforms);
+            self.draws.push((model, offset as DynamicOffset));
+        }
+        if !data.is_empty() {
+            let uniform = self.uniform.as_ref().unwrap();
+            ctx.queue.write_buffer(&uniform.buffer, 0, &data);
+        }
+    }
+
+    /// Every model drawn by a node along with the node's world matrix, as of the last `update`
+    pub fn placed_models(&self) -> impl Iterator<Item = (&VoxelBuffer, Mat4)> {
+        self.nodes
+            .iter()
+            .flatten()
+            .filter_map(move |n| n.model.map(|m| (&self.models[m.0].buffer, n.world)))
+    }
+
+    /// Point lights for the glowing voxels of every model drawn by a node, placed with the node
+    /// as of the last `update`. Add them to `Lights::points` every frame so they follow their
+    /// models around.
+    pub fn emissive_lights(&self) -> impl Iterator<Item = PointLight> + '_ {
+        self.nodes.iter().flatten().flat_map(move |n| {
+            let clusters = n.model.map_or(&[][..], |m| &self.models[m.0].clusters[..]);
+            clusters
+                .iter()
+                .map(move |c| PointLight::from_cluster(c, n.world))
+        })
+    }
+
+    /// The uniform bind group and every model to draw with its textures and uniform offset, as
+    /// of the last `update`
+    pub(crate) fn draws(
+        &self,
+    ) -> Option<(
+        &BindGroup,
+        impl Iterator<Item = (&VoxelBuffer, &BindGroup, DynamicOffset)>,
+    )> {
+        let uniform = self.uniform.as_ref()?;
+        let draws = self.draws.iter().map(move |&(model, offset)| {
+            let model = &self.models[model.0];
+            (&model.buffer, model.textures.as_ref().unwrap(), offset)
+        });
+        Some((&uniform.bind, draws))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).mag() < 1e-5
+    }
+
+    #[test]
+    fn children_follow_parents() {
+        let mut scene = Scene::new();
+        let root = scene.add_node(
+            None,
+            Transform::from_translation(Vec3::unit_x() * 10.0),
+            None,
+        );
+        let arm = scene.add_node(
+            Some(root),
+            Transform::from_translation(Vec3::unit_z()),
+            None,
+        );
+        let hand = scene.add_node(Some(arm), Transform::from_translation(Vec3::unit_z()), None);
+
+        let origin = |scene: &Scene, id| scene.world_matrix(id).transform_point3(Vec3::zero());
+        assert!(close(origin(&scene, hand), Vec3::new(10.0, 0.0, 2.0)));
+
+        // a quarter turn around y at the root swings the whole arm
+        scene.transform_mut(root).rotation = Rotor3::from_rotation_xz(90f32.to_radians());
+        let swung = origin(&scene, hand);
+        assert!(close(swung, Vec3::new(8.0, 0.0, 0.0)));
+
+        scene.update_world();
+        assert_eq!(scene.node(hand).world, scene.world_matrix(hand));
+
+        scene.set_parent(hand, None);
+        assert!(close(origin(&scene, hand), Vec3::unit_z()));
+        assert!(scene.children(arm).is_empty());
+    }
+
+    #[test]
+    fn removing_takes_children() {
+        let mut scene = Scene::new();
+        let root = scene.add_node(None, Transform::default(), None);
+        let child = scene.add_node(Some(root), Transform::default(), None);
+        scene.add_node(Some(child), Transform::default(), None);
+        let other = scene.add_node(None, Transform::default(), None);
+
+        scene.remove_node(child);
+        assert!(scene.children(root).is_empty());
+        assert_eq!(scene.nodes.iter().flatten().count(), 2);
+        // freed slots are reused, the last one freed first
+        let new = scene.add_node(Some(other), Transform::default(), None);
+        assert_eq!(new, NodeId(2));
+    }
+
+    #[test]
+    #[should_panic]
+    fn cycles_are_rejected() {
+        let mut scene = Scene::new();
+        let a = scene.add_node(None, Transform::default(), None);
+        let b = scene.add_node(Some(a), Transform::default(), None);
+        scene.set_parent(a, Some(b));
+    }
+}
@@ -1,11 +1,32 @@
 use crate::pipeline::gbuffer::TextureData;
 use crate::pipeline::gbuffer::Textures;
 use crate::pipeline::gbuffer::Vertex;
-use ultraviolet::*;
 
+pub mod animation;
+pub mod binary;
+pub mod emission;
+pub mod history;
+mod mesh;
+pub mod mesher;
+pub mod palette;
+pub mod quantize;
+pub mod ramp;
+pub mod shapes;
+pub mod storage;
+mod transform;
+
+pub use emission::{EmissiveCluster, Emitters};
+pub use mesh::MeshOptions;
+use mesh::{open_sky, Light, Outside};
+pub use palette::Palette;
+use quantize::{Dither, Quantizer};
+pub use ramp::{Ramp, Ramps};
+use std::ops::RangeInclusive;
+use storage::{Dense, Storage, StorageKind};
 mod txt;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Color {
     red: u8,
     green: u8,
@@ -29,33 +50,277 @@ impl Color {
             visible: true,
         }
     }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn rgb(&self) -> [u8; 3] {
+        [self.red, self.green, self.blue]
+    }
+
+    pub fn rgba(&self) -> [u8; 4] {
+        [
+            self.red,
+            self.green,
+            self.blue,
+            if self.visible { 0xFF } else { 0x00 },
+        ]
+    }
 }
 
-pub struct VoxelBuffer {
-    data: VoxelData,
+impl Default for Color {
+    fn default() -> Self {
+        Self::CLEAR
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+impl Axis {
+    pub fn index(self) -> usize {
+        match self {
+            Axis::X => 0,
+            Axis::Y => 1,
+            Axis::Z => 2,
+        }
+    }
+}
+
+/// Edge length of the sub-chunks a `VoxelBuffer` splits its mesh into, edits only re-mesh the
+/// sub-chunks they touch
+pub const SUBCHUNK_SIZE: u32 = 16;
+
+// smallest gpu allocation for a sub-chunk, so small edits don't reallocate every time
+const MIN_BUFFER_SIZE: u64 = 256;
+
+struct SubMesh {
     vbuffer: wgpu::Buffer,
     ibuffer: wgpu::Buffer,
-    textures: Textures,
-    icnt: u32,
+    vcap: u64,
+    icap: u64,
     vcnt: u32,
+    icnt: u32,
+}
+
+impl SubMesh {
+    fn new(verts: &[Vertex], indices: &[u16], ctx: &crate::Context) -> Self {
+        let vdata: Vec<u8> = verts.iter().flat_map(|x| x.data()).collect();
+        let idata: &[u8] = bytemuck::cast_slice(indices);
+        let vcap = buffer_capacity(vdata.len());
+        let icap = buffer_capacity(idata.len());
+        let mesh = Self {
+            vbuffer: create_buffer(vcap, wgpu::BufferUsage::VERTEX, ctx),
+            ibuffer: create_buffer(icap, wgpu::BufferUsage::INDEX, ctx),
+            vcap,
+            icap,
+            vcnt: verts.len() as u32,
+            icnt: indices.len() as u32,
+        };
+        ctx.queue.write_buffer(&mesh.vbuffer, 0, &vdata);
+        ctx.queue.write_buffer(&mesh.ibuffer, 0, idata);
+        mesh
+    }
+
+    fn write(&mut self, verts: &[Vertex], indices: &[u16], ctx: &crate::Context) {
+        let vdata: Vec<u8> = verts.iter().flat_map(|x| x.data()).collect();
+        let idata: &[u8] = bytemuck::cast_slice(indices);
+        if vdata.len() as u64 > self.vcap {
+            self.vcap = buffer_capacity(vdata.len());
+            self.vbuffer = create_buffer(self.vcap, wgpu::BufferUsage::VERTEX, ctx);
+        }
+        if idata.len() as u64 > self.icap {
+            self.icap = buffer_capacity(idata.len());
+            self.ibuffer = create_buffer(self.icap, wgpu::BufferUsage::INDEX, ctx);
+        }
+        ctx.queue.write_buffer(&self.vbuffer, 0, &vdata);
+        ctx.queue.write_buffer(&self.ibuffer, 0, idata);
+        self.vcnt = verts.len() as u32;
+        self.icnt = indices.len() as u32;
+    }
+}
+
+fn buffer_capacity(len: usize) -> u64 {
+    (len as u64).next_power_of_two().max(MIN_BUFFER_SIZE)
+}
+
+fn create_buffer(size: u64, usage: wgpu::BufferUsage, ctx: &crate::Context) -> wgpu::Buffer {
+    ctx.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("voxel sub-chunk buffer"),
+        size,
+        usage: usage | wgpu::BufferUsage::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+// the sub-chunks an edit to `dirty` can change, `None` if there are none such as for models
+// without any voxels
+fn subchunks_to_remesh(dirty: Region, dims: [u32; 3]) -> Option<[RangeInclusive<u32>; 3]> {
+    // faces and occlusion of the neighbors of an edited voxel change too
+    let remesh = Region::new(
+        [
+            dirty.min[0].saturating_sub(1),
+            dirty.min[1].saturating_sub(1),
+            dirty.min[2].saturating_sub(1),
+        ],
+        [dirty.max[0] + 1, dirty.max[1] + 1, dirty.max[2] + 1],
+    )
+    .intersect(&Region::new([0, 0, 0], dims));
+    if dirty.is_empty() || remesh.is_empty() {
+        return None;
+    }
+    let range = |i: usize| remesh.min[i] / SUBCHUNK_SIZE..=(remesh.max[i] - 1) / SUBCHUNK_SIZE;
+    Some([range(0), range(1), range(2)])
+}
+
+// extra palettes instances can pick, rows of the palette texture after the data's own
+#[derive(Debug, Default)]
+struct Variants {
+    palettes: Vec<Palette>,
+}
+
+impl Variants {
+    // the row to draw the new variant with
+    fn add(&mut self, palette: Palette) -> u32 {
+        self.palettes.push(palette);
+        self.palettes.len() as u32
+    }
+
+    fn set(&mut self, variant: u32, palette: Palette) {
+        assert!(variant > 0, "variant 0 is the model's own palette");
+        self.palettes[variant as usize - 1] = palette;
+    }
+
+    fn rows(&self) -> u32 {
+        1 + self.palettes.len() as u32
+    }
+
+    // the data's palette followed by every variant, one row of 256 texels each
+    fn texels(&self, own: &Palette, emitters: &Emitters) -> Vec<u8> {
+        let mut texels = own.texels_with(emitters);
+        for variant in &self.palettes {
+            texels.extend(variant.texels_with(emitters));
+        }
+        texels
+    }
+
+    // the ramp id of every index of every palette, in the same rows as `texels`
+    fn ramp_ids(&self, own: &Palette, ramps: &Ramps) -> Vec<u8> {
+        let mut ids = own.ramp_ids(ramps);
+        for variant in &self.palettes {
+            ids.extend(variant.ramp_ids(ramps));
+        }
+        ids
+    }
+}
+
+pub struct VoxelBuffer {
+    data: VoxelData,
+    dims: [u32; 3],
+    options: MeshOptions,
+    origin: [i32; 3],
+    chunks: Vec<SubMesh>,
+    textures: Textures,
+    variants: Variants,
+    // which colors of every palette glow, kept in the palette texture's alpha
+    emitters: Emitters,
+    // which colors of every palette are shaded along a ramp, kept in the ramp id texture
+    ramps: Ramps,
+    palettes_dirty: bool,
+    palette_rows: u32,
+    // false when the mesh is made elsewhere, such as by `GpuMesh`
+    meshed: bool,
 }
 
 impl VoxelBuffer {
     pub fn from_data(data: VoxelData, ctx: &crate::Context) -> Self {
-        let (verts, indices, texels) = data.verts();
-        let vbuffer = Vertex::vbuf(&verts, &ctx.device);
-        let ibuffer = Vertex::ibuf(&indices, &ctx.device);
+        Self::from_data_with(data, MeshOptions::default(), ctx)
+    }
+
+    pub fn from_data_with(data: VoxelData, options: MeshOptions, ctx: &crate::Context) -> Self {
+        Self::build(data, options, [0, 0, 0], &|_| false, &open_sky, ctx)
+    }
+
+    // `origin` offsets every vertex, see `VoxelData::mesh` for `outside` and `light`
+    pub(crate) fn build(
+        data: VoxelData,
+        options: MeshOptions,
+        origin: [i32; 3],
+        outside: Outside,
+        light: Light,
+        ctx: &crate::Context,
+    ) -> Self {
+        let meshes = data
+            .mesh_subchunks(&options, origin, outside, light, &|| false)
+            .unwrap();
+        Self::from_meshes(data, options, origin, &meshes, ctx)
+    }
 
-        let d_tex = TextureData::new(&texels.0, texels.1);
+    /// Uploads only the textures of a model and never meshes it on the cpu, for drawing with
+    /// `RaymarchPipeline`. Edits are still uploaded by `update`.
+    pub fn unmeshed(data: VoxelData, ctx: &crate::Context) -> Self {
+        let mut buffer = Self::from_meshes(data, MeshOptions::default(), [0, 0, 0], &[], ctx);
+        buffer.meshed = false;
+        buffer
+    }
 
-        let textures = Textures::new(d_tex, &ctx);
-        Self {
+    // uploads sub-chunk meshes made by `VoxelData::mesh_subchunks`
+    pub(crate) fn from_meshes(
+        mut data: VoxelData,
+        options: MeshOptions,
+        origin: [i32; 3],
+        meshes: &[(Vec<Vertex>, Vec<u16>)],
+        ctx: &crate::Context,
+    ) -> Self {
+        data.take_dirty();
+        data.take_palette_dirty();
+        let mut buffer = Self {
+            dims: data.dims(),
+            chunks: vec![],
+            textures: Self::create_textures(
+                &data,
+                &data.palette.texels(),
+                &data.palette.ramp_ids(&Ramps::new()),
+                ctx,
+            ),
             data,
-            vbuffer,
-            ibuffer,
-            icnt: indices.len() as u32,
-            vcnt: verts.len() as u32,
-            textures,
+            options,
+            origin,
+            variants: Variants::default(),
+            emitters: Emitters::new(),
+            ramps: Ramps::new(),
+            palettes_dirty: false,
+            palette_rows: 1,
+            meshed: true,
+        };
+        buffer.chunks = meshes
+            .iter()
+            .map(|(verts, indices)| SubMesh::new(verts, indices, ctx))
+            .collect();
+        buffer
+    }
+
+    // from now on `update` only uploads textures and the meshes come from `set_meshes`
+    pub(crate) fn mesh_elsewhere(&mut self) {
+        self.meshed = false;
+    }
+
+    // replaces every sub-chunk mesh with ones made by `VoxelData::mesh_subchunks`
+    pub(crate) fn set_meshes(&mut self, meshes: &[(Vec<Vertex>, Vec<u16>)], ctx: &crate::Context) {
+        if meshes.len() == self.chunks.len() {
+            for (chunk, (verts, indices)) in self.chunks.iter_mut().zip(meshes) {
+                chunk.write(verts, indices, ctx);
+            }
+        } else {
+            self.chunks = meshes
+                .iter()
+                .map(|(verts, indices)| SubMesh::new(verts, indices, ctx))
+                .collect();
         }
     }
 
@@ -73,28 +338,310 @@ impl VoxelBuffer {
         Self::from_data(VoxelData::new(colors, width, height, depth), ctx)
     }
 
+    fn mesh_all(&self, outside: Outside, light: Light, ctx: &crate::Context) -> Vec<SubMesh> {
+        let meshes = self
+            .data
+            .mesh_subchunks(&self.options, self.origin, outside, light, &|| false)
+            .unwrap();
+        meshes
+            .iter()
+            .map(|(verts, indices)| SubMesh::new(verts, indices, ctx))
+            .collect()
+    }
+
+    // `palettes` holds 256 rgba texels per palette row and `ramp_ids` 256 ids
+    fn create_textures(
+        data: &VoxelData,
+        palettes: &[u8],
+        ramp_ids: &[u8],
+        ctx: &crate::Context,
+    ) -> Textures {
+        let [width, height, depth] = data.dims();
+        let texels = data.texels(Region::new([0, 0, 0], data.dims()));
+        // the texture is laid out z-major so texels are in the same order as voxel indices
+        Textures::new(
+            TextureData::indices(&texels, [depth, height, width]),
+            TextureData::palette(palettes, (palettes.len() / 1024) as u32),
+            TextureData::ramp_ids(ramp_ids, (ramp_ids.len() / 256) as u32),
+            ctx,
+        )
+    }
+
+    fn palette_texels(&self) -> Vec<u8> {
+        self.variants.texels(&self.data.palette, &self.emitters)
+    }
+
+    fn ramp_ids(&self) -> Vec<u8> {
+        self.variants.ramp_ids(&self.data.palette, &self.ramps)
+    }
+
+    /// Adds a palette instances can draw the model with instead of its own, see
+    /// `Instance::palette`. Returns the index to draw it with, the model's own palette is 0.
+    pub fn add_variant(&mut self, palette: Palette) -> u32 {
+        self.palettes_dirty = true;
+        self.variants.add(palette)
+    }
+
+    pub fn set_variant(&mut self, variant: u32, palette: Palette) {
+        self.variants.set(variant, palette);
+        self.palettes_dirty = true;
+    }
+
+    pub fn variants(&self) -> &[Palette] {
+        &self.variants.palettes
+    }
+
+    /// Makes voxels of the emitting colors glow by themselves in the lighting pass, in every
+    /// palette the model is drawn with. Uploaded by the next `update` like palette edits.
+    pub fn set_emitters(&mut self, emitters: Emitters) {
+        self.emitters = emitters;
+        self.palettes_dirty = true;
+    }
+
+    pub fn emitters(&self) -> &Emitters {
+        &self.emitters
+    }
+
+    /// Shades voxels of the ramped colors along their ramp in the lighting pass, in every
+    /// palette the model is drawn with. Pass the lighting pass the same registry through
+    /// `Lights::ramps`. Uploaded by the next `update` like palette edits.
+    pub fn set_ramps(&mut self, ramps: Ramps) {
+        self.ramps = ramps;
+        self.palettes_dirty = true;
+    }
+
+    pub fn ramps(&self) -> &Ramps {
+        &self.ramps
+    }
+
+    /// Uploads any edits made through `data_mut` since the last update.
+    ///
+    /// Only the sub-chunks and texels inside the dirty region are re-meshed and re-uploaded, and
+    /// palette changes only re-upload the palettes. If the data was resized or variants were
+    /// added the textures are recreated and `true` is returned, in which case any bind groups
+    /// made from `textures()` have to be rebuilt.
+    pub fn update(&mut self, ctx: &crate::Context) -> bool {
+        self.update_with(&|_| false, &open_sky, ctx)
+    }
+
+    pub(crate) fn update_with(
+        &mut self,
+        outside: Outside,
+        light: Light,
+        ctx: &crate::Context,
+    ) -> bool {
+        if self.data.dims() != self.dims {
+            self.data.take_dirty();
+            self.data.take_palette_dirty();
+            self.dims = self.data.dims();
+            if self.meshed {
+                self.chunks = self.mesh_all(outside, light, ctx);
+            }
+            self.textures =
+                Self::create_textures(&self.data, &self.palette_texels(), &self.ramp_ids(), ctx);
+            self.palettes_dirty = false;
+            self.palette_rows = self.variants.rows();
+            return true;
+        }
+
+        let mut rebind = false;
+        if self.data.take_palette_dirty() || self.palettes_dirty {
+            self.palettes_dirty = false;
+            let rows = self.variants.rows();
+            let texels = self.palette_texels();
+            let ids = self.ramp_ids();
+            let palettes = TextureData::palette(&texels, rows);
+            let ramp_ids = TextureData::ramp_ids(&ids, rows);
+            if rows == self.palette_rows {
+                palettes.write(&self.textures.palette_tex, [0, 0, 0], ctx);
+                ramp_ids.write(&self.textures.ramp_tex, [0, 0, 0], ctx);
+            } else {
+                let (tex, view) = palettes.create(ctx);
+                self.textures.palette_tex = tex;
+                self.textures.palette_view = view;
+                let (tex, view) = ramp_ids.create(ctx);
+                self.textures.ramp_tex = tex;
+                self.textures.ramp_view = view;
+                self.palette_rows = rows;
+                rebind = true;
+            }
+        }
+
+        // models without voxels have nothing to mesh or upload
+        let dirty = match self.data.take_dirty() {
+            Some(dirty) if !dirty.is_empty() => dirty,
+            _ => return rebind,
+        };
+        if self.meshed {
+            self.remesh(dirty, outside, light, ctx);
+        }
+
+        let texels = self.data.texels(dirty);
+        let size = dirty.size();
+        TextureData::indices(&texels, [size[2], size[1], size[0]]).write(
+            &self.textures.index_tex,
+            [dirty.min[2], dirty.min[1], dirty.min[0]],
+            ctx,
+        );
+        rebind
+    }
+
+    // re-meshes the sub-chunks an edit to `dirty` can change
+    fn remesh(&mut self, dirty: Region, outside: Outside, light: Light, ctx: &crate::Context) {
+        let [xs, ys, zs] = match subchunks_to_remesh(dirty, self.dims) {
+            Some(ranges) => ranges,
+            None => return,
+        };
+        let counts = subchunk_counts(self.dims);
+        for cx in xs {
+            for cy in ys.clone() {
+                for cz in zs.clone() {
+                    let region = self.data.subchunk_region([cx, cy, cz]);
+                    let (verts, indices) =
+                        self.data
+                            .mesh(region, &self.options, self.origin, outside, light);
+                    let ind = (cx * counts[1] * counts[2]) + (cy * counts[2]) + cz;
+                    self.chunks[ind as usize].write(&verts, &indices, ctx);
+                }
+            }
+        }
+    }
+
+    pub fn data(&self) -> &VoxelData {
+        &self.data
+    }
+
+    pub fn options(&self) -> &MeshOptions {
+        &self.options
+    }
+
+    /// The whole model is re-meshed with the new options on the next call to `update`
+    pub fn set_options(&mut self, options: MeshOptions) {
+        if options != self.options {
+            self.options = options;
+            self.data.mark_dirty(Region::new([0, 0, 0], self.dims));
+        }
+    }
+
+    /// Edits made through this are uploaded on the next call to `update`
+    pub fn data_mut(&mut self) -> &mut VoxelData {
+        &mut self.data
+    }
+
+    pub fn into_data(self) -> VoxelData {
+        self.data
+    }
+
     pub fn textures(&self) -> &Textures {
         &self.textures
     }
 
     pub fn index_count(&self) -> u32 {
-        self.icnt
+        self.chunks.iter().map(|c| c.icnt).sum()
     }
 
     pub fn vert_count(&self) -> u32 {
-        self.vcnt
+        self.chunks.iter().map(|c| c.vcnt).sum()
     }
 
-    pub fn buffers(&self) -> (&wgpu::Buffer, &wgpu::Buffer) {
-        (&self.vbuffer, &self.ibuffer)
+    /// Vertex buffer, index buffer and index count of every non-empty sub-chunk
+    pub fn meshes(&self) -> impl Iterator<Item = (&wgpu::Buffer, &wgpu::Buffer, u32)> {
+        self.chunks
+            .iter()
+            .filter(|c| c.icnt > 0)
+            .map(|c| (&c.vbuffer, &c.ibuffer, c.icnt))
     }
 }
 
+fn subchunk_counts(dims: [u32; 3]) -> [u32; 3] {
+    let count = |n: u32| n.div_ceil(SUBCHUNK_SIZE);
+    [count(dims[0]), count(dims[1]), count(dims[2])]
+}
+
+/// An axis aligned box of voxel coordinates, `min` is inclusive and `max` is exclusive
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub min: [u32; 3],
+    pub max: [u32; 3],
+}
+
+impl Region {
+    pub fn new(min: [u32; 3], max: [u32; 3]) -> Self {
+        Self { min, max }
+    }
+
+    pub fn point(x: u32, y: u32, z: u32) -> Self {
+        Self::new([x, y, z], [x + 1, y + 1, z + 1])
+    }
+
+    pub fn size(&self) -> [u32; 3] {
+        [
+            self.max[0] - self.min[0],
+            self.max[1] - self.min[1],
+            self.max[2] - self.min[2],
+        ]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        (0..3).any(|i| self.max[i] <= self.min[i])
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self::new(
+            [
+                self.min[0].min(other.min[0]),
+                self.min[1].min(other.min[1]),
+                self.min[2].min(other.min[2]),
+            ],
+            [
+                self.max[0].max(other.max[0]),
+                self.max[1].max(other.max[1]),
+                self.max[2].max(other.max[2]),
+            ],
+        )
+    }
+
+    pub fn intersect(&self, other: &Self) -> Self {
+        Self::new(
+            [
+                self.min[0].max(other.min[0]),
+                self.min[1].max(other.min[1]),
+                self.min[2].max(other.min[2]),
+            ],
+            [
+                self.max[0].min(other.max[0]),
+                self.max[1].min(other.max[1]),
+                self.max[2].min(other.max[2]),
+            ],
+        )
+    }
+}
+
+/// A box of voxels, each one an index into the model's `Palette`
 pub struct VoxelData {
-    colors: Vec<Color>,
+    storage: Box<dyn Storage>,
+    palette: Palette,
     width: u32,
     height: u32,
     depth: u32,
+    dirty: Option<Region>,
+    palette_dirty: bool,
+    journal: Option<Vec<(usize, u8)>>,
+}
+
+impl Clone for VoxelData {
+    fn clone(&self) -> Self {
+        Self {
+            storage: self.storage.boxed_clone(),
+            palette: self.palette.clone(),
+            width: self.width,
+            height: self.height,
+            depth: self.depth,
+            dirty: self.dirty,
+            palette_dirty: self.palette_dirty,
+            journal: self.journal.clone(),
+        }
+    }
 }
 
 impl VoxelData {
@@ -148,79 +695,370 @@ impl VoxelData {
         Self::new(colors, width as u32, height as u32, depth as u32)
     }
 
+    /// Creates a model from colors in index order, building its palette along the way. Models
+    /// with more than `palette::MAX_COLORS` colors are quantized with median cut, see
+    /// `quantized` for more control.
     pub fn new(colors: Vec<Color>, width: u32, height: u32, depth: u32) -> Self {
+        assert_eq!(colors.len(), (width * height * depth) as usize);
+        let unique: std::collections::HashSet<_> =
+            colors.iter().filter(|c| c.is_visible()).collect();
+        if unique.len() > palette::MAX_COLORS {
+            return Self::quantized(
+                colors,
+                width,
+                height,
+                depth,
+                palette::MAX_COLORS,
+                Quantizer::MedianCut,
+                Dither::None,
+            );
+        }
+        let mut palette = Palette::new();
+        let indices = colors.iter().map(|&c| palette.index_of(c)).collect();
+        let mut data = Self::with_storage(Box::new(Dense::new(indices)), width, height, depth);
+        data.palette = palette;
+        data
+    }
+
+    /// Creates a model backed by any storage, see `StorageKind` for the ones built in
+    pub fn with_storage(storage: Box<dyn Storage>, width: u32, height: u32, depth: u32) -> Self {
         Self {
-            colors,
+            storage,
+            palette: Palette::new(),
             width,
             height,
             depth,
+            dirty: None,
+            palette_dirty: false,
+            journal: None,
         }
     }
 
-    fn verts(&self) -> (Vec<Vertex>, Vec<u16>, (Vec<u8>, [u32; 2])) {
-        let mut verts = vec![];
-        let mut indices = vec![];
-        let mut texels = vec![];
-        for x in 0..self.width {
-            for y in 0..self.height {
-                for z in 0..self.depth {
-                    let index = (x * self.depth * self.height) + (y * self.depth) + z;
-                    let voxel = &self.colors[index as usize];
-                    if voxel.visible {
-                        let ind_offset = verts.len();
-                        {
-                            let x = x as f32;
-                            let y = y as f32;
-                            let z = z as f32;
-
-                            let tex_off = (texels.len() / 4) as f32;
-                            texels.push(voxel.red);
-                            texels.push(voxel.green);
-                            texels.push(voxel.blue);
-                            texels.push(0xFF);
-
-                            verts.push(Vertex::new(0.0 + x, 0.0 + y, 0.0 + z, 0.0 + tex_off, 0.0));
-                            verts.push(Vertex::new(0.0 + x, 0.0 + y, 1.0 + z, 0.0 + tex_off, 0.0));
-                            verts.push(Vertex::new(0.0 + x, 1.0 + y, 0.0 + z, 0.0 + tex_off, 0.0));
-                            verts.push(Vertex::new(0.0 + x, 1.0 + y, 1.0 + z, 0.0 + tex_off, 0.0));
-                            verts.push(Vertex::new(1.0 + x, 0.0 + y, 0.0 + z, 0.0 + tex_off, 0.0));
-                            verts.push(Vertex::new(1.0 + x, 0.0 + y, 1.0 + z, 0.0 + tex_off, 0.0));
-                            verts.push(Vertex::new(1.0 + x, 1.0 + y, 1.0 + z, 0.0 + tex_off, 0.0));
-                            verts.push(Vertex::new(1.0 + x, 1.0 + y, 0.0 + z, 0.0 + tex_off, 0.0));
-                        }
-
-                        #[rustfmt::skip]
-                        indices.extend([
-                            // left face
-                            0, 1, 3,
-                            0, 3, 2,
-                            // bottom face
-                            0, 4, 5,
-                            0, 5, 1,
-                            // // back face
-                            0, 2, 4,
-                            2, 7, 4,
-                            // front face
-                            1, 3, 5,
-                            3, 6, 5,
-                            // right face
-                            4, 5, 6,
-                            4, 6, 7,
-                            // // top face
-                            2, 7, 6,
-                            2, 6, 3,
-                        ].iter().map(|x| (x + ind_offset) as u16));
+    pub fn empty(width: u32, height: u32, depth: u32) -> Self {
+        Self::empty_with(StorageKind::Dense, width, height, depth)
+    }
+
+    pub fn empty_with(kind: StorageKind, width: u32, height: u32, depth: u32) -> Self {
+        Self::with_storage(kind.create([width, height, depth]), width, height, depth)
+    }
+
+    // an empty model using the same kind of storage and palette as this one
+    fn empty_like(&self, width: u32, height: u32, depth: u32) -> Self {
+        let mut data = Self::with_storage(
+            self.storage.empty([width, height, depth]),
+            width,
+            height,
+            depth,
+        );
+        data.palette = self.palette.clone();
+        data
+    }
+
+    /// Moves the voxels into another kind of storage
+    pub fn convert(&mut self, kind: StorageKind) {
+        let mut storage = kind.create(self.dims());
+        for index in 0..(self.width * self.height * self.depth) as usize {
+            let color = self.storage.get(index);
+            if color != 0 {
+                storage.set(index, color);
+            }
+        }
+        self.storage = storage;
+    }
+
+    /// Approximate number of bytes used to store the voxels
+    pub fn memory_usage(&self) -> usize {
+        self.storage.memory_usage()
+    }
+
+    pub fn dims(&self) -> [u32; 3] {
+        [self.width, self.height, self.depth]
+    }
+
+    pub fn in_bounds(&self, x: u32, y: u32, z: u32) -> bool {
+        x < self.width && y < self.height && z < self.depth
+    }
+
+    fn index(&self, x: u32, y: u32, z: u32) -> usize {
+        ((x * self.height * self.depth) + (y * self.depth) + z) as usize
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    /// Recolors the model, voxels keep their indices
+    pub fn palette_mut(&mut self) -> &mut Palette {
+        self.palette_dirty = true;
+        &mut self.palette
+    }
+
+    /// Swaps in another palette and returns the old one, for color variants of the same model
+    pub fn set_palette(&mut self, palette: Palette) -> Palette {
+        self.palette_dirty = true;
+        std::mem::replace(&mut self.palette, palette)
+    }
+
+    /// Switches to a shared palette, every voxel takes the closest color it has. See
+    /// `quantize_to` for dithering.
+    pub fn apply_palette(&mut self, palette: Palette) {
+        self.quantize_to(palette, Dither::None);
+    }
+
+    /// Returns `Color::CLEAR` for positions outside of the model
+    pub fn get(&self, x: u32, y: u32, z: u32) -> Color {
+        self.palette.get(self.get_index(x, y, z))
+    }
+
+    /// Sets a voxel and returns its previous color, positions outside of the model are ignored.
+    ///
+    /// New colors are added to the palette, once it's full the closest color in it is used.
+    pub fn set(&mut self, x: u32, y: u32, z: u32, color: Color) -> Color {
+        if !self.in_bounds(x, y, z) {
+            return Color::CLEAR;
+        }
+        let index = self.index_for(color);
+        let old = self.set_index(x, y, z, index);
+        self.palette.get(old)
+    }
+
+    // the palette index `set` stores for a color, adding it to the palette if there's room
+    fn index_for(&mut self, color: Color) -> u8 {
+        let len = self.palette.len();
+        let index = self.palette.index_of(color);
+        if self.palette.len() != len {
+            self.palette_dirty = true;
+        }
+        index
+    }
+
+    /// Returns the palette index of a voxel, 0 for empty space and positions outside of the
+    /// model
+    pub fn get_index(&self, x: u32, y: u32, z: u32) -> u8 {
+        if self.in_bounds(x, y, z) {
+            self.storage.get(self.index(x, y, z))
+        } else {
+            0
+        }
+    }
+
+    /// Sets the palette index of a voxel and returns its previous index, positions outside of
+    /// the model are ignored
+    pub fn set_index(&mut self, x: u32, y: u32, z: u32, color: u8) -> u8 {
+        if !self.in_bounds(x, y, z) {
+            return 0;
+        }
+        let index = self.index(x, y, z);
+        let old = self.storage.set(index, color);
+        if old != color {
+            self.mark_dirty(Region::point(x, y, z));
+            if let Some(journal) = &mut self.journal {
+                journal.push((index, old));
+            }
+        }
+        old
+    }
+
+    fn position(&self, index: usize) -> [u32; 3] {
+        let index = index as u32;
+        [
+            index / (self.height * self.depth),
+            (index / self.depth) % self.height,
+            index % self.depth,
+        ]
+    }
+
+    // starts recording the previous index of every voxel changed by `set`
+    fn start_journal(&mut self) {
+        self.journal = Some(vec![]);
+    }
+
+    fn take_journal(&mut self) -> Vec<(usize, u8)> {
+        self.journal.take().unwrap_or_default()
+    }
+
+    /// Resizes the model keeping voxels at the same coordinates, voxels outside of the new size
+    /// are dropped and new space is cleared
+    pub fn resize(&mut self, width: u32, height: u32, depth: u32) {
+        let mut resized = self.empty_like(width, height, depth);
+        for x in 0..self.width.min(width) {
+            for y in 0..self.height.min(height) {
+                for z in 0..self.depth.min(depth) {
+                    let index = resized.index(x, y, z);
+                    resized.storage.set(index, self.get_index(x, y, z));
+                }
+            }
+        }
+        self.storage = resized.storage;
+        self.width = width;
+        self.height = height;
+        self.depth = depth;
+        self.dirty = Some(Region::new([0, 0, 0], self.dims()));
+        // recorded indices are meaningless after a resize
+        if let Some(journal) = &mut self.journal {
+            journal.clear();
+        }
+    }
+
+    pub(crate) fn mark_dirty(&mut self, region: Region) {
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => dirty.union(&region),
+            None => region,
+        });
+    }
+
+    /// Whether voxels or the palette changed since they were last taken
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.is_some() || self.palette_dirty
+    }
+
+    /// Returns the region edited since the last call and resets it
+    pub fn take_dirty(&mut self) -> Option<Region> {
+        self.dirty.take()
+    }
+
+    /// Returns whether the palette changed since the last call and resets it
+    pub fn take_palette_dirty(&mut self) -> bool {
+        std::mem::replace(&mut self.palette_dirty, false)
+    }
+
+    // meshes of every sub-chunk in the order `VoxelBuffer` keeps them, `None` as soon as
+    // `cancelled` returns true
+    pub(crate) fn mesh_subchunks(
+        &self,
+        options: &MeshOptions,
+        origin: [i32; 3],
+        outside: Outside,
+        light: Light,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Option<Vec<(Vec<Vertex>, Vec<u16>)>> {
+        let counts = subchunk_counts(self.dims());
+        let mut meshes = vec![];
+        for cx in 0..counts[0] {
+            for cy in 0..counts[1] {
+                for cz in 0..counts[2] {
+                    if cancelled() {
+                        return None;
                     }
+                    let region = self.subchunk_region([cx, cy, cz]);
+                    meshes.push(self.mesh(region, options, origin, outside, light));
                 }
             }
         }
+        Some(meshes)
+    }
 
-        for vert in &mut verts {
-            let ind = vert.uv.x;
-            vert.uv.x = ind / (texels.len() as f32 / 4.0);
-            vert.uv.y = 0.5;
+    fn subchunk_region(&self, chunk: [u32; 3]) -> Region {
+        let min = [
+            chunk[0] * SUBCHUNK_SIZE,
+            chunk[1] * SUBCHUNK_SIZE,
+            chunk[2] * SUBCHUNK_SIZE,
+        ];
+        Region::new(
+            min,
+            [
+                (min[0] + SUBCHUNK_SIZE).min(self.width),
+                (min[1] + SUBCHUNK_SIZE).min(self.height),
+                (min[2] + SUBCHUNK_SIZE).min(self.depth),
+            ],
+        )
+    }
+
+    #[cfg(test)]
+    fn colors(&self) -> Vec<Color> {
+        let len = (self.width * self.height * self.depth) as usize;
+        (0..len)
+            .map(|i| self.palette.get(self.storage.get(i)))
+            .collect()
+    }
+
+    // palette indices for a region in index order
+    fn texels(&self, region: Region) -> Vec<u8> {
+        let mut texels = vec![];
+        for x in region.min[0]..region.max[0] {
+            for y in region.min[1]..region.max[1] {
+                for z in region.min[2]..region.max[2] {
+                    texels.push(self.get_index(x, y, z));
+                }
+            }
         }
-        let tlen = texels.len() as u32;
-        (verts, indices, (texels, [tlen / 4, 1]))
+        texels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the rgba of palette index `index` in row `row` of the palette texture
+    fn texel(texels: &[u8], row: u32, index: u8) -> [u8; 4] {
+        let start = (row as usize * 256 + index as usize) * 4;
+        [
+            texels[start],
+            texels[start + 1],
+            texels[start + 2],
+            texels[start + 3],
+        ]
+    }
+
+    #[test]
+    fn variants_fill_the_rows_after_the_own_palette() {
+        let red = Color::new(255, 0, 0);
+        let green = Color::new(0, 255, 0);
+        let blue = Color::new(0, 0, 255);
+        let own = Palette::from_colors(vec![red]);
+
+        let mut variants = Variants::default();
+        assert_eq!(variants.rows(), 1);
+        assert_eq!(variants.add(Palette::from_colors(vec![green])), 1);
+        assert_eq!(variants.add(Palette::from_colors(vec![blue])), 2);
+        assert_eq!(variants.rows(), 3);
+
+        let texels = variants.texels(&own, &Emitters::new());
+        assert_eq!(texels.len(), 3 * 256 * 4);
+        assert_eq!(texel(&texels, 0, 1), red.rgba());
+        assert_eq!(texel(&texels, 1, 1), green.rgba());
+        assert_eq!(texel(&texels, 2, 1), blue.rgba());
+        assert_eq!(texel(&texels, 2, 0), Color::CLEAR.rgba());
+
+        // replacing a variant only rewrites its own row
+        variants.set(1, Palette::from_colors(vec![blue]));
+        let texels = variants.texels(&own, &Emitters::new());
+        assert_eq!(variants.rows(), 3);
+        assert_eq!(texel(&texels, 0, 1), red.rgba());
+        assert_eq!(texel(&texels, 1, 1), blue.rgba());
+        assert_eq!(texel(&texels, 2, 1), blue.rgba());
+    }
+
+    #[test]
+    fn remeshing_covers_the_neighbors() {
+        let dims = [SUBCHUNK_SIZE * 2, SUBCHUNK_SIZE, 5];
+        // an edit on a sub-chunk border also remeshes the sub-chunk next to it
+        let edge = Region::point(SUBCHUNK_SIZE - 1, 0, 4);
+        assert_eq!(subchunks_to_remesh(edge, dims), Some([0..=1, 0..=0, 0..=0]));
+        let inside = Region::point(SUBCHUNK_SIZE + 3, 3, 3);
+        assert_eq!(
+            subchunks_to_remesh(inside, dims),
+            Some([1..=1, 0..=0, 0..=0])
+        );
+    }
+
+    #[test]
+    fn empty_models_have_nothing_to_remesh() {
+        let all = |dims| Region::new([0, 0, 0], dims);
+        assert_eq!(subchunks_to_remesh(all([0, 0, 0]), [0, 0, 0]), None);
+        assert_eq!(subchunks_to_remesh(all([4, 0, 4]), [4, 0, 4]), None);
+        assert_eq!(
+            subchunks_to_remesh(Region::new([2, 2, 2], [2, 3, 3]), [4, 4, 4]),
+            None
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "variant 0")]
+    fn the_own_palette_is_not_a_variant() {
+        let mut variants = Variants::default();
+        variants.add(Palette::new());
+        variants.set(0, Palette::new());
     }
 }
@@ -0,0 +1,286 @@
+use super::palette::MAX_COLORS;
+use super::quantize::{Dither, Quantizer};
+use super::{MeshOptions, Palette, VoxelBuffer, VoxelData};
+
+/// What happens when playback reaches the last frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Playback {
+    /// Stops on the last frame
+    Once,
+    /// Starts over from the first frame
+    Loop,
+    /// Plays backwards to the first frame, then forwards again
+    PingPong,
+}
+
+// playback state, kept apart from the buffers so it works without a gpu
+#[derive(Debug, Clone)]
+struct Clock {
+    frames: usize,
+    fps: f32,
+    playback: Playback,
+    time: f32,
+    playing: bool,
+}
+
+impl Clock {
+    // number of frames before the animation repeats
+    fn period(&self) -> usize {
+        match self.playback {
+            Playback::PingPong if self.frames > 1 => 2 * (self.frames - 1),
+            _ => self.frames,
+        }
+    }
+
+    fn step(&self) -> usize {
+        (self.time * self.fps) as usize
+    }
+
+    fn frame(&self) -> usize {
+        let step = self.step();
+        match self.playback {
+            Playback::Once => step.min(self.frames - 1),
+            Playback::Loop => step % self.frames,
+            Playback::PingPong => {
+                let period = self.period();
+                let step = step % period;
+                if step < self.frames {
+                    step
+                } else {
+                    period - step
+                }
+            }
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.playback == Playback::Once && self.step() >= self.frames - 1
+    }
+
+    fn advance(&mut self, dt: f32) {
+        if !self.playing {
+            return;
+        }
+        self.time += dt;
+        // wrap repeating animations so the time doesn't lose precision over long sessions
+        let period = self.period() as f32 / self.fps;
+        match self.playback {
+            Playback::Once => self.time = self.time.min(period),
+            Playback::Loop | Playback::PingPong => self.time %= period,
+        }
+    }
+}
+
+/// A sequence of models played back like a flipbook, such as an animation exported from
+/// MagicaVoxel one frame per model.
+///
+/// Every frame is meshed and uploaded once up front, so switching frames only changes which
+/// buffers get drawn. All frames share one palette.
+pub struct AnimatedVoxelBuffer {
+    frames: Vec<VoxelBuffer>,
+    clock: Clock,
+}
+
+impl AnimatedVoxelBuffer {
+    pub fn new(frames: Vec<VoxelData>, fps: f32, ctx: &crate::Context) -> Self {
+        Self::with_options(frames, fps, MeshOptions::default(), ctx)
+    }
+
+    /// Builds the frames onto one shared palette, see `share_palette`. Starts playing in a loop.
+    pub fn with_options(
+        mut frames: Vec<VoxelData>,
+        fps: f32,
+        options: MeshOptions,
+        ctx: &crate::Context,
+    ) -> Self {
+        assert!(!frames.is_empty(), "an animation needs at least one frame");
+        assert!(fps > 0.0, "fps has to be positive");
+        share_palette(&mut frames);
+        let clock = Clock {
+            frames: frames.len(),
+            fps,
+            playback: Playback::Loop,
+            time: 0.0,
+            playing: true,
+        };
+        let frames = frames
+            .into_iter()
+            .map(|data| VoxelBuffer::from_data_with(data, options, ctx))
+            .collect();
+        Self { frames, clock }
+    }
+
+    /// Moves playback forward by `dt` seconds
+    pub fn advance(&mut self, dt: f32) {
+        self.clock.advance(dt);
+    }
+
+    /// Uploads edits made to any frame, returns `true` if bind groups of any frame's textures
+    /// have to be rebuilt
+    pub fn update(&mut self, ctx: &crate::Context) -> bool {
+        let mut rebind = false;
+        for frame in &mut self.frames {
+            rebind |= frame.update(ctx);
+        }
+        rebind
+    }
+
+    pub fn play(&mut self) {
+        if self.clock.is_finished() {
+            self.clock.time = 0.0;
+        }
+        self.clock.playing = true;
+    }
+
+    pub fn pause(&mut self) {
+        self.clock.playing = false;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.clock.playing
+    }
+
+    /// Whether a `Playback::Once` animation reached its last frame
+    pub fn is_finished(&self) -> bool {
+        self.clock.is_finished()
+    }
+
+    /// Jumps to a frame, ping-pong playback continues forwards from it
+    pub fn seek(&mut self, frame: usize) {
+        assert!(frame < self.frames.len(), "frame {} out of range", frame);
+        self.clock.time = frame as f32 / self.clock.fps;
+    }
+
+    pub fn fps(&self) -> f32 {
+        self.clock.fps
+    }
+
+    /// Changes the speed, keeping the current frame
+    pub fn set_fps(&mut self, fps: f32) {
+        assert!(fps > 0.0, "fps has to be positive");
+        self.clock.time *= self.clock.fps / fps;
+        self.clock.fps = fps;
+    }
+
+    pub fn playback(&self) -> Playback {
+        self.clock.playback
+    }
+
+    pub fn set_playback(&mut self, playback: Playback) {
+        let frame = self.frame_index();
+        self.clock.playback = playback;
+        self.seek(frame);
+    }
+
+    pub fn frame_index(&self) -> usize {
+        self.clock.frame()
+    }
+
+    /// The frame to draw right now
+    pub fn current(&self) -> &VoxelBuffer {
+        &self.frames[self.frame_index()]
+    }
+
+    pub fn frames(&self) -> &[VoxelBuffer] {
+        &self.frames
+    }
+
+    /// Colors a frame's palette doesn't have yet only get added to that frame, use
+    /// `set_palette` to recolor the whole animation
+    pub fn frame_mut(&mut self, frame: usize) -> &mut VoxelBuffer {
+        &mut self.frames[frame]
+    }
+
+    pub fn palette(&self) -> &Palette {
+        self.frames[0].data().palette()
+    }
+
+    /// Swaps the palette of every frame, voxels keep their indices
+    pub fn set_palette(&mut self, palette: Palette) {
+        for frame in &mut self.frames {
+            frame.data_mut().set_palette(palette.clone());
+        }
+    }
+}
+
+/// Moves every model onto one palette holding the colors of all of them. Models keep their
+/// colors unless there are more than `palette::MAX_COLORS` between them, then the palette is
+/// quantized with median cut.
+pub fn share_palette(models: &mut [VoxelData]) {
+    let colors = models
+        .iter()
+        .flat_map(|m| m.palette().colors().iter().copied());
+    let unique: std::collections::HashSet<_> = colors.clone().collect();
+    let palette = if unique.len() > MAX_COLORS {
+        Palette::quantized(colors, MAX_COLORS, Quantizer::MedianCut)
+    } else {
+        Palette::from_colors(colors)
+    };
+    for model in models {
+        if model.palette() != &palette {
+            model.quantize_to(palette.clone(), Dither::None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxel_data::Color;
+
+    fn clock(frames: usize, playback: Playback) -> Clock {
+        Clock {
+            frames,
+            fps: 10.0,
+            playback,
+            time: 0.0,
+            playing: true,
+        }
+    }
+
+    fn played(clock: &mut Clock, steps: usize) -> Vec<usize> {
+        (0..steps)
+            .map(|_| {
+                let frame = clock.frame();
+                clock.advance(0.1001);
+                frame
+            })
+            .collect()
+    }
+
+    #[test]
+    fn playback_modes() {
+        assert_eq!(
+            played(&mut clock(3, Playback::Loop), 7),
+            [0, 1, 2, 0, 1, 2, 0]
+        );
+        assert_eq!(
+            played(&mut clock(3, Playback::PingPong), 7),
+            [0, 1, 2, 1, 0, 1, 2]
+        );
+        let mut once = clock(3, Playback::Once);
+        assert_eq!(played(&mut once, 5), [0, 1, 2, 2, 2]);
+        assert!(once.is_finished());
+        assert_eq!(played(&mut clock(1, Playback::PingPong), 3), [0, 0, 0]);
+
+        let mut paused = clock(3, Playback::Loop);
+        paused.playing = false;
+        assert_eq!(played(&mut paused, 3), [0, 0, 0]);
+    }
+
+    #[test]
+    fn frames_share_a_palette() {
+        let red = Color::new(255, 0, 0);
+        let blue = Color::new(0, 0, 255);
+        let mut frames = vec![
+            VoxelData::new(vec![red, Color::CLEAR], 2, 1, 1),
+            VoxelData::new(vec![Color::CLEAR, blue, red, red], 4, 1, 1),
+        ];
+        share_palette(&mut frames);
+        assert_eq!(frames[0].palette(), frames[1].palette());
+        assert_eq!(frames[0].get(0, 0, 0), red);
+        assert_eq!(frames[1].get(1, 0, 0), blue);
+        assert_eq!(frames[1].get(2, 0, 0), red);
+        assert_eq!(frames[1].get(0, 0, 0), Color::CLEAR);
+    }
+}
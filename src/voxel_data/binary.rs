@@ -0,0 +1,453 @@
+//! The Janus binary model format.
+//!
+//! Everything is little endian:
+//!
+//! | bytes | contents |
+//! |-------|----------|
+//! | 4 | magic, `JANV` |
+//! | 1 | version |
+//! | 1 | flags, bit 0 is a name and bit 1 a pivot |
+//! | 12 | width, height and depth as `u32` |
+//! | 1 + 4n | number of palette colors followed by their rgba, alpha 0 for invisible entries |
+//! | varint + n | name as utf-8, if flagged |
+//! | 12 | pivot as three `f32`, if flagged |
+//! | varint + runs | number of runs followed by every run as a varint length and a palette index |
+//! | 4 | crc32 of everything before it |
+//!
+//! Runs cover the voxels in index order, so large empty or solid areas take a couple of bytes.
+//! Models of more than `MAX_VOLUME` voxels are refused when loading, as a handful of bytes can
+//! claim any size.
+//! Version 1 stored palette colors as rgb only, every one of them visible.
+
+use super::storage::Dense;
+use super::{Color, Palette, VoxelData};
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"JANV";
+/// The format version written by `to_bytes`
+pub const VERSION: u8 = 2;
+
+/// Most voxels a loaded model may have, 128 MiB of palette indices
+pub const MAX_VOLUME: u64 = 512 * 512 * 512;
+
+const HAS_NAME: u8 = 1;
+const HAS_PIVOT: u8 = 2;
+
+/// Optional information stored next to the voxels
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Metadata {
+    pub name: Option<String>,
+    /// The point the model is placed and rotated around, in voxels
+    pub pivot: Option<[f32; 3]>,
+}
+
+/// Why a model couldn't be loaded
+#[derive(Debug)]
+pub enum BinaryError {
+    Io(io::Error),
+    /// The data doesn't start with the Janus magic bytes
+    NotJanus,
+    /// The data was written by a newer version of Janus
+    UnsupportedVersion(u8),
+    /// The data ends in the middle of the model
+    UnexpectedEnd,
+    /// The checksum doesn't match, the data was damaged
+    ChecksumMismatch,
+    /// The data is well formed but describes an impossible model
+    Invalid(&'static str),
+}
+
+impl fmt::Display for BinaryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BinaryError::Io(err) => write!(f, "{}", err),
+            BinaryError::NotJanus => write!(f, "not a janus model"),
+            BinaryError::UnsupportedVersion(v) => write!(f, "unsupported format version {}", v),
+            BinaryError::UnexpectedEnd => write!(f, "model data ends early"),
+            BinaryError::ChecksumMismatch => write!(f, "model data is damaged"),
+            BinaryError::Invalid(reason) => write!(f, "invalid model: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for BinaryError {}
+
+impl From<io::Error> for BinaryError {
+    fn from(err: io::Error) -> Self {
+        BinaryError::Io(err)
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// reads through a byte slice, running out of bytes is an error
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], BinaryError> {
+        if len > self.bytes.len() {
+            return Err(BinaryError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, BinaryError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, BinaryError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn f32(&mut self) -> Result<f32, BinaryError> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    fn varint(&mut self) -> Result<u64, BinaryError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(BinaryError::Invalid("varint is too long"))
+    }
+}
+
+impl VoxelData {
+    /// Encodes the model in the Janus binary format without any metadata
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_with(&Metadata::default())
+    }
+
+    /// Encodes the model and its metadata in the Janus binary format
+    pub fn to_bytes_with(&self, metadata: &Metadata) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        let mut flags = 0;
+        if metadata.name.is_some() {
+            flags |= HAS_NAME;
+        }
+        if metadata.pivot.is_some() {
+            flags |= HAS_PIVOT;
+        }
+        out.push(flags);
+        for dim in &self.dims() {
+            out.extend_from_slice(&dim.to_le_bytes());
+        }
+
+        out.push(self.palette.len() as u8);
+        for color in self.palette.colors() {
+            out.extend_from_slice(&color.rgba());
+        }
+
+        if let Some(name) = &metadata.name {
+            write_varint(&mut out, name.len() as u64);
+            out.extend_from_slice(name.as_bytes());
+        }
+        if let Some(pivot) = metadata.pivot {
+            for p in &pivot {
+                out.extend_from_slice(&p.to_bits().to_le_bytes());
+            }
+        }
+
+        let mut runs: Vec<(u64, u8)> = vec![];
+        for index in 0..(self.width * self.height * self.depth) as usize {
+            let color = self.storage.get(index);
+            match runs.last_mut() {
+                Some((len, last)) if *last == color => *len += 1,
+                _ => runs.push((1, color)),
+            }
+        }
+        write_varint(&mut out, runs.len() as u64);
+        for (len, color) in runs {
+            write_varint(&mut out, len);
+            out.push(color);
+        }
+
+        let crc = crc32fast::hash(&out);
+        out.extend_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    /// Decodes a model in the Janus binary format, dropping its metadata
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BinaryError> {
+        Self::from_bytes_with(bytes).map(|(data, _)| data)
+    }
+
+    /// Decodes a model and its metadata, damaged or truncated data is an error rather than a
+    /// panic
+    pub fn from_bytes_with(bytes: &[u8]) -> Result<(Self, Metadata), BinaryError> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(BinaryError::NotJanus);
+        }
+        if bytes.len() < MAGIC.len() + 1 + 4 {
+            return Err(BinaryError::UnexpectedEnd);
+        }
+        let (body, crc) = bytes.split_at(bytes.len() - 4);
+        let mut reader = Reader { bytes: body };
+        reader.take(MAGIC.len())?;
+        let version = reader.u8()?;
+        if version > VERSION {
+            return Err(BinaryError::UnsupportedVersion(version));
+        }
+        if crc32fast::hash(body).to_le_bytes() != crc {
+            return Err(BinaryError::ChecksumMismatch);
+        }
+
+        let flags = reader.u8()?;
+        let [width, height, depth] = [reader.u32()?, reader.u32()?, reader.u32()?];
+        let volume = (width as u64) * (height as u64) * (depth as u64);
+        if volume > MAX_VOLUME {
+            return Err(BinaryError::Invalid("model is too big"));
+        }
+
+        let count = reader.u8()? as usize;
+        let mut colors = Vec::with_capacity(count);
+        for _ in 0..count {
+            // entries are kept exactly as stored, repeats included, so indices stay valid
+            let color = if version < 2 {
+                let rgb = reader.take(3)?;
+                Color::new(rgb[0], rgb[1], rgb[2])
+            } else {
+                let rgba = reader.take(4)?;
+                match rgba[3] {
+                    0 => Color::CLEAR,
+                    _ => Color::new(rgba[0], rgba[1], rgba[2]),
+                }
+            };
+            colors.push(color);
+        }
+        let palette = Palette::from_entries(colors);
+
+        let mut metadata = Metadata::default();
+        if flags & HAS_NAME != 0 {
+            let len = reader.varint()? as usize;
+            let name = std::str::from_utf8(reader.take(len)?)
+                .map_err(|_| BinaryError::Invalid("name is not utf-8"))?;
+            metadata.name = Some(name.to_string());
+        }
+        if flags & HAS_PIVOT != 0 {
+            metadata.pivot = Some([reader.f32()?, reader.f32()?, reader.f32()?]);
+        }
+
+        let runs = reader.varint()?;
+        let mut indices = vec![];
+        for _ in 0..runs {
+            let len = reader.varint()?;
+            let color = reader.u8()?;
+            if color as usize > count {
+                return Err(BinaryError::Invalid("voxel outside of the palette"));
+            }
+            if indices.len() as u64 + len > volume {
+                return Err(BinaryError::Invalid("more voxels than fit in the model"));
+            }
+            indices.resize(indices.len() + len as usize, color);
+        }
+        if indices.len() as u64 != volume {
+            return Err(BinaryError::Invalid("fewer voxels than fit in the model"));
+        }
+        if !reader.bytes.is_empty() {
+            return Err(BinaryError::Invalid("trailing data after the voxels"));
+        }
+
+        let mut data = Self::with_storage(Box::new(Dense::new(indices)), width, height, depth);
+        data.palette = palette;
+        Ok((data, metadata))
+    }
+
+    /// Loads a model saved with `save`
+    pub fn load(path: impl AsRef<Path>) -> Result<(Self, Metadata), BinaryError> {
+        Self::from_bytes_with(&std::fs::read(path)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>, metadata: &Metadata) -> io::Result<()> {
+        std::fs::write(path, self.to_bytes_with(metadata))
+    }
+}
+
+// Models embed in other serde formats as their binary encoding.
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use super::VoxelData;
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::fmt;
+
+    impl Serialize for VoxelData {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(&self.to_bytes())
+        }
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = VoxelData;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a janus binary model")
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<VoxelData, E> {
+            VoxelData::from_bytes(bytes).map_err(E::custom)
+        }
+
+        // text formats such as json write bytes as a list of numbers
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<VoxelData, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            self.visit_bytes(&bytes)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for VoxelData {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_bytes(BytesVisitor)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxel_data::shapes::Brush;
+
+    fn sample() -> VoxelData {
+        let mut data = VoxelData::empty(20, 30, 40);
+        data.fill_box([0, 0, 0], [19, 4, 39], Brush::Fill(Color::new(90, 60, 30)));
+        data.fill_sphere([10, 15, 20], 6.0, Brush::Fill(Color::new(200, 10, 10)));
+        data.set(19, 29, 39, Color::new(1, 2, 3));
+        data
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = sample();
+        let metadata = Metadata {
+            name: Some("rock".to_string()),
+            pivot: Some([10.0, 0.0, 20.0]),
+        };
+        let bytes = data.to_bytes_with(&metadata);
+        // solid slabs and empty space compress well
+        assert!(bytes.len() < 20 * 30 * 40 / 4, "{} bytes", bytes.len());
+
+        let (loaded, loaded_metadata) = VoxelData::from_bytes_with(&bytes).unwrap();
+        assert_eq!(loaded_metadata, metadata);
+        assert_eq!(loaded.dims(), data.dims());
+        assert_eq!(loaded.palette(), data.palette());
+        assert_eq!(loaded.colors(), data.colors());
+
+        let empty = VoxelData::empty(0, 0, 0);
+        assert_eq!(
+            VoxelData::from_bytes(&empty.to_bytes()).unwrap().dims(),
+            [0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn palette_entries_survive_exactly() {
+        let red = Color::new(255, 0, 0);
+        let mut data = VoxelData::empty(3, 1, 1);
+        data.set(0, 0, 0, red);
+        data.set(1, 0, 0, Color::new(0, 255, 0));
+        data.set(2, 0, 0, Color::new(0, 0, 255));
+        // a repeated entry and a hidden one, both reachable through the public palette
+        data.palette_mut().set(2, red);
+        data.palette_mut().set(3, Color::CLEAR);
+
+        let loaded = VoxelData::from_bytes(&data.to_bytes()).unwrap();
+        assert_eq!(loaded.palette(), data.palette());
+        assert_eq!(loaded.palette().colors(), &[red, red, Color::CLEAR]);
+        for x in 0..3 {
+            assert_eq!(loaded.get_index(x, 0, 0), data.get_index(x, 0, 0));
+            assert_eq!(loaded.get(x, 0, 0), data.get(x, 0, 0));
+        }
+    }
+
+    #[test]
+    fn version_one_stores_rgb() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&[1, 0]);
+        for _ in 0..3 {
+            bytes.extend_from_slice(&1u32.to_le_bytes());
+        }
+        bytes.extend_from_slice(&[1, 10, 20, 30]);
+        // one run of one voxel of color 1
+        bytes.extend_from_slice(&[1, 1, 1]);
+        let crc = crc32fast::hash(&bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+
+        let loaded = VoxelData::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.get(0, 0, 0), Color::new(10, 20, 30));
+    }
+
+    #[test]
+    fn corrupt_input_is_an_error() {
+        let bytes = sample().to_bytes();
+        assert!(matches!(
+            VoxelData::from_bytes(b"JANX"),
+            Err(BinaryError::NotJanus)
+        ));
+        assert!(VoxelData::from_bytes(&bytes[..bytes.len() / 2]).is_err());
+
+        let mut flipped = bytes.clone();
+        flipped[30] ^= 0x10;
+        assert!(matches!(
+            VoxelData::from_bytes(&flipped),
+            Err(BinaryError::ChecksumMismatch)
+        ));
+
+        let mut newer = bytes;
+        newer[4] = VERSION + 1;
+        assert!(matches!(
+            VoxelData::from_bytes(&newer),
+            Err(BinaryError::UnsupportedVersion(_))
+        ));
+
+        // a few bytes with a valid checksum claiming billions of empty voxels
+        let mut huge = MAGIC.to_vec();
+        huge.extend_from_slice(&[VERSION, 0]);
+        for _ in 0..3 {
+            huge.extend_from_slice(&1600u32.to_le_bytes());
+        }
+        huge.push(0);
+        // one run covering them all, 1600^3 as a varint
+        huge.extend_from_slice(&[1, 0x80, 0x80, 0x90, 0xa1, 0x0f, 0]);
+        let crc = crc32fast::hash(&huge);
+        huge.extend_from_slice(&crc.to_le_bytes());
+        assert!(matches!(
+            VoxelData::from_bytes(&huge),
+            Err(BinaryError::Invalid("model is too big"))
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_embeds_the_binary_format() {
+        let data = sample();
+        let json = serde_json::to_string(&data).unwrap();
+        let loaded: VoxelData = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.colors(), data.colors());
+        assert!(serde_json::from_str::<VoxelData>("[1, 2, 3]").is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use super::{Color, VoxelData};
+use std::collections::HashMap;
+use ultraviolet::*;
+
+// rounds of moving clusters to the middle of their voxels, they settle well before this
+const CLUSTER_ROUNDS: usize = 8;
+
+/// Glowing voxels of a model close enough together to light their surroundings as one light
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct EmissiveCluster {
+    /// Middle of the voxels weighted by how brightly they glow, in model space
+    pub center: Vec3,
+    /// Average color of the voxels weighted by how brightly they glow, from 0 to 1
+    pub color: Vec3,
+    /// Strengths of all of the voxels added up
+    pub strength: f32,
+    pub voxels: u32,
+}
+
+/// Which colors of a model glow and how brightly, voxels of other colors don't give off light
+#[derive(Debug, Clone, Default)]
+pub struct Emitters {
+    strengths: HashMap<Color, f32>,
+}
+
+impl Emitters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes voxels of a color glow in that color times `strength`, 0 stops them glowing
+    pub fn set(&mut self, color: Color, strength: f32) {
+        if strength > 0.0 && color.is_visible() {
+            self.strengths.insert(color, strength);
+        } else {
+            self.strengths.remove(&color);
+        }
+    }
+
+    /// How brightly a color glows, 0 for colors that don't
+    pub fn get(&self, color: Color) -> f32 {
+        self.strengths.get(&color).copied().unwrap_or(0.0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strengths.is_empty()
+    }
+
+    /// Groups the glowing voxels of a model into at most `max` clusters, nearby voxels end up in
+    /// the same one
+    pub fn clusters(&self, data: &VoxelData, max: usize) -> Vec<EmissiveCluster> {
+        if self.is_empty() || max == 0 {
+            return vec![];
+        }
+        // center, color and strength of every glowing voxel
+        let [w, h, d] = data.dims();
+        let mut glowing = vec![];
+        for x in 0..w {
+            for y in 0..h {
+                for z in 0..d {
+                    let color = data.get(x, y, z);
+                    let strength = self.get(color);
+                    if strength > 0.0 {
+                        let [r, g, b] = color.rgb();
+                        glowing.push((
+                            Vec3::new(x as f32, y as f32, z as f32) + Vec3::broadcast(0.5),
+                            Vec3::new(r as f32, g as f32, b as f32) / 255.0,
+                            strength,
+                        ));
+                    }
+                }
+            }
+        }
+        if glowing.is_empty() {
+            return vec![];
+        }
+
+        // start from the brightest voxel and keep adding the one furthest from every center
+        let brightest =
+            glowing.iter().enumerate().fold(
+                0,
+                |best, (i, v)| if v.2 > glowing[best].2 { i } else { best },
+            );
+        let mut centers = vec![glowing[brightest].0];
+        while centers.len() < max.min(glowing.len()) {
+            let distance = |pos: Vec3| {
+                centers
+                    .iter()
+                    .map(|c| (*c - pos).mag_sq())
+                    .fold(f32::MAX, f32::min)
+            };
+            let (far, dist) = glowing.iter().map(|v| (v.0, distance(v.0))).fold(
+                (Vec3::zero(), 0.0),
+                |best, v| if v.1 > best.1 { v } else { best },
+            );
+            if dist == 0.0 {
+                break;
+            }
+            centers.push(far);
+        }
+
+        let nearest = |centers: &[Vec3], pos: Vec3| {
+            (0..centers.len())
+                .min_by(|&a, &b| {
+                    let (da, db) = ((centers[a] - pos).mag_sq(), (centers[b] - pos).mag_sq());
+                    da.partial_cmp(&db).unwrap()
+                })
+                .unwrap()
+        };
+        let mut clusters = vec![];
+        for _ in 0..=CLUSTER_ROUNDS {
+            clusters = vec![
+                EmissiveCluster {
+                    center: Vec3::zero(),
+                    color: Vec3::zero(),
+                    strength: 0.0,
+                    voxels: 0,
+                };
+                centers.len()
+            ];
+            for &(pos, color, strength) in &glowing {
+                let cluster = &mut clusters[nearest(&centers, pos)];
+                cluster.center += pos * strength;
+                cluster.color += color * strength;
+                cluster.strength += strength;
+                cluster.voxels += 1;
+            }
+            for (cluster, center) in clusters.iter_mut().zip(&mut centers) {
+                if cluster.voxels > 0 {
+                    cluster.center /= cluster.strength;
+                    cluster.color /= cluster.strength;
+                    *center = cluster.center;
+                }
+            }
+        }
+        clusters.retain(|c| c.voxels > 0);
+        clusters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearby_voxels_share_a_cluster() {
+        let red = Color::new(255, 0, 0);
+        let blue = Color::new(0, 0, 255);
+        let mut data = VoxelData::empty(16, 1, 1);
+        data.set(0, 0, 0, red);
+        data.set(1, 0, 0, red);
+        data.set(14, 0, 0, blue);
+        data.set(15, 0, 0, blue);
+        data.set(8, 0, 0, Color::new(1, 1, 1));
+        let mut emitters = Emitters::new();
+        emitters.set(red, 1.0);
+        emitters.set(blue, 3.0);
+
+        let mut clusters = emitters.clusters(&data, 4);
+        clusters.sort_by(|a, b| a.center.x.partial_cmp(&b.center.x).unwrap());
+        assert_eq!(clusters.len(), 4);
+        assert_eq!(clusters[0].center, Vec3::new(0.5, 0.5, 0.5));
+
+        let mut clusters = emitters.clusters(&data, 2);
+        clusters.sort_by(|a, b| a.center.x.partial_cmp(&b.center.x).unwrap());
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].center, Vec3::new(1.0, 0.5, 0.5));
+        assert_eq!(clusters[0].color, Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(clusters[1].strength, 6.0);
+        assert_eq!(clusters[1].voxels, 2);
+
+        // one light weighs the brighter side more
+        let all = emitters.clusters(&data, 1);
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].center.x, (0.5 + 1.5 + 3.0 * 14.5 + 3.0 * 15.5) / 8.0);
+        assert!(emitters.clusters(&data, 0).is_empty());
+    }
+}
@@ -0,0 +1,412 @@
+use super::{Color, Palette, VoxelData};
+use std::collections::{HashMap, VecDeque};
+
+/// Default cap on the memory used by a `History`
+pub const DEFAULT_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Copy, Clone)]
+struct Change {
+    index: usize,
+    before: u8,
+    after: u8,
+}
+
+// a reversible set of voxel changes, one undo step
+#[derive(Debug, Default)]
+struct Delta {
+    dims: [u32; 3],
+    changes: Vec<Change>,
+    // the palette before and after, if the edit changed it
+    palette: Option<(Palette, Palette)>,
+    // position of each voxel index in `changes`, only kept while a group is open
+    lookup: HashMap<usize, usize>,
+}
+
+impl Delta {
+    fn from_journal(journal: Vec<(usize, u8)>, palette: Palette, data: &VoxelData) -> Self {
+        let mut delta = Self {
+            dims: data.dims(),
+            ..Self::default()
+        };
+        if palette != data.palette {
+            delta.palette = Some((palette, data.palette.clone()));
+        }
+        for (index, before) in journal {
+            // only the first change to a voxel knows its original index
+            if !delta.lookup.contains_key(&index) {
+                delta.lookup.insert(index, delta.changes.len());
+                delta.changes.push(Change {
+                    index,
+                    before,
+                    after: data.storage.get(index),
+                });
+            }
+        }
+        delta
+    }
+
+    fn merge(&mut self, other: Delta) {
+        if self.changes.is_empty() {
+            self.dims = other.dims;
+        }
+        if let Some((before, after)) = other.palette {
+            match &mut self.palette {
+                Some((_, last)) => *last = after,
+                None => self.palette = Some((before, after)),
+            }
+        }
+        for change in other.changes {
+            match self.lookup.get(&change.index) {
+                Some(&i) => self.changes[i].after = change.after,
+                None => {
+                    self.lookup.insert(change.index, self.changes.len());
+                    self.changes.push(change);
+                }
+            }
+        }
+    }
+
+    // drops no-op changes and the lookup table once the delta is final
+    fn finish(mut self) -> Self {
+        self.lookup = HashMap::new();
+        self.changes.retain(|c| c.before != c.after);
+        if matches!(&self.palette, Some((before, after)) if before == after) {
+            self.palette = None;
+        }
+        self
+    }
+
+    fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.palette.is_none()
+    }
+
+    fn bytes(&self) -> usize {
+        let palettes = self.palette.as_ref().map_or(0, |(before, after)| {
+            (before.len() + after.len()) * std::mem::size_of::<Color>()
+        });
+        self.changes.len() * std::mem::size_of::<Change>() + palettes
+    }
+
+    fn revert(&self, data: &mut VoxelData) {
+        for change in self.changes.iter().rev() {
+            let [x, y, z] = data.position(change.index);
+            data.set_index(x, y, z, change.before);
+        }
+        if let Some((before, _)) = &self.palette {
+            data.set_palette(before.clone());
+        }
+    }
+
+    fn apply(&self, data: &mut VoxelData) {
+        if let Some((_, after)) = &self.palette {
+            data.set_palette(after.clone());
+        }
+        for change in &self.changes {
+            let [x, y, z] = data.position(change.index);
+            data.set_index(x, y, z, change.after);
+        }
+    }
+}
+
+/// Undo/redo history of edits made to a `VoxelData`.
+///
+/// Every edit is stored as the list of voxels it changed along with their palette indices
+/// before and after, plus the whole palette before and after if the edit changed it, so undoing
+/// and redoing restores the exact same data. Size changes such as
+/// `VoxelData::resize` can't be recorded, call `clear` after making them.
+pub struct History {
+    undo: VecDeque<Delta>,
+    redo: Vec<Delta>,
+    group: Option<Delta>,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::with_max_bytes(DEFAULT_MAX_BYTES)
+    }
+
+    /// Creates a history that drops its oldest steps once it uses more than `max_bytes`
+    pub fn with_max_bytes(max_bytes: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: vec![],
+            group: None,
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    /// Runs an edit against `data` and records every voxel it changes as one undo step, or as
+    /// part of the open group
+    pub fn edit<R>(&mut self, data: &mut VoxelData, f: impl FnOnce(&mut VoxelData) -> R) -> R {
+        let palette = data.palette.clone();
+        data.start_journal();
+        let result = f(data);
+        let delta = Delta::from_journal(data.take_journal(), palette, data);
+        match &mut self.group {
+            Some(group) => group.merge(delta),
+            None => self.push(delta.finish()),
+        }
+        result
+    }
+
+    /// Sets a single voxel, see `VoxelData::set`
+    pub fn set(&mut self, data: &mut VoxelData, x: u32, y: u32, z: u32, color: Color) -> Color {
+        self.edit(data, |data| data.set(x, y, z, color))
+    }
+
+    /// Starts grouping edits into a single undo step, such as all the voxels of a brush stroke
+    pub fn begin_group(&mut self) {
+        if self.group.is_none() {
+            self.group = Some(Delta::default());
+        }
+    }
+
+    pub fn end_group(&mut self) {
+        if let Some(group) = self.group.take() {
+            self.push(group.finish());
+        }
+    }
+
+    /// Returns false if there was nothing to undo
+    pub fn undo(&mut self, data: &mut VoxelData) -> bool {
+        self.end_group();
+        let delta = match self.undo.pop_back() {
+            Some(delta) => delta,
+            None => return false,
+        };
+        if delta.dims != data.dims() {
+            self.clear();
+            return false;
+        }
+        delta.revert(data);
+        self.redo.push(delta);
+        true
+    }
+
+    /// Returns false if there was nothing to redo
+    pub fn redo(&mut self, data: &mut VoxelData) -> bool {
+        self.end_group();
+        let delta = match self.redo.pop() {
+            Some(delta) => delta,
+            None => return false,
+        };
+        if delta.dims != data.dims() {
+            self.clear();
+            return false;
+        }
+        delta.apply(data);
+        self.undo.push_back(delta);
+        true
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty() || self.group.as_ref().is_some_and(|g| !g.is_empty())
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.group = None;
+        self.bytes = 0;
+    }
+
+    /// Approximate memory used by the recorded steps
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn push(&mut self, delta: Delta) {
+        if delta.is_empty() {
+            return;
+        }
+        for dropped in self.redo.drain(..) {
+            self.bytes -= dropped.bytes();
+        }
+        self.bytes += delta.bytes();
+        self.undo.push_back(delta);
+        // always keep the latest step even if it is over the cap on its own
+        while self.bytes > self.max_bytes && self.undo.len() > 1 {
+            let dropped = self.undo.pop_front().unwrap();
+            self.bytes -= dropped.bytes();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxel_data::quantize::Dither;
+
+    // the palette and every palette index, equal only if the data is byte for byte the same
+    fn snapshot(data: &VoxelData) -> (Palette, Vec<u8>) {
+        let [w, h, d] = data.dims();
+        let mut indices = vec![];
+        for x in 0..w {
+            for y in 0..h {
+                for z in 0..d {
+                    indices.push(data.get_index(x, y, z));
+                }
+            }
+        }
+        (data.palette().clone(), indices)
+    }
+
+    fn sample() -> VoxelData {
+        let mut data = VoxelData::empty(4, 3, 5);
+        data.set(1, 1, 1, Color::new(10, 20, 30));
+        data.set(3, 2, 4, Color::new(40, 50, 60));
+        data
+    }
+
+    #[test]
+    fn undo_redo_restores_data() {
+        let mut data = sample();
+        let original = snapshot(&data);
+        let mut history = History::new();
+
+        history.set(&mut data, 0, 0, 0, Color::new(1, 2, 3));
+        history.set(&mut data, 1, 1, 1, Color::CLEAR);
+        let edited = snapshot(&data);
+
+        assert!(history.undo(&mut data));
+        assert!(history.undo(&mut data));
+        assert!(!history.undo(&mut data));
+        assert_eq!(snapshot(&data), original);
+
+        assert!(history.redo(&mut data));
+        assert!(history.redo(&mut data));
+        assert!(!history.redo(&mut data));
+        assert_eq!(snapshot(&data), edited);
+    }
+
+    #[test]
+    fn groups_are_one_step() {
+        let mut data = sample();
+        let original = snapshot(&data);
+        let mut history = History::new();
+
+        history.begin_group();
+        for x in 0..4 {
+            history.set(&mut data, x, 0, 0, Color::new(9, 9, 9));
+        }
+        // painting over the same voxel twice in a stroke still undoes to the original color
+        history.set(&mut data, 0, 0, 0, Color::new(7, 7, 7));
+        history.end_group();
+
+        assert!(history.undo(&mut data));
+        assert_eq!(snapshot(&data), original);
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn bulk_edits() {
+        let mut data = sample();
+        let original = snapshot(&data);
+        let mut history = History::new();
+
+        history.edit(&mut data, |data| {
+            for x in 0..4 {
+                for y in 0..3 {
+                    for z in 0..5 {
+                        data.set(x, y, z, Color::new(1, 1, 1));
+                    }
+                }
+            }
+        });
+        let filled = snapshot(&data);
+
+        history.undo(&mut data);
+        assert_eq!(snapshot(&data), original);
+        history.redo(&mut data);
+        assert_eq!(snapshot(&data), filled);
+    }
+
+    #[test]
+    fn palette_changes_are_undone() {
+        let mut data = sample();
+        let original = snapshot(&data);
+        let mut history = History::new();
+
+        // a new color grows the palette, undoing takes the entry back out
+        history.set(&mut data, 0, 0, 0, Color::new(1, 2, 3));
+        assert_eq!(data.palette().len(), 3);
+        let grown = snapshot(&data);
+        assert!(history.undo(&mut data));
+        assert_eq!(snapshot(&data), original);
+        assert!(history.redo(&mut data));
+        assert_eq!(snapshot(&data), grown);
+    }
+
+    #[test]
+    fn palette_swaps_are_undone() {
+        let mut data = sample();
+        let original = snapshot(&data);
+        let mut history = History::new();
+
+        let swapped = Palette::from_colors(vec![Color::new(40, 50, 60), Color::new(10, 20, 30)]);
+        history.edit(&mut data, |data| data.apply_palette(swapped));
+        let applied = snapshot(&data);
+        assert_eq!(data.get(1, 1, 1), Color::new(10, 20, 30));
+        assert_eq!(data.get_index(1, 1, 1), 2);
+
+        assert!(history.undo(&mut data));
+        assert_eq!(snapshot(&data), original);
+        assert_eq!(data.get(1, 1, 1), Color::new(10, 20, 30));
+        assert!(history.redo(&mut data));
+        assert_eq!(snapshot(&data), applied);
+
+        let gray = Palette::from_colors(vec![Color::new(30, 30, 30)]);
+        history.edit(&mut data, |data| data.quantize_to(gray, Dither::Ordered));
+        let quantized = snapshot(&data);
+        assert!(history.undo(&mut data));
+        assert_eq!(snapshot(&data), applied);
+        assert!(history.redo(&mut data));
+        assert_eq!(snapshot(&data), quantized);
+    }
+
+    #[test]
+    fn new_edits_clear_redo() {
+        let mut data = sample();
+        let mut history = History::new();
+
+        history.set(&mut data, 0, 0, 0, Color::new(1, 2, 3));
+        history.undo(&mut data);
+        assert!(history.can_redo());
+        history.set(&mut data, 0, 1, 0, Color::new(1, 2, 3));
+        assert!(!history.can_redo());
+    }
+
+    #[test]
+    fn memory_cap_drops_oldest() {
+        let mut data = sample();
+        let step = std::mem::size_of::<Change>();
+        let mut history = History::with_max_bytes(step * 3);
+
+        for x in 0..4 {
+            history.set(&mut data, x, 0, 0, Color::new(5, 5, 5));
+        }
+        assert!(history.bytes() <= step * 3);
+
+        let mut undone = 0;
+        while history.undo(&mut data) {
+            undone += 1;
+        }
+        assert_eq!(undone, 3);
+        // the first edit fell out of the history
+        assert_eq!(data.get(0, 0, 0), Color::new(5, 5, 5));
+        assert_eq!(data.get(1, 0, 0), Color::CLEAR);
+    }
+}
@@ -0,0 +1,311 @@
+use super::{Region, VoxelData};
+use crate::pipeline::gbuffer::Vertex;
+use ultraviolet::*;
+
+/// Settings used when turning voxels into triangles
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MeshOptions {
+    /// Bake ambient occlusion into the corners of every face
+    pub ao: bool,
+    /// How dark a fully occluded corner gets, from 0 to 1
+    pub ao_strength: f32,
+    /// Number of brightness steps occlusion is snapped to when shading, 0 leaves it smooth
+    pub ao_levels: u32,
+}
+
+impl Default for MeshOptions {
+    fn default() -> Self {
+        Self {
+            ao: true,
+            ao_strength: 0.6,
+            ao_levels: 0,
+        }
+    }
+}
+
+/// Says whether a position outside of a model is solid
+pub(crate) type Outside<'a> = &'a dyn Fn([i32; 3]) -> bool;
+
+/// Sky and block light of an open position in or around a model, from 0 to 1
+pub(crate) type Light<'a> = &'a dyn Fn([i32; 3]) -> Vec2;
+
+/// Light of models that aren't part of a `VoxelWorld`, open sky and no block light
+pub(crate) fn open_sky(_: [i32; 3]) -> Vec2 {
+    Vec2::new(1.0, 0.0)
+}
+
+// one side of a voxel
+struct Face {
+    pos: [i32; 3],
+    axis: usize,
+    sign: i32,
+    uv: Vec3,
+}
+
+impl VoxelData {
+    fn is_solid(&self, pos: [i32; 3], outside: Outside) -> bool {
+        let [x, y, z] = [pos[0] as u32, pos[1] as u32, pos[2] as u32];
+        if pos.iter().all(|&p| p >= 0) && self.in_bounds(x, y, z) {
+            self.get(x, y, z).visible
+        } else {
+            outside(pos)
+        }
+    }
+
+    // classic voxel AO, 0 is fully occluded and 3 is open
+    fn corner_occlusion(&self, sides: [[i32; 3]; 3], outside: Outside) -> u32 {
+        let side1 = self.is_solid(sides[0], outside);
+        let side2 = self.is_solid(sides[1], outside);
+        if side1 && side2 {
+            0
+        } else {
+            3 - (side1 as u32 + side2 as u32 + self.is_solid(sides[2], outside) as u32)
+        }
+    }
+
+    /// Meshes the visible faces of every voxel in a region.
+    ///
+    /// `origin` is added to every vertex position and `outside` says whether positions beyond
+    /// the edges of the model are solid, which lets neighboring chunks hide each others faces.
+    /// Every corner gets the average `light` of the open voxels around it in front of the face.
+    pub(crate) fn mesh(
+        &self,
+        region: Region,
+        options: &MeshOptions,
+        origin: [i32; 3],
+        outside: Outside,
+        light: Light,
+    ) -> (Vec<Vertex>, Vec<u16>) {
+        let mut mesh = (vec![], vec![]);
+        for x in region.min[0]..region.max[0] {
+            for y in region.min[1]..region.max[1] {
+                for z in region.min[2]..region.max[2] {
+                    if !self.get(x, y, z).visible {
+                        continue;
+                    }
+                    // sample the center of this voxel's texel
+                    let uv = Vec3::new(
+                        (z as f32 + 0.5) / self.depth as f32,
+                        (y as f32 + 0.5) / self.height as f32,
+                        (x as f32 + 0.5) / self.width as f32,
+                    );
+                    let pos = [x as i32, y as i32, z as i32];
+                    for axis in 0..3 {
+                        for &sign in &[1, -1] {
+                            let face = Face {
+                                pos,
+                                axis,
+                                sign,
+                                uv,
+                            };
+                            self.mesh_face(face, options, origin, outside, light, &mut mesh);
+                        }
+                    }
+                }
+            }
+        }
+        mesh
+    }
+
+    fn mesh_face(
+        &self,
+        face: Face,
+        options: &MeshOptions,
+        origin: [i32; 3],
+        outside: Outside,
+        light: Light,
+        (verts, indices): &mut (Vec<Vertex>, Vec<u16>),
+    ) {
+        let Face {
+            pos,
+            axis,
+            sign,
+            uv,
+        } = face;
+        let mut normal = [0; 3];
+        normal[axis] = sign;
+        let front = [pos[0] + normal[0], pos[1] + normal[1], pos[2] + normal[2]];
+        if self.is_solid(front, outside) {
+            return;
+        }
+
+        // walking the corners in this order is counter-clockwise seen from the positive side
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let mut corners = [[0, 0], [1, 0], [1, 1], [0, 1]];
+        if sign < 0 {
+            corners.reverse();
+        }
+
+        let mut center = [
+            (pos[0] + origin[0]) as f32 + 0.5,
+            (pos[1] + origin[1]) as f32 + 0.5,
+            (pos[2] + origin[2]) as f32 + 0.5,
+        ];
+        center[axis] += sign as f32 * 0.5;
+        let center = Vec3::new(center[0], center[1], center[2]);
+
+        let ind_offset = verts.len();
+        let mut ao = [3; 4];
+        for (i, &[cu, cv]) in corners.iter().enumerate() {
+            let mut corner = [
+                (pos[0] + origin[0]) as f32,
+                (pos[1] + origin[1]) as f32,
+                (pos[2] + origin[2]) as f32,
+            ];
+            corner[axis] += if sign > 0 { 1.0 } else { 0.0 };
+            corner[u] += cu as f32;
+            corner[v] += cv as f32;
+
+            let mut side1 = front;
+            side1[u] += if cu == 1 { 1 } else { -1 };
+            let mut side2 = front;
+            side2[v] += if cv == 1 { 1 } else { -1 };
+            let mut diagonal = side1;
+            diagonal[v] = side2[v];
+            if options.ao {
+                ao[i] = self.corner_occlusion([side1, side2, diagonal], outside);
+            }
+
+            // light can't get around the corner when both sides are blocked
+            let open1 = !self.is_solid(side1, outside);
+            let open2 = !self.is_solid(side2, outside);
+            let mut sum = light(front);
+            let mut count = 1.0;
+            for &(cell, open) in &[
+                (side1, open1),
+                (side2, open2),
+                (
+                    diagonal,
+                    (open1 || open2) && !self.is_solid(diagonal, outside),
+                ),
+            ] {
+                if open {
+                    sum += light(cell);
+                    count += 1.0;
+                }
+            }
+
+            let occlusion = 1.0 - options.ao_strength * (3 - ao[i]) as f32 / 3.0;
+            verts.push(Vertex {
+                pos: Vec3::new(corner[0], corner[1], corner[2]),
+                uv,
+                ao: Vec2::new(occlusion, options.ao_levels as f32),
+                face: center,
+                normal: Vec3::new(normal[0] as f32, normal[1] as f32, normal[2] as f32),
+                light: sum / count,
+            });
+        }
+
+        // split the quad along the brighter diagonal so occlusion interpolates evenly
+        #[rustfmt::skip]
+        let quad = if ao[0] + ao[2] < ao[1] + ao[3] {
+            [1, 2, 3,
+             1, 3, 0]
+        } else {
+            [0, 1, 2,
+             0, 2, 3]
+        };
+        indices.extend(quad.iter().map(|i| (i + ind_offset) as u16));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxel_data::shapes::Brush;
+    use crate::voxel_data::Color;
+
+    #[test]
+    fn hidden_faces_are_culled() {
+        let mut data = VoxelData::empty(2, 1, 1);
+        data.set(0, 0, 0, Color::new(1, 1, 1));
+        data.set(1, 0, 0, Color::new(1, 1, 1));
+        let (verts, indices) = data.mesh(
+            Region::new([0, 0, 0], [2, 1, 1]),
+            &Default::default(),
+            [0, 0, 0],
+            &|_| false,
+            &open_sky,
+        );
+        assert_eq!(verts.len(), 10 * 4);
+        assert_eq!(indices.len(), 10 * 6);
+
+        // solid surroundings hide the outer faces as well
+        let region = Region::new([0, 0, 0], [2, 1, 1]);
+        let (verts, _) = data.mesh(region, &Default::default(), [0, 0, 0], &|_| true, &open_sky);
+        assert!(verts.is_empty());
+    }
+
+    #[test]
+    fn corners_are_occluded() {
+        // a floor with a single voxel standing on it
+        let mut data = VoxelData::empty(3, 2, 3);
+        data.fill_box([0, 0, 0], [2, 0, 2], Brush::Fill(Color::new(1, 1, 1)));
+        data.set(1, 1, 1, Color::new(1, 1, 1));
+        let options = MeshOptions {
+            ao: true,
+            ao_strength: 1.0,
+            ao_levels: 0,
+        };
+        let region = Region::new([1, 0, 0], [2, 1, 1]);
+        let (verts, _) = data.mesh(region, &options, [0, 0, 0], &|_| false, &open_sky);
+        // top face of the floor voxel next to the pillar is darkened on the pillar side only
+        let top = verts
+            .chunks(4)
+            .find(|face| face.iter().all(|v| v.pos.y == 1.0))
+            .unwrap();
+        for vert in top {
+            let expected = if vert.pos.z == 1.0 { 2.0 / 3.0 } else { 1.0 };
+            assert!((vert.ao.x - expected).abs() < 1e-6);
+        }
+
+        let options = MeshOptions {
+            ao: false,
+            ..options
+        };
+        let (verts, _) = data.mesh(region, &options, [0, 0, 0], &|_| false, &open_sky);
+        assert!(verts.iter().all(|v| v.ao.x == 1.0));
+    }
+
+    #[test]
+    fn corners_average_the_light_in_front() {
+        // a floor with a wall along x = 0, lit only from beyond x = 1
+        let mut data = VoxelData::empty(3, 2, 1);
+        data.fill_box([0, 0, 0], [2, 0, 0], Brush::Fill(Color::new(1, 1, 1)));
+        data.set(0, 1, 0, Color::new(1, 1, 1));
+        let light = |pos: [i32; 3]| Vec2::new(0.0, if pos[0] > 1 { 1.0 } else { 0.0 });
+        let region = Region::new([1, 0, 0], [2, 1, 1]);
+        let (verts, _) = data.mesh(region, &Default::default(), [0, 0, 0], &|_| false, &light);
+        let top = verts
+            .chunks(4)
+            .find(|face| face.iter().all(|v| v.pos.y == 1.0))
+            .unwrap();
+        for vert in top {
+            // corners next to the wall only see the dark voxel in front, the far ones share
+            // in the light next door
+            let expected = if vert.pos.x == 1.0 { 0.0 } else { 0.5 };
+            assert_eq!(vert.light.y, expected);
+        }
+    }
+
+    #[test]
+    fn faces_are_lit_at_their_center() {
+        let mut data = VoxelData::empty(1, 1, 1);
+        data.set(0, 0, 0, Color::new(1, 1, 1));
+        let region = Region::new([0, 0, 0], [1, 1, 1]);
+        let (verts, _) = data.mesh(
+            region,
+            &Default::default(),
+            [10, 0, 0],
+            &|_| false,
+            &open_sky,
+        );
+        for face in verts.chunks(4) {
+            let corners = face.iter().fold(Vec3::zero(), |sum, v| sum + v.pos) / 4.0;
+            for vert in face {
+                assert_eq!(vert.face, corners);
+                assert_eq!(vert.face - vert.normal * 0.5, Vec3::new(10.5, 0.5, 0.5));
+            }
+        }
+    }
+}
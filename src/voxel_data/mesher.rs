@@ -0,0 +1,361 @@
+use super::mesh::{open_sky, Light, Outside};
+use super::{MeshOptions, VoxelBuffer, VoxelData};
+use crate::pipeline::gbuffer::Vertex;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use ultraviolet::Vec2;
+
+/// A model meshed on a worker thread, ready to be uploaded
+pub struct MeshedData {
+    data: VoxelData,
+    options: MeshOptions,
+    origin: [i32; 3],
+    meshes: Vec<(Vec<Vertex>, Vec<u16>)>,
+}
+
+impl MeshedData {
+    pub fn data(&self) -> &VoxelData {
+        &self.data
+    }
+
+    pub fn vert_count(&self) -> usize {
+        self.meshes.iter().map(|(verts, _)| verts.len()).sum()
+    }
+
+    /// Creates the gpu buffers and textures, this is the only part done on the calling thread
+    pub fn upload(self, ctx: &crate::Context) -> VoxelBuffer {
+        VoxelBuffer::from_meshes(self.data, self.options, self.origin, &self.meshes, ctx)
+    }
+
+    pub(crate) fn meshes(&self) -> &[(Vec<Vertex>, Vec<u16>)] {
+        &self.meshes
+    }
+}
+
+/// Everything the mesh of a model depends on past its own voxels, copied out of a
+/// `VoxelWorld` so a worker can mesh one of its chunks: which voxels around it are solid and
+/// the light in and around it
+pub(crate) struct Surroundings {
+    dims: [u32; 3],
+    // one voxel wider than the model on every side, indexed like `VoxelData`
+    solid: Vec<bool>,
+    light: Vec<Vec2>,
+}
+
+impl Surroundings {
+    /// Copies what `outside` and `light` say about every position the mesher can look at
+    pub fn capture(dims: [u32; 3], outside: Outside, light: Light) -> Self {
+        let [w, h, d] = dims;
+        let len = ((w + 2) * (h + 2) * (d + 2)) as usize;
+        let mut surroundings = Self {
+            dims,
+            solid: Vec::with_capacity(len),
+            light: Vec::with_capacity(len),
+        };
+        for x in -1..=w as i32 {
+            for y in -1..=h as i32 {
+                for z in -1..=d as i32 {
+                    let pos = [x, y, z];
+                    // the mesher only asks about the voxels past the model's edges
+                    let inside = (0..3).all(|i| pos[i] >= 0 && pos[i] < dims[i] as i32);
+                    surroundings.solid.push(!inside && outside(pos));
+                    surroundings.light.push(light(pos));
+                }
+            }
+        }
+        surroundings
+    }
+
+    fn index(&self, pos: [i32; 3]) -> Option<usize> {
+        let [w, h, d] = self.dims;
+        let size = [w as i32 + 2, h as i32 + 2, d as i32 + 2];
+        let mut local = [0; 3];
+        for i in 0..3 {
+            local[i] = pos[i] + 1;
+            if local[i] < 0 || local[i] >= size[i] {
+                return None;
+            }
+        }
+        Some((local[0] * size[1] * size[2] + local[1] * size[2] + local[2]) as usize)
+    }
+
+    fn outside(&self, pos: [i32; 3]) -> bool {
+        self.index(pos).is_some_and(|i| self.solid[i])
+    }
+
+    fn light(&self, pos: [i32; 3]) -> Vec2 {
+        self.index(pos)
+            .map_or_else(|| open_sky(pos), |i| self.light[i])
+    }
+}
+
+struct Job<K> {
+    key: K,
+    version: u64,
+    data: VoxelData,
+    options: MeshOptions,
+    origin: [i32; 3],
+    // `None` for standalone models, with nothing solid past their edges
+    surroundings: Option<Surroundings>,
+}
+
+// the newest version queued for every key still waiting on a result
+type Versions<K> = Arc<Mutex<HashMap<K, u64>>>;
+
+/// Meshes models on a pool of worker threads so the render loop doesn't stall.
+///
+/// Jobs are queued under a key, such as a chunk coordinate or model id. Queuing a newer version
+/// of the same key cancels the older one, workers drop it between sub-chunks and `poll` never
+/// returns it. Models are meshed as standalone, with nothing solid past their edges, unless
+/// they are chunks of a `VoxelWorld`.
+pub struct Mesher<K> {
+    jobs: Option<Sender<Job<K>>>,
+    results: Receiver<(K, u64, MeshedData)>,
+    versions: Versions<K>,
+    next_version: u64,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<K: Hash + Eq + Clone + Send + 'static> Default for Mesher<K> {
+    fn default() -> Self {
+        let threads = thread::available_parallelism().map_or(2, |n| n.get());
+        // leave a core for the render loop
+        Self::new(threads.saturating_sub(1).max(1))
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send + 'static> Mesher<K> {
+    pub fn new(threads: usize) -> Self {
+        assert!(threads > 0, "a mesher needs at least one thread");
+        let (jobs, receiver) = channel::<Job<K>>();
+        let (sender, results) = channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let versions: Versions<K> = Arc::default();
+        let workers = (0..threads)
+            .map(|i| {
+                let receiver = Arc::clone(&receiver);
+                let sender = sender.clone();
+                let versions = Arc::clone(&versions);
+                thread::Builder::new()
+                    .name(format!("mesher {}", i))
+                    .spawn(move || work(receiver, sender, versions))
+                    .expect("failed to spawn mesher thread")
+            })
+            .collect();
+        Self {
+            jobs: Some(jobs),
+            results,
+            versions,
+            next_version: 0,
+            workers,
+        }
+    }
+
+    /// Meshes a model in the background, replacing any job still pending under the same key
+    pub fn queue(&mut self, key: K, data: VoxelData, options: MeshOptions) {
+        self.send(key, data, options, [0, 0, 0], None);
+    }
+
+    // like `queue` for a chunk at `origin` of a world, see `VoxelBuffer::build`
+    pub(crate) fn queue_chunk(
+        &mut self,
+        key: K,
+        data: VoxelData,
+        options: MeshOptions,
+        origin: [i32; 3],
+        surroundings: Surroundings,
+    ) {
+        self.send(key, data, options, origin, Some(surroundings));
+    }
+
+    fn send(
+        &mut self,
+        key: K,
+        data: VoxelData,
+        options: MeshOptions,
+        origin: [i32; 3],
+        surroundings: Option<Surroundings>,
+    ) {
+        self.next_version += 1;
+        let version = self.next_version;
+        self.versions.lock().unwrap().insert(key.clone(), version);
+        let job = Job {
+            key,
+            version,
+            data,
+            options,
+            origin,
+            surroundings,
+        };
+        // workers only stop once the sender is dropped
+        self.jobs.as_ref().unwrap().send(job).unwrap();
+    }
+
+    /// Drops the pending job for a key, if any
+    pub fn cancel(&mut self, key: &K) {
+        self.versions.lock().unwrap().remove(key);
+    }
+
+    /// Number of keys still waiting on a result
+    pub fn pending(&self) -> usize {
+        self.versions.lock().unwrap().len()
+    }
+
+    /// Whether a key is still waiting on a result
+    pub fn is_pending(&self, key: &K) -> bool {
+        self.versions.lock().unwrap().contains_key(key)
+    }
+
+    /// Results finished since the last call, without blocking. Upload them with
+    /// `MeshedData::upload`.
+    pub fn poll(&mut self) -> Vec<(K, MeshedData)> {
+        let mut versions = self.versions.lock().unwrap();
+        self.results
+            .try_iter()
+            .filter(|(key, version, _)| {
+                let current = versions.get(key) == Some(version);
+                if current {
+                    versions.remove(key);
+                }
+                current
+            })
+            .map(|(key, _, meshed)| (key, meshed))
+            .collect()
+    }
+}
+
+impl<K> Drop for Mesher<K> {
+    fn drop(&mut self) {
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn work<K: Hash + Eq>(
+    jobs: Arc<Mutex<Receiver<Job<K>>>>,
+    results: Sender<(K, u64, MeshedData)>,
+    versions: Versions<K>,
+) {
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        let cancelled = || versions.lock().unwrap().get(&job.key) != Some(&job.version);
+        let meshes = match &job.surroundings {
+            Some(around) => job.data.mesh_subchunks(
+                &job.options,
+                job.origin,
+                &|pos| around.outside(pos),
+                &|pos| around.light(pos),
+                &cancelled,
+            ),
+            None => {
+                job.data
+                    .mesh_subchunks(&job.options, job.origin, &|_| false, &open_sky, &cancelled)
+            }
+        };
+        let meshes = match meshes {
+            Some(meshes) => meshes,
+            None => continue,
+        };
+        let meshed = MeshedData {
+            data: job.data,
+            options: job.options,
+            origin: job.origin,
+            meshes,
+        };
+        if results.send((job.key, job.version, meshed)).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxel_data::Color;
+    use std::time::{Duration, Instant};
+
+    fn finish(mesher: &mut Mesher<u32>) -> Vec<(u32, MeshedData)> {
+        let start = Instant::now();
+        let mut done = vec![];
+        while mesher.pending() > 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "meshing timed out"
+            );
+            done.extend(mesher.poll());
+            thread::yield_now();
+        }
+        done
+    }
+
+    fn solid(size: u32) -> VoxelData {
+        let colors = vec![Color::new(200, 10, 10); (size * size * size) as usize];
+        VoxelData::new(colors, size, size, size)
+    }
+
+    #[test]
+    fn newer_versions_win() {
+        let mut mesher = Mesher::new(2);
+        mesher.queue(0, solid(40), MeshOptions::default());
+        mesher.queue(1, solid(2), MeshOptions::default());
+        mesher.queue(0, solid(3), MeshOptions::default());
+        let mut done = finish(&mut mesher);
+        done.sort_by_key(|(key, _)| *key);
+        assert_eq!(done.len(), 2);
+        assert_eq!(done[0].1.data().dims(), [3, 3, 3]);
+        assert_eq!(done[1].1.data().dims(), [2, 2, 2]);
+        // only the outside of a solid cube is meshed, 9 faces on each of its 6 sides
+        assert_eq!(done[0].1.vert_count(), 6 * 9 * 4);
+    }
+
+    #[test]
+    fn chunks_mesh_like_their_world() {
+        let data = solid(4);
+        let origin = [32, 0, -32];
+        // a wall of solid voxels against -x and light fading towards +y
+        let outside = |pos: [i32; 3]| pos[0] < 0;
+        let light = |pos: [i32; 3]| Vec2::new(1.0 - pos[1] as f32 / 8.0, 0.25);
+        let options = MeshOptions::default();
+        let expected = data
+            .mesh_subchunks(&options, origin, &outside, &light, &|| false)
+            .unwrap();
+
+        let mut mesher = Mesher::new(1);
+        let around = Surroundings::capture(data.dims(), &outside, &light);
+        mesher.queue_chunk(3, data, options, origin, around);
+        let done = finish(&mut mesher);
+        assert_eq!(done.len(), 1);
+        let bytes = |meshes: &[(Vec<Vertex>, Vec<u16>)]| -> Vec<(Vec<u8>, Vec<u16>)> {
+            meshes
+                .iter()
+                .map(|(verts, indices)| {
+                    (
+                        verts.iter().flat_map(|v| v.data()).collect(),
+                        indices.clone(),
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(bytes(done[0].1.meshes()), bytes(&expected));
+        // the face against the wall isn't meshed, 5 sides of 16 faces are
+        assert_eq!(done[0].1.vert_count(), 5 * 16 * 4);
+    }
+
+    #[test]
+    fn cancelled_jobs_never_arrive() {
+        let mut mesher = Mesher::new(1);
+        mesher.queue(7, solid(20), MeshOptions::default());
+        mesher.cancel(&7);
+        assert_eq!(mesher.pending(), 0);
+        thread::sleep(Duration::from_millis(50));
+        assert!(mesher.poll().is_empty());
+    }
+}
@@ -0,0 +1,211 @@
+use super::quantize::Matcher;
+use super::{Color, Emitters, Ramps};
+
+mod formats;
+
+pub use formats::PaletteError;
+
+/// Most colors a palette can hold, index 0 is always empty space
+pub const MAX_COLORS: usize = 255;
+
+/// The colors a model's voxels index into.
+///
+/// Index 0 is reserved for empty space, so the first color has index 1. Swapping the palette of
+/// a model recolors it without touching its voxels.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Palette {
+    colors: Vec<Color>,
+}
+
+impl Palette {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds a palette from the unique visible colors in order, anything past `MAX_COLORS`
+    /// is dropped
+    pub fn from_colors(colors: impl IntoIterator<Item = Color>) -> Self {
+        let mut palette = Self::new();
+        for color in colors {
+            palette.insert(color);
+        }
+        palette
+    }
+
+    // entries exactly as given, repeated and invisible colors included, anything past
+    // `MAX_COLORS` is dropped
+    pub(crate) fn from_entries(mut colors: Vec<Color>) -> Self {
+        colors.truncate(MAX_COLORS);
+        Self { colors }
+    }
+
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.colors.is_empty()
+    }
+
+    /// The colors starting at index 1
+    pub fn colors(&self) -> &[Color] {
+        &self.colors
+    }
+
+    /// Returns `Color::CLEAR` for index 0 and indices past the end of the palette
+    pub fn get(&self, index: u8) -> Color {
+        match index {
+            0 => Color::CLEAR,
+            i => self
+                .colors
+                .get(i as usize - 1)
+                .copied()
+                .unwrap_or(Color::CLEAR),
+        }
+    }
+
+    /// Replaces the color at an existing index, every voxel using it changes color
+    pub fn set(&mut self, index: u8, color: Color) {
+        assert!(
+            index > 0 && (index as usize) <= self.colors.len(),
+            "palette index {} out of range",
+            index
+        );
+        self.colors[index as usize - 1] = color;
+    }
+
+    pub fn find(&self, color: Color) -> Option<u8> {
+        if !color.is_visible() {
+            return Some(0);
+        }
+        self.colors
+            .iter()
+            .position(|&c| c == color)
+            .map(|i| (i + 1) as u8)
+    }
+
+    /// Returns the index of a color, adding it if it's new. `None` if the palette is full.
+    pub fn insert(&mut self, color: Color) -> Option<u8> {
+        if let Some(index) = self.find(color) {
+            return Some(index);
+        }
+        if self.colors.len() == MAX_COLORS {
+            return None;
+        }
+        self.colors.push(color);
+        Some(self.colors.len() as u8)
+    }
+
+    /// Index of the closest color as seen by the eye, 0 for an empty palette
+    pub fn nearest(&self, color: Color) -> u8 {
+        Matcher::new(self).nearest(color)
+    }
+
+    // index to store for a color, full palettes fall back to the closest color they have
+    pub(crate) fn index_of(&mut self, color: Color) -> u8 {
+        self.insert(color).unwrap_or_else(|| self.nearest(color))
+    }
+
+    /// Rgba texels for all 256 indices, the layout of the palette texture
+    pub fn texels(&self) -> Vec<u8> {
+        (0..=255).flat_map(|i| self.get(i).rgba()).collect()
+    }
+
+    /// The ramp id of all 256 indices, the layout of a row of the ramp id texture
+    pub fn ramp_ids(&self, ramps: &Ramps) -> Vec<u8> {
+        (0..=255).map(|i| ramps.id(self.get(i))).collect()
+    }
+
+    /// Like `texels` but glowing colors store `1 / (1 + strength)` in alpha, so colors that
+    /// don't glow stay opaque and only empty space is 0
+    pub fn texels_with(&self, emitters: &Emitters) -> Vec<u8> {
+        (0..=255)
+            .flat_map(|i| {
+                let color = self.get(i);
+                let mut rgba = color.rgba();
+                let strength = emitters.get(color);
+                if strength > 0.0 {
+                    rgba[3] = (255.0 / (1.0 + strength)).round().max(1.0) as u8;
+                }
+                rgba
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxel_data::VoxelData;
+
+    #[test]
+    fn indices() {
+        let red = Color::new(255, 0, 0);
+        let blue = Color::new(0, 0, 255);
+        let mut palette = Palette::from_colors(vec![red, Color::CLEAR, blue, red]);
+        assert_eq!(palette.len(), 2);
+        assert_eq!(palette.find(Color::CLEAR), Some(0));
+        assert_eq!(palette.find(blue), Some(2));
+        assert_eq!(palette.get(0), Color::CLEAR);
+        assert_eq!(palette.get(1), red);
+        assert_eq!(palette.get(3), Color::CLEAR);
+
+        for i in 0..MAX_COLORS - 2 {
+            palette.insert(Color::new(0, i as u8, 0));
+        }
+        assert_eq!(palette.insert(Color::new(250, 10, 10)), None);
+        assert_eq!(palette.index_of(Color::new(250, 10, 10)), 1);
+        assert_eq!(palette.texels().len(), 256 * 4);
+    }
+
+    #[test]
+    fn glowing_colors_keep_strength_in_alpha() {
+        let lamp = Color::new(255, 200, 0);
+        let stone = Color::new(90, 90, 90);
+        let palette = Palette::from_colors(vec![stone, lamp]);
+        let mut emitters = Emitters::new();
+        emitters.set(lamp, 3.0);
+        let texels = palette.texels_with(&emitters);
+        assert_eq!(&texels[..4], &[0, 0, 0, 0]);
+        assert_eq!(&texels[4..8], &[90, 90, 90, 255]);
+        assert_eq!(&texels[8..12], &[255, 200, 0, 64]);
+        // the strength the shaders read back
+        assert!((255.0 / texels[11] as f32 - 1.0 - 3.0).abs() < 0.02);
+        assert_eq!(palette.texels_with(&Emitters::new()), palette.texels());
+    }
+
+    #[test]
+    fn swapping_recolors() {
+        let red = Color::new(255, 0, 0);
+        let mut data = VoxelData::new(vec![red, Color::CLEAR, red], 3, 1, 1);
+        assert_eq!(data.palette().colors(), &[red]);
+        assert_eq!(data.get_index(2, 0, 0), 1);
+        data.take_dirty();
+        data.take_palette_dirty();
+
+        let green = Color::new(0, 255, 0);
+        let old = data.set_palette(Palette::from_colors(vec![green]));
+        assert_eq!(old.get(1), red);
+        assert!(data.is_dirty());
+        assert_eq!(data.take_dirty(), None);
+        assert_eq!(data.get(0, 0, 0), green);
+        assert_eq!(data.get(1, 0, 0), Color::CLEAR);
+    }
+
+    #[test]
+    fn applying_picks_nearest() {
+        let mut data = VoxelData::empty(2, 1, 1);
+        data.set(0, 0, 0, Color::new(250, 10, 10));
+        data.set(1, 0, 0, Color::new(10, 10, 240));
+        let shared = Palette::from_colors(vec![
+            Color::new(0, 0, 255),
+            Color::new(0, 255, 0),
+            Color::new(255, 0, 0),
+        ]);
+        data.apply_palette(shared.clone());
+        assert_eq!(data.palette(), &shared);
+        assert_eq!(data.get(0, 0, 0), Color::new(255, 0, 0));
+        assert_eq!(data.get(1, 0, 0), Color::new(0, 0, 255));
+    }
+}
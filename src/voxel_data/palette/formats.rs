@@ -0,0 +1,303 @@
+use super::Palette;
+use crate::voxel_data::Color;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// Why a palette file couldn't be read or written
+#[derive(Debug)]
+pub enum PaletteError {
+    Io(io::Error),
+    Png(png::DecodingError),
+    /// A line of a text palette that doesn't hold a color, lines are counted from 1
+    Parse {
+        line: usize,
+        message: String,
+    },
+    /// The file extension isn't one of `gpl`, `hex`, `pal` or `png`
+    UnknownFormat(String),
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaletteError::Io(err) => write!(f, "{}", err),
+            PaletteError::Png(err) => write!(f, "invalid png: {}", err),
+            PaletteError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            PaletteError::UnknownFormat(ext) => write!(f, "unknown palette format '{}'", ext),
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+impl From<io::Error> for PaletteError {
+    fn from(err: io::Error) -> Self {
+        PaletteError::Io(err)
+    }
+}
+
+impl From<png::DecodingError> for PaletteError {
+    fn from(err: png::DecodingError) -> Self {
+        PaletteError::Png(err)
+    }
+}
+
+impl From<png::EncodingError> for PaletteError {
+    fn from(err: png::EncodingError) -> Self {
+        match err {
+            png::EncodingError::IoError(err) => PaletteError::Io(err),
+            err => PaletteError::Io(io::Error::other(err.to_string())),
+        }
+    }
+}
+
+fn parse_error(line: usize, message: impl Into<String>) -> PaletteError {
+    PaletteError::Parse {
+        line: line + 1,
+        message: message.into(),
+    }
+}
+
+// "R G B" in decimal, anything after the third number is ignored
+fn parse_rgb(line: usize, text: &str) -> Result<Color, PaletteError> {
+    let mut rgb = [0; 3];
+    let mut parts = text.split_whitespace();
+    for channel in &mut rgb {
+        let part = parts
+            .next()
+            .ok_or_else(|| parse_error(line, "expected three color channels"))?;
+        *channel = part
+            .parse()
+            .map_err(|_| parse_error(line, format!("'{}' is not a channel value", part)))?;
+    }
+    Ok(Color::new(rgb[0], rgb[1], rgb[2]))
+}
+
+// Palettes keep the first `MAX_COLORS` unique colors of a file, empty space doesn't take a slot.
+impl Palette {
+    /// Loads a palette picking the format from the file extension
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, PaletteError> {
+        let path = path.as_ref();
+        let ext = extension(path);
+        if ext == "png" {
+            return Self::from_png(&std::fs::read(path)?);
+        }
+        let text = std::fs::read_to_string(path)?;
+        match ext.as_str() {
+            "gpl" => Self::from_gpl(&text),
+            "hex" => Self::from_hex(&text),
+            "pal" => Self::from_pal(&text),
+            _ => Err(PaletteError::UnknownFormat(ext)),
+        }
+    }
+
+    /// Saves a palette picking the format from the file extension
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), PaletteError> {
+        let path = path.as_ref();
+        let name = path
+            .file_stem()
+            .map_or_else(String::new, |s| s.to_string_lossy().into_owned());
+        let data = match extension(path).as_str() {
+            "gpl" => self.to_gpl(&name).into_bytes(),
+            "hex" => self.to_hex().into_bytes(),
+            "pal" => self.to_pal().into_bytes(),
+            "png" => self.to_png()?,
+            ext => return Err(PaletteError::UnknownFormat(ext.to_string())),
+        };
+        std::fs::write(path, data)?;
+        Ok(())
+    }
+
+    /// Reads a GIMP palette, color names are dropped
+    pub fn from_gpl(text: &str) -> Result<Self, PaletteError> {
+        let mut lines = text.lines().enumerate();
+        match lines.next() {
+            Some((_, header)) if header.trim() == "GIMP Palette" => {}
+            _ => return Err(parse_error(0, "missing 'GIMP Palette' header")),
+        }
+        let mut colors = vec![];
+        for (i, line) in lines {
+            let line = line.trim();
+            if line.is_empty()
+                || line.starts_with('#')
+                || line.starts_with("Name:")
+                || line.starts_with("Columns:")
+            {
+                continue;
+            }
+            colors.push(parse_rgb(i, line)?);
+        }
+        Ok(Self::from_colors(colors))
+    }
+
+    pub fn to_gpl(&self, name: &str) -> String {
+        let mut text = format!("GIMP Palette\nName: {}\nColumns: 8\n#\n", name);
+        for color in self.colors() {
+            let [r, g, b] = color.rgb();
+            text += &format!("{:3} {:3} {:3}\t#{:02x}{:02x}{:02x}\n", r, g, b, r, g, b);
+        }
+        text
+    }
+
+    /// Reads one `rrggbb` color per line, with or without a leading `#`
+    pub fn from_hex(text: &str) -> Result<Self, PaletteError> {
+        let mut colors = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let hex = line.trim().trim_start_matches('#');
+            if hex.is_empty() {
+                continue;
+            }
+            let value = match hex.len() {
+                6 => u32::from_str_radix(hex, 16).ok(),
+                _ => None,
+            }
+            .ok_or_else(|| parse_error(i, format!("'{}' is not a hex color", line.trim())))?;
+            colors.push(Color::new(
+                (value >> 16) as u8,
+                (value >> 8) as u8,
+                value as u8,
+            ));
+        }
+        Ok(Self::from_colors(colors))
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.colors()
+            .iter()
+            .map(|c| {
+                let [r, g, b] = c.rgb();
+                format!("{:02x}{:02x}{:02x}\n", r, g, b)
+            })
+            .collect()
+    }
+
+    /// Reads a JASC (Paint Shop Pro) palette
+    pub fn from_pal(text: &str) -> Result<Self, PaletteError> {
+        let mut lines = text.lines().map(str::trim).enumerate();
+        match lines.next() {
+            Some((_, "JASC-PAL")) => {}
+            _ => return Err(parse_error(0, "missing 'JASC-PAL' header")),
+        }
+        lines.next();
+        let count = match lines.next() {
+            Some((i, line)) => line
+                .parse::<usize>()
+                .map_err(|_| parse_error(i, "expected the number of colors"))?,
+            None => return Err(parse_error(2, "expected the number of colors")),
+        };
+        let mut colors = vec![];
+        for (i, line) in lines.filter(|(_, line)| !line.is_empty()).take(count) {
+            colors.push(parse_rgb(i, line)?);
+        }
+        if colors.len() < count {
+            return Err(parse_error(
+                3 + colors.len(),
+                format!("expected {} colors, found {}", count, colors.len()),
+            ));
+        }
+        Ok(Self::from_colors(colors))
+    }
+
+    pub fn to_pal(&self) -> String {
+        let mut text = format!("JASC-PAL\r\n0100\r\n{}\r\n", self.len());
+        for color in self.colors() {
+            let [r, g, b] = color.rgb();
+            text += &format!("{} {} {}\r\n", r, g, b);
+        }
+        text
+    }
+
+    /// Reads the colors of a swatch image such as the ones on Lospec, left to right and top to
+    /// bottom. Scaled up swatches work too and transparent pixels are skipped.
+    pub fn from_png(data: &[u8]) -> Result<Self, PaletteError> {
+        let mut decoder = png::Decoder::new(data);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let (info, mut reader) = decoder.read_info()?;
+        let mut pixels = vec![0; info.buffer_size()];
+        reader.next_frame(&mut pixels)?;
+
+        let (color_type, _) = reader.output_color_type();
+        let colors = match color_type {
+            png::ColorType::RGB => pixels
+                .chunks(3)
+                .map(|p| Color::new(p[0], p[1], p[2]))
+                .collect::<Vec<_>>(),
+            png::ColorType::RGBA => pixels
+                .chunks(4)
+                .filter(|p| p[3] > 0)
+                .map(|p| Color::new(p[0], p[1], p[2]))
+                .collect(),
+            png::ColorType::Grayscale => pixels.iter().map(|&v| Color::new(v, v, v)).collect(),
+            png::ColorType::GrayscaleAlpha => pixels
+                .chunks(2)
+                .filter(|p| p[1] > 0)
+                .map(|p| Color::new(p[0], p[0], p[0]))
+                .collect(),
+            // expanded away by the decoder
+            png::ColorType::Indexed => unreachable!(),
+        };
+        Ok(Self::from_colors(colors))
+    }
+
+    /// Writes the palette as a swatch one pixel high, an empty palette is a single black pixel
+    pub fn to_png(&self) -> Result<Vec<u8>, PaletteError> {
+        let mut data = vec![];
+        let mut encoder = png::Encoder::new(&mut data, self.len().max(1) as u32, 1);
+        encoder.set_color(png::ColorType::RGB);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut pixels: Vec<u8> = self.colors().iter().flat_map(|c| c.rgb()).collect();
+        pixels.resize(pixels.len().max(3), 0);
+        encoder.write_header()?.write_image_data(&pixels)?;
+        Ok(data)
+    }
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .map_or_else(String::new, |e| e.to_string_lossy().to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Palette {
+        Palette::from_colors(vec![
+            Color::new(26, 28, 44),
+            Color::new(93, 39, 93),
+            Color::new(255, 205, 117),
+        ])
+    }
+
+    #[test]
+    fn text_formats_round_trip() {
+        let palette = sample();
+        assert_eq!(Palette::from_gpl(&palette.to_gpl("test")).unwrap(), palette);
+        assert_eq!(Palette::from_hex(&palette.to_hex()).unwrap(), palette);
+        assert_eq!(Palette::from_pal(&palette.to_pal()).unwrap(), palette);
+        assert_eq!(
+            Palette::from_png(&palette.to_png().unwrap()).unwrap(),
+            palette
+        );
+
+        let gpl = "GIMP Palette\nName: Sweetie\n# comment\n 26  28  44\tblack\n93 39 93\n";
+        assert_eq!(Palette::from_gpl(gpl).unwrap().len(), 2);
+        let hex = "#1a1c2c\n5D275D\n\n";
+        assert_eq!(
+            Palette::from_hex(hex).unwrap().get(2),
+            Color::new(93, 39, 93)
+        );
+    }
+
+    #[test]
+    fn bad_input_is_an_error() {
+        match Palette::from_hex("1a1c2c\nnope\n") {
+            Err(PaletteError::Parse { line: 2, .. }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(Palette::from_gpl("26 28 44\n").is_err());
+        assert!(Palette::from_pal("JASC-PAL\n0100\n3\n1 2 3\n").is_err());
+        assert!(Palette::from_png(b"not a png").is_err());
+    }
+}
@@ -0,0 +1,402 @@
+use super::palette::MAX_COLORS;
+use super::{Color, Palette, VoxelData};
+use std::collections::HashMap;
+
+/// How the colors of a reduced palette are picked
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Quantizer {
+    /// Splits the colors into boxes along their widest axis until there are enough boxes, fast
+    /// and predictable
+    MedianCut,
+    /// Refines median cut by moving every color to the average of the voxels closest to it
+    KMeans { iterations: u32 },
+}
+
+/// How colors between two palette entries are drawn
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Dither {
+    /// Every voxel takes the closest color
+    None,
+    /// Voxels mix the two closest colors in a repeating 4×4×4 pattern, so gradients survive
+    /// small palettes
+    Ordered,
+}
+
+// linear srgb to Oklab, distances in it match how different colors look
+pub(crate) fn oklab(color: Color) -> [f32; 3] {
+    let linear = |c: u8| {
+        let c = c as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let [r, g, b] = color.rgb();
+    let (r, g, b) = (linear(r), linear(g), linear(b));
+    let l = (0.412_221_46 * r + 0.536_332_55 * g + 0.051_445_995 * b).cbrt();
+    let m = (0.211_903_5 * r + 0.680_699_5 * g + 0.107_396_96 * b).cbrt();
+    let s = (0.088_302_46 * r + 0.281_718_85 * g + 0.629_978_7 * b).cbrt();
+    [
+        0.210_454_26 * l + 0.793_617_8 * m - 0.004_072_047 * s,
+        1.977_998_5 * l - 2.428_592_2 * m + 0.450_593_7 * s,
+        0.025_904_037 * l + 0.782_771_77 * m - 0.808_675_77 * s,
+    ]
+}
+
+fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)
+}
+
+/// Finds the closest colors of a palette in Oklab
+pub(crate) struct Matcher {
+    labs: Vec<[f32; 3]>,
+}
+
+impl Matcher {
+    pub(crate) fn new(palette: &Palette) -> Self {
+        Self::from_colors(palette.colors())
+    }
+
+    fn from_colors(colors: &[Color]) -> Self {
+        Self {
+            labs: colors.iter().map(|&c| oklab(c)).collect(),
+        }
+    }
+
+    /// The closest and second closest palette index, 0 when there are none
+    fn nearest_two(&self, lab: [f32; 3]) -> ([u8; 2], [f32; 2]) {
+        let mut best = ([0, 0], [f32::MAX, f32::MAX]);
+        for (i, &other) in self.labs.iter().enumerate() {
+            let d = distance(lab, other);
+            if d < best.1[0] {
+                best = ([i as u8 + 1, best.0[0]], [d, best.1[0]]);
+            } else if d < best.1[1] {
+                best.0[1] = i as u8 + 1;
+                best.1[1] = d;
+            }
+        }
+        best
+    }
+
+    pub(crate) fn nearest(&self, color: Color) -> u8 {
+        self.nearest_two(oklab(color)).0[0]
+    }
+
+    fn dithered(&self, color: Color, pos: [u32; 3]) -> u8 {
+        let lab = oklab(color);
+        let ([first, second], _) = self.nearest_two(lab);
+        if second == 0 {
+            return first;
+        }
+        // how far along the line between the two closest colors this one sits
+        let a = self.labs[first as usize - 1];
+        let b = self.labs[second as usize - 1];
+        let ab = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+        let len = ab[0] * ab[0] + ab[1] * ab[1] + ab[2] * ab[2];
+        let t = ((lab[0] - a[0]) * ab[0] + (lab[1] - a[1]) * ab[1] + (lab[2] - a[2]) * ab[2])
+            / len.max(f32::EPSILON);
+        if t > bayer(pos) {
+            second
+        } else {
+            first
+        }
+    }
+}
+
+// thresholds of a 4×4×4 ordered dither matrix, spread evenly between 0 and 1
+fn bayer(pos: [u32; 3]) -> f32 {
+    // every pair of consecutive entries sits on opposite corners of the 2×2×2 cube
+    const CUBE: [u32; 8] = [0, 6, 4, 3, 2, 5, 7, 1];
+    let cell = |bit: u32| {
+        let [x, y, z] = [
+            (pos[0] >> bit) & 1,
+            (pos[1] >> bit) & 1,
+            (pos[2] >> bit) & 1,
+        ];
+        CUBE[(x * 4 + y * 2 + z) as usize]
+    };
+    (8 * cell(0) + cell(1)) as f32 / 64.0 + 0.5 / 64.0
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    color: Color,
+    lab: [f32; 3],
+    count: u32,
+}
+
+fn average(entries: &[Entry]) -> Color {
+    let mut sum = [0u64; 3];
+    let mut total = 0u64;
+    for entry in entries {
+        for (s, c) in sum.iter_mut().zip(entry.color.rgb().iter()) {
+            *s += *c as u64 * entry.count as u64;
+        }
+        total += entry.count as u64;
+    }
+    let total = total.max(1);
+    Color::new(
+        ((sum[0] + total / 2) / total) as u8,
+        ((sum[1] + total / 2) / total) as u8,
+        ((sum[2] + total / 2) / total) as u8,
+    )
+}
+
+fn median_cut(mut entries: Vec<Entry>, count: usize) -> Vec<Color> {
+    let mut boxes: Vec<Vec<Entry>> = vec![];
+    if !entries.is_empty() {
+        // sort first so the result doesn't depend on hash map order
+        entries.sort_by_key(|e| e.color.rgb());
+        boxes.push(entries);
+    }
+    while boxes.len() < count {
+        // split the box with the widest spread of colors
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .map(|(i, b)| {
+                let (axis, range) = (0..3)
+                    .map(|axis| {
+                        let values = b.iter().map(|e| e.lab[axis]);
+                        let min = values.clone().fold(f32::MAX, f32::min);
+                        let max = values.fold(f32::MIN, f32::max);
+                        (axis, max - min)
+                    })
+                    .fold((0, -1.0), |a, b| if b.1 > a.1 { b } else { a });
+                (i, axis, range)
+            })
+            .fold(None, |best: Option<(usize, usize, f32)>, b| match best {
+                Some(best) if best.2 >= b.2 => Some(best),
+                _ => Some(b),
+            });
+        let (i, axis, _) = match widest {
+            Some(widest) => widest,
+            None => break,
+        };
+
+        let mut split = boxes.swap_remove(i);
+        split.sort_by(|a, b| a.lab[axis].partial_cmp(&b.lab[axis]).unwrap());
+        // cut where half of the voxels are on either side
+        let half = split.iter().map(|e| e.count as u64).sum::<u64>() / 2;
+        let mut seen = 0;
+        let mut at = 1;
+        for (j, entry) in split.iter().enumerate() {
+            seen += entry.count as u64;
+            if seen >= half {
+                at = (j + 1).clamp(1, split.len() - 1);
+                break;
+            }
+        }
+        let rest = split.split_off(at);
+        boxes.push(split);
+        boxes.push(rest);
+    }
+    boxes.iter().map(|b| average(b)).collect()
+}
+
+fn k_means(mut entries: Vec<Entry>, count: usize, iterations: u32) -> Vec<Color> {
+    entries.sort_by_key(|e| e.color.rgb());
+    let mut centers = median_cut(entries.clone(), count);
+    for _ in 0..iterations {
+        // centers can meet, a palette would merge them
+        let matcher = Matcher::from_colors(&centers);
+        let mut clusters: Vec<Vec<Entry>> = (0..centers.len()).map(|_| vec![]).collect();
+        for entry in &entries {
+            let index = matcher.nearest_two(entry.lab).0[0] as usize;
+            clusters[index - 1].push(*entry);
+        }
+        let moved: Vec<Color> = clusters
+            .iter()
+            .zip(&centers)
+            .map(|(cluster, &center)| {
+                if cluster.is_empty() {
+                    center
+                } else {
+                    average(cluster)
+                }
+            })
+            .collect();
+        if moved == centers {
+            break;
+        }
+        centers = moved;
+    }
+    centers
+}
+
+impl Palette {
+    /// Picks at most `count` colors that best stand in for a set of colors, weighted by how
+    /// often each one appears. Empty space is ignored.
+    pub fn quantized(
+        colors: impl IntoIterator<Item = Color>,
+        count: usize,
+        quantizer: Quantizer,
+    ) -> Self {
+        let mut counts: HashMap<Color, u32> = HashMap::new();
+        for color in colors.into_iter().filter(|c| c.is_visible()) {
+            *counts.entry(color).or_insert(0) += 1;
+        }
+        let entries: Vec<_> = counts
+            .into_iter()
+            .map(|(color, count)| Entry {
+                color,
+                lab: oklab(color),
+                count,
+            })
+            .collect();
+        let count = count.min(MAX_COLORS);
+        let colors = match quantizer {
+            Quantizer::MedianCut => median_cut(entries, count),
+            Quantizer::KMeans { iterations } => k_means(entries, count, iterations),
+        };
+        Self::from_colors(colors)
+    }
+}
+
+impl VoxelData {
+    /// Creates a model from colors in index order like `new`, reducing them to at most `count`
+    /// colors first. Use this for imports with more colors than a palette holds.
+    pub fn quantized(
+        colors: Vec<Color>,
+        width: u32,
+        height: u32,
+        depth: u32,
+        count: usize,
+        quantizer: Quantizer,
+        dither: Dither,
+    ) -> Self {
+        assert_eq!(colors.len(), (width * height * depth) as usize);
+        let palette = Palette::quantized(colors.iter().copied(), count, quantizer);
+        let mut data = Self::empty(width, height, depth);
+        data.palette = palette;
+        let matcher = Matcher::new(&data.palette);
+        let mut cache = HashMap::new();
+        for (index, &color) in colors.iter().enumerate() {
+            if color.is_visible() {
+                let pos = data.position(index);
+                let color = match dither {
+                    Dither::None => *cache.entry(color).or_insert_with(|| matcher.nearest(color)),
+                    Dither::Ordered => matcher.dithered(color, pos),
+                };
+                data.storage.set(index, color);
+            }
+        }
+        data
+    }
+
+    /// Reduces the model to at most `count` colors
+    pub fn quantize(&mut self, count: usize, quantizer: Quantizer, dither: Dither) {
+        let palette = Palette::quantized(self.visible_colors(), count, quantizer);
+        self.quantize_to(palette, dither);
+    }
+
+    /// Switches to another palette, every voxel takes the closest color it has in Oklab or a
+    /// dithered mix of the two closest
+    pub fn quantize_to(&mut self, palette: Palette, dither: Dither) {
+        let matcher = Matcher::new(&palette);
+        let remap: Vec<u8> = (0..=255)
+            .map(|i| match self.palette.get(i) {
+                color if color.is_visible() => matcher.nearest(color),
+                _ => 0,
+            })
+            .collect();
+        for x in 0..self.width {
+            for y in 0..self.height {
+                for z in 0..self.depth {
+                    let index = self.get_index(x, y, z);
+                    if index == 0 {
+                        continue;
+                    }
+                    let new = match dither {
+                        Dither::None => remap[index as usize],
+                        Dither::Ordered => matcher.dithered(self.palette.get(index), [x, y, z]),
+                    };
+                    self.set_index(x, y, z, new);
+                }
+            }
+        }
+        self.set_palette(palette);
+    }
+
+    fn visible_colors(&self) -> Vec<Color> {
+        let mut colors = vec![];
+        for x in 0..self.width {
+            for y in 0..self.height {
+                for z in 0..self.depth {
+                    let color = self.get(x, y, z);
+                    if color.is_visible() {
+                        colors.push(color);
+                    }
+                }
+            }
+        }
+        colors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a gradient with many more colors than fit in a palette
+    fn gradient() -> Vec<Color> {
+        (0..32 * 32 * 2)
+            .map(|i| {
+                Color::new(
+                    (i % 32 * 8) as u8,
+                    (i / 32 % 32 * 8) as u8,
+                    (i / 1024 * 200) as u8,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reduces_colors() {
+        let colors = gradient();
+        for &quantizer in &[Quantizer::MedianCut, Quantizer::KMeans { iterations: 4 }] {
+            let palette = Palette::quantized(colors.iter().copied(), 16, quantizer);
+            assert_eq!(palette.len(), 16);
+        }
+
+        // too many colors for a palette get quantized on import
+        let data = VoxelData::new(colors, 32, 32, 2);
+        assert_eq!(data.palette().len(), MAX_COLORS);
+
+        // fewer colors than asked for are kept as they are
+        let two = vec![Color::new(200, 0, 0), Color::CLEAR, Color::new(1, 2, 3)];
+        let palette = Palette::quantized(two, 16, Quantizer::MedianCut);
+        assert_eq!(
+            palette.colors(),
+            &[Color::new(1, 2, 3), Color::new(200, 0, 0)]
+        );
+    }
+
+    #[test]
+    fn perceptual_matching() {
+        // this gray is closer to black in rgb but looks closer to white
+        let palette = Palette::from_colors(vec![Color::new(0, 0, 0), Color::new(255, 255, 255)]);
+        assert_eq!(palette.nearest(Color::new(118, 118, 118)), 2);
+        assert_eq!(palette.nearest(Color::new(90, 90, 90)), 1);
+    }
+
+    #[test]
+    fn dithering_mixes_neighbors() {
+        let black = Color::new(0, 0, 0);
+        let white = Color::new(255, 255, 255);
+        let gray = Color::new(99, 99, 99);
+        let mut data = VoxelData::new(vec![gray; 64], 4, 4, 4);
+        let mut plain = data.clone();
+        let palette = Palette::from_colors(vec![black, white]);
+
+        plain.quantize_to(palette.clone(), Dither::None);
+        let colors = plain.colors();
+        assert!(colors.iter().all(|&c| c == colors[0]));
+
+        data.quantize_to(palette, Dither::Ordered);
+        let whites = data.colors().iter().filter(|&&c| c == white).count();
+        assert!(whites > 8 && whites < 56, "{} white voxels", whites);
+    }
+}
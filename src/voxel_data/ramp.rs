@@ -0,0 +1,158 @@
+use super::Color;
+
+/// Shades every ramp is stretched over on the gpu, from unlit to fully lit
+pub const RAMP_SHADES: usize = 16;
+
+/// Most colors a `Ramps` can give a ramp, ids have to fit in a byte next to 0 for no ramp
+pub const MAX_RAMPS: usize = 255;
+
+/// The shades a color steps through as the light on it goes from none to full, darkest first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ramp {
+    shades: Vec<Color>,
+}
+
+impl Ramp {
+    pub fn new(shades: impl IntoIterator<Item = Color>) -> Self {
+        let shades: Vec<_> = shades.into_iter().collect();
+        assert!(!shades.is_empty(), "a ramp needs at least one shade");
+        Self { shades }
+    }
+
+    /// `steps` shades evenly spaced from `dark` up to `lit`
+    pub fn between(dark: Color, lit: Color, steps: usize) -> Self {
+        let steps = steps.max(1);
+        let (dark, lit) = (dark.rgb(), lit.rgb());
+        Self::new((0..steps).map(|i| {
+            let t = if steps == 1 {
+                1.0
+            } else {
+                i as f32 / (steps - 1) as f32
+            };
+            let channel =
+                |c: usize| (dark[c] as f32 + (lit[c] as f32 - dark[c] as f32) * t).round();
+            Color::new(channel(0) as u8, channel(1) as u8, channel(2) as u8)
+        }))
+    }
+
+    pub fn shades(&self) -> &[Color] {
+        &self.shades
+    }
+
+    // the shades stretched over `RAMP_SHADES` rgba texels, each shade covering an even part of
+    // the light
+    fn texels(&self) -> Vec<u8> {
+        (0..RAMP_SHADES)
+            .flat_map(|i| self.shades[i * self.shades.len() / RAMP_SHADES].rgba())
+            .collect()
+    }
+}
+
+/// Which colors are shaded along a ramp instead of having their color scaled by the light
+/// reaching them, the light only tints the shade it picks.
+///
+/// Ramps are looked up by id in the lighting pass, so models and `Lights::ramps` have to be
+/// given the same registry, and given it again after it changes.
+#[derive(Debug, Clone, Default)]
+pub struct Ramps {
+    // in id order, starting at 1
+    ramps: Vec<(Color, Ramp)>,
+}
+
+impl Ramps {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Shades voxels of a color along `ramp`, `None` lights them normally again. Panics if more
+    /// than `MAX_RAMPS` colors would have a ramp.
+    pub fn set(&mut self, color: Color, ramp: Option<Ramp>) {
+        let existing = self.ramps.iter().position(|(c, _)| *c == color);
+        match (existing, ramp) {
+            (Some(i), Some(ramp)) => self.ramps[i].1 = ramp,
+            (Some(i), None) => {
+                self.ramps.remove(i);
+            }
+            (None, Some(ramp)) if color.is_visible() => {
+                assert!(self.ramps.len() < MAX_RAMPS, "too many ramps");
+                self.ramps.push((color, ramp));
+            }
+            (None, _) => {}
+        }
+    }
+
+    pub fn get(&self, color: Color) -> Option<&Ramp> {
+        self.ramps.iter().find(|(c, _)| *c == color).map(|(_, r)| r)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ramps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ramps.is_empty()
+    }
+
+    /// The id the lighting pass knows a color's ramp by, 0 for colors without one
+    pub fn id(&self, color: Color) -> u8 {
+        self.ramps
+            .iter()
+            .position(|(c, _)| *c == color)
+            .map_or(0, |i| i as u8 + 1)
+    }
+
+    /// Rows of `RAMP_SHADES` rgba texels, row `id - 1` holding the ramp with that id
+    pub fn texels(&self) -> Vec<u8> {
+        self.ramps.iter().flat_map(|(_, r)| r.texels()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ramps_stretch_over_the_shades() {
+        let ramp = Ramp::between(Color::new(0, 0, 0), Color::new(200, 100, 0), 3);
+        assert_eq!(
+            ramp.shades(),
+            &[
+                Color::new(0, 0, 0),
+                Color::new(100, 50, 0),
+                Color::new(200, 100, 0)
+            ]
+        );
+        let texels = ramp.texels();
+        assert_eq!(texels.len(), RAMP_SHADES * 4);
+        // the darkest shade covers the first third of the light and the lit one the last
+        assert_eq!(texels[..4], [0, 0, 0, 255]);
+        assert_eq!(texels[5 * 4..6 * 4], [0, 0, 0, 255]);
+        assert_eq!(texels[6 * 4..7 * 4], [100, 50, 0, 255]);
+        assert_eq!(texels[(RAMP_SHADES - 1) * 4..], [200, 100, 0, 255]);
+    }
+
+    #[test]
+    fn ids_follow_the_table_rows() {
+        let red = Color::new(255, 0, 0);
+        let blue = Color::new(0, 0, 255);
+        let mut ramps = Ramps::new();
+        ramps.set(red, Some(Ramp::new(vec![red])));
+        ramps.set(blue, Some(Ramp::new(vec![blue])));
+        ramps.set(Color::CLEAR, Some(Ramp::new(vec![red])));
+        assert_eq!(ramps.len(), 2);
+        assert_eq!(ramps.id(red), 1);
+        assert_eq!(ramps.id(blue), 2);
+        assert_eq!(ramps.id(Color::new(1, 2, 3)), 0);
+        assert_eq!(ramps.id(Color::CLEAR), 0);
+
+        let row = RAMP_SHADES * 4;
+        let texels = ramps.texels();
+        assert_eq!(texels.len(), 2 * row);
+        assert_eq!(texels[row..row + 4], blue.rgba());
+
+        // later ramps move up a row when one is taken out
+        ramps.set(red, None);
+        assert_eq!(ramps.id(blue), 1);
+        assert_eq!(ramps.texels()[..4], blue.rgba());
+    }
+}
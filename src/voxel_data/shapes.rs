@@ -0,0 +1,301 @@
+use super::{Axis, Color, VoxelData};
+use std::collections::VecDeque;
+
+/// What a shape does to the voxels it covers
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Brush {
+    /// Sets every voxel to a color
+    Fill(Color),
+    /// Removes every voxel
+    Clear,
+    /// Recolors voxels that already exist and leaves empty space alone
+    Paint(Color),
+}
+
+impl Brush {
+    fn apply(self, data: &mut VoxelData, pos: [i32; 3]) {
+        if pos.iter().any(|&p| p < 0) {
+            return;
+        }
+        let [x, y, z] = [pos[0] as u32, pos[1] as u32, pos[2] as u32];
+        match self {
+            Brush::Fill(color) => {
+                data.set(x, y, z, color);
+            }
+            Brush::Clear => {
+                data.set(x, y, z, Color::CLEAR);
+            }
+            Brush::Paint(color) => {
+                if data.get(x, y, z).is_visible() {
+                    data.set(x, y, z, color);
+                }
+            }
+        }
+    }
+}
+
+/// Which neighbors a flood fill spreads to
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Connectivity {
+    /// The 6 voxels sharing a face
+    Faces,
+    /// All 26 surrounding voxels
+    All,
+}
+
+// Shapes take signed coordinates so they can hang off the edge of the model, anything outside of
+// the model is clipped.
+impl VoxelData {
+    /// Applies a brush to every voxel in the box between two corners, both inclusive
+    pub fn fill_box(&mut self, a: [i32; 3], b: [i32; 3], brush: Brush) {
+        let dims = self.dims();
+        let mut min = [0; 3];
+        let mut max = [0; 3];
+        for i in 0..3 {
+            min[i] = a[i].min(b[i]).max(0);
+            max[i] = a[i].max(b[i]).min(dims[i] as i32 - 1);
+        }
+        for x in min[0]..=max[0] {
+            for y in min[1]..=max[1] {
+                for z in min[2]..=max[2] {
+                    brush.apply(self, [x, y, z]);
+                }
+            }
+        }
+    }
+
+    /// Applies a brush to every voxel within `radius` of `center`
+    pub fn fill_sphere(&mut self, center: [i32; 3], radius: f32, brush: Brush) {
+        let r = radius.ceil() as i32;
+        for x in -r..=r {
+            for y in -r..=r {
+                for z in -r..=r {
+                    if (x * x + y * y + z * z) as f32 <= radius * radius {
+                        brush.apply(self, [center[0] + x, center[1] + y, center[2] + z]);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Applies a brush to a cylinder running `length` voxels along `axis` from the center of
+    /// its base
+    pub fn fill_cylinder(
+        &mut self,
+        base: [i32; 3],
+        axis: Axis,
+        radius: f32,
+        length: u32,
+        brush: Brush,
+    ) {
+        let r = radius.ceil() as i32;
+        let along = axis.index();
+        let (u, v) = ((along + 1) % 3, (along + 2) % 3);
+        for h in 0..length as i32 {
+            for a in -r..=r {
+                for b in -r..=r {
+                    if (a * a + b * b) as f32 <= radius * radius {
+                        let mut pos = base;
+                        pos[along] += h;
+                        pos[u] += a;
+                        pos[v] += b;
+                        brush.apply(self, pos);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Applies a brush along a 3D Bresenham line between two voxels, both inclusive
+    pub fn line(&mut self, from: [i32; 3], to: [i32; 3], brush: Brush) {
+        let delta = [
+            (to[0] - from[0]).abs(),
+            (to[1] - from[1]).abs(),
+            (to[2] - from[2]).abs(),
+        ];
+        let step = [
+            (to[0] - from[0]).signum(),
+            (to[1] - from[1]).signum(),
+            (to[2] - from[2]).signum(),
+        ];
+        // walk along the axis with the biggest change and accumulate error on the other two
+        let major = (0..3).max_by_key(|&i| delta[i]).unwrap();
+        let (a, b) = ((major + 1) % 3, (major + 2) % 3);
+        let mut err_a = 2 * delta[a] - delta[major];
+        let mut err_b = 2 * delta[b] - delta[major];
+
+        let mut pos = from;
+        for _ in 0..=delta[major] {
+            brush.apply(self, pos);
+            if err_a > 0 {
+                pos[a] += step[a];
+                err_a -= 2 * delta[major];
+            }
+            if err_b > 0 {
+                pos[b] += step[b];
+                err_b -= 2 * delta[major];
+            }
+            err_a += 2 * delta[a];
+            err_b += 2 * delta[b];
+            pos[major] += step[major];
+        }
+    }
+
+    /// Recolors the region of same colored voxels connected to `start`, returns how many voxels
+    /// were changed
+    pub fn flood_fill(
+        &mut self,
+        start: [u32; 3],
+        color: Color,
+        connectivity: Connectivity,
+    ) -> usize {
+        let [x, y, z] = start;
+        if !self.in_bounds(x, y, z) {
+            return 0;
+        }
+        // compared by index, a full palette can turn `color` into the target's own index
+        let target = self.get_index(x, y, z);
+        let index = self.index_for(color);
+        if target == index {
+            return 0;
+        }
+
+        let mut count = 0;
+        let mut queue = VecDeque::new();
+        self.set_index(x, y, z, index);
+        queue.push_back(start);
+        while let Some([x, y, z]) = queue.pop_front() {
+            count += 1;
+            for dx in -1i32..=1 {
+                for dy in -1i32..=1 {
+                    for dz in -1i32..=1 {
+                        let offsets = dx.abs() + dy.abs() + dz.abs();
+                        if offsets == 0 || (connectivity == Connectivity::Faces && offsets > 1) {
+                            continue;
+                        }
+                        let (nx, ny, nz) = (x as i32 + dx, y as i32 + dy, z as i32 + dz);
+                        if nx < 0 || ny < 0 || nz < 0 {
+                            continue;
+                        }
+                        let (nx, ny, nz) = (nx as u32, ny as u32, nz as u32);
+                        if self.in_bounds(nx, ny, nz) && self.get_index(nx, ny, nz) == target {
+                            self.set_index(nx, ny, nz, index);
+                            queue.push_back([nx, ny, nz]);
+                        }
+                    }
+                }
+            }
+        }
+        count
+    }
+
+    /// Recolors every voxel of one color, returns how many voxels were changed
+    pub fn replace_color(&mut self, from: Color, to: Color) -> usize {
+        if from == to {
+            return 0;
+        }
+        let [width, height, depth] = self.dims();
+        let mut count = 0;
+        for x in 0..width {
+            for y in 0..height {
+                for z in 0..depth {
+                    if self.get(x, y, z) == from {
+                        self.set(x, y, z, to);
+                        count += 1;
+                    }
+                }
+            }
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(data: &VoxelData) -> usize {
+        data.colors().iter().filter(|c| c.is_visible()).count()
+    }
+
+    #[test]
+    fn boxes_are_clipped() {
+        let mut data = VoxelData::empty(4, 4, 4);
+        data.fill_box([-2, 1, 1], [1, 9, 2], Brush::Fill(Color::new(1, 2, 3)));
+        assert_eq!(count(&data), 2 * 3 * 2);
+        data.fill_box([0, 0, 0], [0, 3, 3], Brush::Clear);
+        assert_eq!(count(&data), 3 * 2);
+    }
+
+    #[test]
+    fn spheres_and_cylinders() {
+        let mut data = VoxelData::empty(9, 9, 9);
+        data.fill_sphere([4, 4, 4], 1.0, Brush::Fill(Color::new(1, 2, 3)));
+        assert_eq!(count(&data), 7);
+
+        let mut data = VoxelData::empty(9, 9, 9);
+        data.fill_cylinder([4, 0, 4], Axis::Y, 1.0, 9, Brush::Fill(Color::new(1, 2, 3)));
+        assert_eq!(count(&data), 5 * 9);
+        assert!(data.get(4, 8, 4).is_visible());
+        assert!(!data.get(5, 4, 5).is_visible());
+    }
+
+    #[test]
+    fn lines_hit_both_ends() {
+        let mut data = VoxelData::empty(8, 8, 8);
+        data.line([0, 0, 0], [7, 3, 5], Brush::Fill(Color::new(1, 2, 3)));
+        assert!(data.get(0, 0, 0).is_visible());
+        assert!(data.get(7, 3, 5).is_visible());
+        assert_eq!(count(&data), 8);
+    }
+
+    #[test]
+    fn flood_fill_connectivity() {
+        let red = Color::new(255, 0, 0);
+        let blue = Color::new(0, 0, 255);
+        let mut data = VoxelData::empty(3, 3, 3);
+        // two voxels touching only at an edge
+        data.set(0, 0, 0, red);
+        data.set(1, 1, 0, red);
+
+        let mut faces = data.clone();
+        assert_eq!(faces.flood_fill([0, 0, 0], blue, Connectivity::Faces), 1);
+        assert_eq!(data.flood_fill([0, 0, 0], blue, Connectivity::All), 2);
+        assert_eq!(data.get(1, 1, 0), blue);
+    }
+
+    #[test]
+    fn flood_fill_with_a_full_palette() {
+        let mut data = VoxelData::empty(4, 1, 1);
+        for i in 0..crate::voxel_data::palette::MAX_COLORS {
+            data.palette_mut().insert(Color::new(i as u8, 0, 0));
+        }
+        let dark = Color::new(10, 0, 0);
+        for x in 0..4 {
+            data.set(x, 0, 0, dark);
+        }
+        // no room for the new color, it falls back to the closest one the voxels already have
+        let close = Color::new(10, 0, 1);
+        assert_eq!(data.flood_fill([0, 0, 0], close, Connectivity::Faces), 0);
+        assert_eq!(data.get(3, 0, 0), dark);
+
+        // a color with its own index still fills the whole region
+        let bright = Color::new(200, 0, 0);
+        assert_eq!(data.flood_fill([0, 0, 0], bright, Connectivity::Faces), 4);
+        assert_eq!(data.get(3, 0, 0), bright);
+    }
+
+    #[test]
+    fn paint_only_recolors() {
+        let red = Color::new(255, 0, 0);
+        let blue = Color::new(0, 0, 255);
+        let mut data = VoxelData::empty(3, 3, 3);
+        data.set(1, 1, 1, red);
+        data.fill_box([0, 0, 0], [2, 2, 2], Brush::Paint(blue));
+        assert_eq!(count(&data), 1);
+        assert_eq!(data.get(1, 1, 1), blue);
+
+        assert_eq!(data.replace_color(blue, red), 1);
+        assert_eq!(data.get(1, 1, 1), red);
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::mem;
+
+/// How a `VoxelData` keeps its voxels in memory.
+///
+/// Voxels are palette indices addressed by the same `x * height * depth + y * depth + z` index
+/// used everywhere else, positions that were never set are 0, empty space.
+pub trait Storage: Send + Sync {
+    fn get(&self, index: usize) -> u8;
+
+    /// Sets a voxel and returns its previous palette index
+    fn set(&mut self, index: usize, color: u8) -> u8;
+
+    /// Approximate number of bytes used to store the voxels
+    fn memory_usage(&self) -> usize;
+
+    /// Creates an empty storage of the same kind for a model of another size
+    fn empty(&self, dims: [u32; 3]) -> Box<dyn Storage>;
+
+    fn boxed_clone(&self) -> Box<dyn Storage>;
+}
+
+/// The storage backends that come with Janus
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StorageKind {
+    /// One entry per voxel, fastest to read and write
+    Dense,
+    /// Hash map of visible voxels, best for scattered voxels in a big volume
+    Sparse,
+    /// Run length encoded columns along z, best for large solid or empty areas
+    Rle,
+}
+
+impl StorageKind {
+    pub fn create(self, dims: [u32; 3]) -> Box<dyn Storage> {
+        match self {
+            StorageKind::Dense => {
+                Box::new(Dense::new(vec![0; (dims[0] * dims[1] * dims[2]) as usize]))
+            }
+            StorageKind::Sparse => Box::new(Sparse::default()),
+            StorageKind::Rle => Box::new(Rle::new(dims)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Dense {
+    indices: Vec<u8>,
+}
+
+impl Dense {
+    pub fn new(indices: Vec<u8>) -> Self {
+        Self { indices }
+    }
+}
+
+impl Storage for Dense {
+    fn get(&self, index: usize) -> u8 {
+        self.indices[index]
+    }
+
+    fn set(&mut self, index: usize, color: u8) -> u8 {
+        mem::replace(&mut self.indices[index], color)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.indices.capacity()
+    }
+
+    fn empty(&self, dims: [u32; 3]) -> Box<dyn Storage> {
+        StorageKind::Dense.create(dims)
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Storage> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Sparse {
+    voxels: HashMap<u32, u8>,
+}
+
+impl Storage for Sparse {
+    fn get(&self, index: usize) -> u8 {
+        self.voxels.get(&(index as u32)).copied().unwrap_or(0)
+    }
+
+    fn set(&mut self, index: usize, color: u8) -> u8 {
+        let old = if color == 0 {
+            self.voxels.remove(&(index as u32))
+        } else {
+            self.voxels.insert(index as u32, color)
+        };
+        old.unwrap_or(0)
+    }
+
+    fn memory_usage(&self) -> usize {
+        // hashbrown keeps one control byte per bucket next to the entries
+        self.voxels.capacity() * (mem::size_of::<(u32, u8)>() + 1)
+    }
+
+    fn empty(&self, dims: [u32; 3]) -> Box<dyn Storage> {
+        StorageKind::Sparse.create(dims)
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Storage> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Run {
+    len: u32,
+    color: u8,
+}
+
+/// Every column of voxels along z stored as runs of the same color, empty columns take no space
+#[derive(Debug, Clone)]
+pub struct Rle {
+    depth: u32,
+    columns: Vec<Vec<Run>>,
+}
+
+impl Rle {
+    pub fn new(dims: [u32; 3]) -> Self {
+        Self {
+            depth: dims[2],
+            columns: vec![vec![]; (dims[0] * dims[1]) as usize],
+        }
+    }
+}
+
+impl Storage for Rle {
+    fn get(&self, index: usize) -> u8 {
+        let depth = self.depth as usize;
+        let mut z = (index % depth) as u32;
+        for run in &self.columns[index / depth] {
+            if z < run.len {
+                return run.color;
+            }
+            z -= run.len;
+        }
+        0
+    }
+
+    fn set(&mut self, index: usize, color: u8) -> u8 {
+        let depth = self.depth as usize;
+        let column = &mut self.columns[index / depth];
+
+        // re-encoding the whole column keeps runs merged, columns are short enough for this
+        let mut colors = Vec::with_capacity(depth);
+        for run in column.iter() {
+            colors.extend((0..run.len).map(|_| run.color));
+        }
+        colors.resize(depth, 0);
+        let old = mem::replace(&mut colors[index % depth], color);
+        if old == color {
+            return old;
+        }
+
+        column.clear();
+        for color in colors {
+            match column.last_mut() {
+                Some(run) if run.color == color => run.len += 1,
+                _ => column.push(Run { len: 1, color }),
+            }
+        }
+        // trailing empty space is implied
+        if column.last().map(|run| run.color) == Some(0) {
+            column.pop();
+        }
+        column.shrink_to_fit();
+        old
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.columns.capacity() * mem::size_of::<Vec<Run>>()
+            + self
+                .columns
+                .iter()
+                .map(|c| c.capacity() * mem::size_of::<Run>())
+                .sum::<usize>()
+    }
+
+    fn empty(&self, dims: [u32; 3]) -> Box<dyn Storage> {
+        StorageKind::Rle.create(dims)
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Storage> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxel_data::shapes::Brush;
+    use crate::voxel_data::{Color, VoxelData};
+
+    const KINDS: &[StorageKind] = &[StorageKind::Dense, StorageKind::Sparse, StorageKind::Rle];
+
+    #[test]
+    fn storages_agree() {
+        let indices = [1, 0, 2];
+        let mut stores: Vec<_> = KINDS.iter().map(|k| k.create([3, 4, 5])).collect();
+        for i in 0..60 {
+            let index = indices[(i * 7 / 3) % 3];
+            for store in &mut stores {
+                store.set(i, index);
+            }
+        }
+        // overwrite the middle of a run
+        for store in &mut stores {
+            assert_eq!(store.set(12, 9), indices[(12 * 7 / 3) % 3]);
+        }
+        for i in 0..60 {
+            let expected = stores[0].get(i);
+            assert!(stores.iter().all(|s| s.get(i) == expected));
+        }
+    }
+
+    // a hollow box is the worst case for dense storage, almost all of it is empty space
+    #[test]
+    fn sparse_models_use_less_memory() {
+        let size = 128;
+        let mut usage = vec![];
+        for &kind in KINDS {
+            let mut data = VoxelData::empty_with(kind, size, size, size);
+            let edge = size as i32 - 1;
+            let color = Color::new(200, 100, 50);
+            for &(a, b) in &[
+                ([0, 0, 0], [edge, edge, 0]),
+                ([0, 0, edge], [edge, edge, edge]),
+                ([0, 0, 0], [edge, 0, edge]),
+                ([0, edge, 0], [edge, edge, edge]),
+                ([0, 0, 0], [0, edge, edge]),
+                ([edge, 0, 0], [edge, edge, edge]),
+            ] {
+                data.fill_box(a, b, Brush::Fill(color));
+            }
+            assert_eq!(data.get(0, 64, 64), color);
+            assert_eq!(data.get(64, 64, 64), Color::CLEAR);
+            usage.push(data.memory_usage());
+        }
+        let columns = (size * size) as usize;
+        let filled = (size.pow(3) - (size - 2).pow(3)) as usize;
+        assert_eq!(usage[0], columns * size as usize);
+        // every sparse entry and every column's runs have to be paid for
+        assert!(usage[1] >= filled * (mem::size_of::<(u32, u8)>() + 1));
+        assert!(usage[2] >= columns * (mem::size_of::<Vec<Run>>() + mem::size_of::<Run>()));
+        // dense voxels are single palette indices while a sparse entry still takes nine bytes
+        assert!(usage[1] * 2 < usage[0]);
+        assert!(usage[2] * 2 < usage[0]);
+    }
+}
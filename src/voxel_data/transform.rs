@@ -0,0 +1,242 @@
+use super::{Axis, Region, VoxelData};
+use std::collections::HashMap;
+
+// All of these return a new model since they usually change its size.
+impl VoxelData {
+    // copies every voxel to a new model of size `dims` at the position given by `f`
+    fn remap(&self, dims: [u32; 3], f: impl Fn([u32; 3]) -> [u32; 3]) -> Self {
+        let mut out = self.empty_like(dims[0], dims[1], dims[2]);
+        for x in 0..self.width {
+            for y in 0..self.height {
+                for z in 0..self.depth {
+                    let [nx, ny, nz] = f([x, y, z]);
+                    let index = out.index(nx, ny, nz);
+                    out.storage
+                        .set(index, self.storage.get(self.index(x, y, z)));
+                }
+            }
+        }
+        out
+    }
+
+    /// Rotates the model by 90° `turns` times counter-clockwise around `axis`, negative turns
+    /// rotate clockwise
+    pub fn rotated(&self, axis: Axis, turns: i32) -> Self {
+        let mut out = self.remap(self.dims(), |pos| pos);
+        for _ in 0..turns.rem_euclid(4) {
+            out = out.rotated_once(axis);
+        }
+        out
+    }
+
+    fn rotated_once(&self, axis: Axis) -> Self {
+        // rotating around `axis` maps u to v and v to -u
+        let a = axis.index();
+        let (u, v) = ((a + 1) % 3, (a + 2) % 3);
+        let dims = self.dims();
+        let mut new_dims = dims;
+        new_dims[u] = dims[v];
+        new_dims[v] = dims[u];
+        self.remap(new_dims, |pos| {
+            let mut new = pos;
+            new[u] = dims[v] - 1 - pos[v];
+            new[v] = pos[u];
+            new
+        })
+    }
+
+    /// Flips the model along `axis`, for making left and right variants
+    pub fn mirrored(&self, axis: Axis) -> Self {
+        let a = axis.index();
+        let dims = self.dims();
+        self.remap(dims, |pos| {
+            let mut new = pos;
+            new[a] = dims[a] - 1 - pos[a];
+            new
+        })
+    }
+
+    /// The tightest region containing every visible voxel, `None` if the model is empty
+    pub fn bounds(&self) -> Option<Region> {
+        let mut bounds: Option<Region> = None;
+        for x in 0..self.width {
+            for y in 0..self.height {
+                for z in 0..self.depth {
+                    if self.get(x, y, z).is_visible() {
+                        let point = Region::point(x, y, z);
+                        bounds = Some(bounds.map_or(point, |b| b.union(&point)));
+                    }
+                }
+            }
+        }
+        bounds
+    }
+
+    /// Cuts the model down to a region, see `bounds` for cropping to the visible voxels
+    pub fn cropped(&self, region: Region) -> Self {
+        let region = region.intersect(&Region::new([0, 0, 0], self.dims()));
+        if region.is_empty() {
+            return self.empty_like(0, 0, 0);
+        }
+        let [width, height, depth] = region.size();
+        let mut out = self.empty_like(width, height, depth);
+        for x in 0..width {
+            for y in 0..height {
+                for z in 0..depth {
+                    let index = out.index(x, y, z);
+                    let color =
+                        self.get_index(x + region.min[0], y + region.min[1], z + region.min[2]);
+                    out.storage.set(index, color);
+                }
+            }
+        }
+        out
+    }
+
+    /// Adds empty space around the model, `before` on the low side of each axis and `after` on
+    /// the high side
+    pub fn padded(&self, before: [u32; 3], after: [u32; 3]) -> Self {
+        let dims = self.dims();
+        let new_dims = [
+            dims[0] + before[0] + after[0],
+            dims[1] + before[1] + after[1],
+            dims[2] + before[2] + after[2],
+        ];
+        self.remap(new_dims, |pos| {
+            [pos[0] + before[0], pos[1] + before[1], pos[2] + before[2]]
+        })
+    }
+
+    /// Scales the model up by an integer factor, every voxel becomes a `factor`³ block
+    pub fn scaled_up(&self, factor: u32) -> Self {
+        assert!(factor > 0, "scale factor must be at least 1");
+        let mut out = self.empty_like(
+            self.width * factor,
+            self.height * factor,
+            self.depth * factor,
+        );
+        for x in 0..out.width {
+            for y in 0..out.height {
+                for z in 0..out.depth {
+                    let index = out.index(x, y, z);
+                    out.storage
+                        .set(index, self.get_index(x / factor, y / factor, z / factor));
+                }
+            }
+        }
+        out
+    }
+
+    /// Scales the model down by an integer factor, every `factor`³ block becomes the most
+    /// common color in it. Ties go to visible voxels so thin details don't vanish.
+    pub fn scaled_down(&self, factor: u32) -> Self {
+        assert!(factor > 0, "scale factor must be at least 1");
+        let mut out = self.empty_like(
+            self.width.div_ceil(factor),
+            self.height.div_ceil(factor),
+            self.depth.div_ceil(factor),
+        );
+        let mut votes: HashMap<u8, u32> = HashMap::new();
+        for x in 0..out.width {
+            for y in 0..out.height {
+                for z in 0..out.depth {
+                    votes.clear();
+                    let block = Region::new(
+                        [x * factor, y * factor, z * factor],
+                        [(x + 1) * factor, (y + 1) * factor, (z + 1) * factor],
+                    )
+                    .intersect(&Region::new([0, 0, 0], self.dims()));
+                    for bx in block.min[0]..block.max[0] {
+                        for by in block.min[1]..block.max[1] {
+                            for bz in block.min[2]..block.max[2] {
+                                *votes.entry(self.get_index(bx, by, bz)).or_insert(0) += 1;
+                            }
+                        }
+                    }
+                    // pick deterministically between colors with the same count
+                    let winner = votes
+                        .iter()
+                        .max_by_key(|(&index, &count)| (count, index != 0, index))
+                        .map_or(0, |(index, _)| *index);
+                    let index = out.index(x, y, z);
+                    out.storage.set(index, winner);
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxel_data::shapes::Brush;
+    use crate::voxel_data::Color;
+
+    fn sample() -> VoxelData {
+        let mut data = VoxelData::empty(2, 3, 4);
+        data.set(0, 0, 0, Color::new(1, 0, 0));
+        data.set(1, 2, 3, Color::new(0, 1, 0));
+        data.set(1, 0, 2, Color::new(0, 0, 1));
+        data
+    }
+
+    #[test]
+    fn rotations() {
+        let data = sample();
+        for &axis in &[Axis::X, Axis::Y, Axis::Z] {
+            assert_eq!(data.rotated(axis, 4).colors(), data.colors());
+            assert_eq!(
+                data.rotated(axis, 1).rotated(axis, -1).colors(),
+                data.colors()
+            );
+        }
+
+        // a quarter turn around y takes +x to -z
+        let rotated = data.rotated(Axis::Y, 1);
+        assert_eq!(rotated.dims(), [4, 3, 2]);
+        assert_eq!(rotated.get(0, 0, 1), Color::new(1, 0, 0));
+        assert_eq!(rotated.get(2, 0, 0), Color::new(0, 0, 1));
+    }
+
+    #[test]
+    fn mirroring() {
+        let data = sample();
+        let mirrored = data.mirrored(Axis::Z);
+        assert_eq!(mirrored.get(0, 0, 3), Color::new(1, 0, 0));
+        assert_eq!(mirrored.mirrored(Axis::Z).colors(), data.colors());
+    }
+
+    #[test]
+    fn crop_and_pad() {
+        let mut data = VoxelData::empty(5, 5, 5);
+        data.set(1, 2, 3, Color::new(1, 1, 1));
+        data.set(2, 2, 4, Color::new(2, 2, 2));
+
+        let bounds = data.bounds().unwrap();
+        assert_eq!(bounds, Region::new([1, 2, 3], [3, 3, 5]));
+        let cropped = data.cropped(bounds);
+        assert_eq!(cropped.dims(), [2, 1, 2]);
+        assert_eq!(cropped.get(1, 0, 1), Color::new(2, 2, 2));
+
+        let padded = cropped.padded([1, 2, 3], [2, 2, 0]);
+        assert_eq!(padded.dims(), [5, 5, 5]);
+        assert_eq!(padded.colors(), data.colors());
+    }
+
+    #[test]
+    fn scaling() {
+        let data = sample();
+        let up = data.scaled_up(3);
+        assert_eq!(up.dims(), [6, 9, 12]);
+        assert_eq!(up.get(5, 8, 11), Color::new(0, 1, 0));
+        assert_eq!(up.scaled_down(3).colors(), data.colors());
+
+        // half a block of voxels wins a tie against empty space
+        let mut data = VoxelData::empty(2, 2, 2);
+        data.fill_box([0, 0, 0], [1, 1, 0], Brush::Fill(Color::new(1, 1, 1)));
+        assert_eq!(data.scaled_down(2).get(0, 0, 0), Color::new(1, 1, 1));
+        data.set(0, 0, 0, Color::CLEAR);
+        assert_eq!(data.scaled_down(2).get(0, 0, 0), Color::CLEAR);
+    }
+}
@@ -0,0 +1,510 @@
+use crate::voxel_data::mesher::{Mesher, Surroundings};
+use crate::voxel_data::{Color, Emitters, MeshOptions, Ramps, Region, VoxelBuffer, VoxelData};
+use light::WorldLight;
+use std::collections::HashMap;
+use ultraviolet::Vec2;
+
+pub mod light;
+
+/// Edge length of the chunks a `VoxelWorld` is made of
+pub const CHUNK_SIZE: u32 = 32;
+
+/// Provides chunks to a `VoxelWorld` as they come into range, such as a world generator or a
+/// save file
+pub trait ChunkSource {
+    /// Returns the voxels of a chunk, `None` if it is empty. The data must be `CHUNK_SIZE` on
+    /// every axis.
+    fn load(&mut self, coord: [i32; 3]) -> Option<VoxelData>;
+
+    /// Receives chunks as they are unloaded, so edits can be saved
+    fn unload(&mut self, _coord: [i32; 3], _data: VoxelData) {}
+}
+
+/// Splits world coordinates into chunk coordinates and a position within that chunk
+pub fn chunk_coord(pos: [i32; 3]) -> ([i32; 3], [u32; 3]) {
+    let size = CHUNK_SIZE as i32;
+    (
+        [
+            pos[0].div_euclid(size),
+            pos[1].div_euclid(size),
+            pos[2].div_euclid(size),
+        ],
+        [
+            pos[0].rem_euclid(size) as u32,
+            pos[1].rem_euclid(size) as u32,
+            pos[2].rem_euclid(size) as u32,
+        ],
+    )
+}
+
+fn chunk_origin(coord: [i32; 3]) -> [i32; 3] {
+    let size = CHUNK_SIZE as i32;
+    [coord[0] * size, coord[1] * size, coord[2] * size]
+}
+
+/// A level sized voxel scene split into fixed size chunks that can be streamed in and out.
+///
+/// Chunk meshes are built in world space so the whole world renders with one model matrix, each
+/// chunk still has its own textures though.
+///
+/// Sky and block light are flood filled through the loaded chunks as they are loaded and
+/// edited, and baked into the meshes, see `light`.
+pub struct VoxelWorld {
+    chunks: HashMap<[i32; 3], VoxelBuffer>,
+    // chunks that have been loaded or edited but not uploaded to the gpu yet
+    pending: HashMap<[i32; 3], VoxelData>,
+    options: MeshOptions,
+    light: WorldLight,
+    ramps: Ramps,
+    // meshes chunks off the render thread, started by the first update
+    mesher: Option<Mesher<[i32; 3]>>,
+}
+
+impl Default for VoxelWorld {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VoxelWorld {
+    pub fn new() -> Self {
+        Self::with_options(MeshOptions::default())
+    }
+
+    pub fn with_options(options: MeshOptions) -> Self {
+        Self {
+            chunks: HashMap::new(),
+            pending: HashMap::new(),
+            options,
+            light: WorldLight::default(),
+            ramps: Ramps::new(),
+            mesher: None,
+        }
+    }
+
+    fn chunk(&self, coord: [i32; 3]) -> Option<&VoxelData> {
+        self.chunks
+            .get(&coord)
+            .map(|c| c.data())
+            .or_else(|| self.pending.get(&coord))
+    }
+
+    fn chunk_mut(&mut self, coord: [i32; 3]) -> Option<&mut VoxelData> {
+        match self.chunks.get_mut(&coord) {
+            Some(chunk) => Some(chunk.data_mut()),
+            None => self.pending.get_mut(&coord),
+        }
+    }
+
+    pub fn is_loaded(&self, coord: [i32; 3]) -> bool {
+        self.chunks.contains_key(&coord) || self.pending.contains_key(&coord)
+    }
+
+    /// Returns `Color::CLEAR` for positions in chunks that aren't loaded
+    pub fn get(&self, x: i32, y: i32, z: i32) -> Color {
+        let (coord, [lx, ly, lz]) = chunk_coord([x, y, z]);
+        self.chunk(coord)
+            .map_or(Color::CLEAR, |chunk| chunk.get(lx, ly, lz))
+    }
+
+    /// Sets a voxel in world space and returns its previous color. Setting a voxel in a missing
+    /// chunk creates it.
+    pub fn set(&mut self, x: i32, y: i32, z: i32, color: Color) -> Color {
+        let (coord, [lx, ly, lz]) = chunk_coord([x, y, z]);
+        if !self.is_loaded(coord) {
+            if !color.is_visible() {
+                return Color::CLEAR;
+            }
+            self.pending
+                .insert(coord, VoxelData::empty(CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE));
+            self.relight(|light, voxel| light.add_chunk(coord, voxel));
+        }
+        let old = self.chunk_mut(coord).unwrap().set(lx, ly, lz, color);
+        let emits = |c| self.light.emission(c);
+        if old.is_visible() != color.is_visible() || emits(old) != emits(color) {
+            self.relight(|light, voxel| light.voxel_changed([x, y, z], voxel));
+        }
+        if old.is_visible() != color.is_visible() {
+            // neighboring chunks may have faces or occlusion that depend on this voxel
+            for dx in -1..=1 {
+                for dy in -1..=1 {
+                    for dz in -1..=1 {
+                        let (other, [nx, ny, nz]) = chunk_coord([x + dx, y + dy, z + dz]);
+                        if other != coord {
+                            if let Some(chunk) = self.chunk_mut(other) {
+                                chunk.mark_dirty(Region::point(nx, ny, nz));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        old
+    }
+
+    /// Adds a chunk to the world, replacing any chunk already there
+    pub fn insert_chunk(&mut self, coord: [i32; 3], data: VoxelData) {
+        assert_eq!(
+            data.dims(),
+            [CHUNK_SIZE; 3],
+            "chunks must be CHUNK_SIZE cubed"
+        );
+        // light the old chunk gave its neighbors goes with it
+        self.remove_chunk(coord);
+        self.pending.insert(coord, data);
+        self.touch_borders(coord);
+        self.relight(|light, voxel| light.add_chunk(coord, voxel));
+    }
+
+    pub fn remove_chunk(&mut self, coord: [i32; 3]) -> Option<VoxelData> {
+        let data = match self.chunks.remove(&coord) {
+            Some(chunk) => Some(chunk.into_data()),
+            None => self.pending.remove(&coord),
+        };
+        if let Some(mesher) = &mut self.mesher {
+            mesher.cancel(&coord);
+        }
+        if data.is_some() {
+            self.touch_borders(coord);
+            self.relight(|light, voxel| light.remove_chunk(coord, voxel));
+        }
+        data
+    }
+
+    /// Makes voxels of the emitting colors glow and give off block light, at the level
+    /// `light::emission_level` gives their strength. Only voxels loaded or set afterwards light
+    /// up and only chunks meshed afterwards glow, so emitters are best set up before streaming
+    /// in any chunks.
+    pub fn set_emitters(&mut self, emitters: Emitters) {
+        self.light.set_emitters(emitters);
+    }
+
+    pub fn emitters(&self) -> &Emitters {
+        self.light.emitters()
+    }
+
+    /// Shades the ramped colors of every chunk along their ramp, see `VoxelBuffer::set_ramps`
+    pub fn set_ramps(&mut self, ramps: Ramps) {
+        for chunk in self.chunks.values_mut() {
+            chunk.set_ramps(ramps.clone());
+        }
+        self.ramps = ramps;
+    }
+
+    pub fn ramps(&self) -> &Ramps {
+        &self.ramps
+    }
+
+    /// Sky and block light at a position from 0 to `light::MAX_LIGHT`, positions in chunks that
+    /// aren't loaded are under open sky
+    pub fn light(&self, x: i32, y: i32, z: i32) -> (u8, u8) {
+        self.light.get([x, y, z])
+    }
+
+    // runs a light update and marks the voxels around everything it changed for re-meshing,
+    // their faces bake in the light
+    fn relight(
+        &mut self,
+        update: impl FnOnce(&mut WorldLight, &dyn Fn([i32; 3]) -> Color) -> Vec<[i32; 3]>,
+    ) {
+        let mut light = std::mem::take(&mut self.light);
+        let changed = update(&mut light, &|[x, y, z]| self.get(x, y, z));
+        self.light = light;
+
+        // bounds of the changes in each chunk, grown by the voxels whose corners they light
+        let mut bounds: HashMap<[i32; 3], ([i32; 3], [i32; 3])> = HashMap::new();
+        for pos in changed {
+            let (coord, _) = chunk_coord(pos);
+            let (min, max) = bounds.entry(coord).or_insert((pos, pos));
+            for i in 0..3 {
+                min[i] = min[i].min(pos[i]);
+                max[i] = max[i].max(pos[i]);
+            }
+        }
+        for (min, max) in bounds.values() {
+            let min = [min[0] - 1, min[1] - 1, min[2] - 1];
+            let max = [max[0] + 1, max[1] + 1, max[2] + 1];
+            let (first, _) = chunk_coord(min);
+            let (last, _) = chunk_coord(max);
+            for cx in first[0]..=last[0] {
+                for cy in first[1]..=last[1] {
+                    for cz in first[2]..=last[2] {
+                        let coord = [cx, cy, cz];
+                        let origin = chunk_origin(coord);
+                        let local = |pos: [i32; 3], i: usize| {
+                            (pos[i] - origin[i]).max(0).min(CHUNK_SIZE as i32 - 1) as u32
+                        };
+                        let region = Region::new(
+                            [local(min, 0), local(min, 1), local(min, 2)],
+                            [local(max, 0) + 1, local(max, 1) + 1, local(max, 2) + 1],
+                        );
+                        if let Some(chunk) = self.chunk_mut(coord) {
+                            chunk.mark_dirty(region);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // sky and block light of a position next to a chunk's voxels, as the mesher bakes it
+    fn light_at(&self, origin: [i32; 3], pos: [i32; 3]) -> Vec2 {
+        let (sky, block) =
+            self.light
+                .get([origin[0] + pos[0], origin[1] + pos[1], origin[2] + pos[2]]);
+        Vec2::new(light::brightness(sky), light::brightness(block))
+    }
+
+    // marks the sides of neighboring chunks facing `coord` for re-meshing
+    fn touch_borders(&mut self, coord: [i32; 3]) {
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    let offset = [dx, dy, dz];
+                    if offset == [0, 0, 0] {
+                        continue;
+                    }
+                    let mut border = Region::new([0, 0, 0], [CHUNK_SIZE; 3]);
+                    for (i, o) in offset.iter().enumerate() {
+                        match o {
+                            1 => border.max[i] = 1,
+                            -1 => border.min[i] = CHUNK_SIZE - 1,
+                            _ => {}
+                        }
+                    }
+                    let other = [coord[0] + dx, coord[1] + dy, coord[2] + dz];
+                    if let Some(chunk) = self.chunk_mut(other) {
+                        chunk.mark_dirty(border);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Loads every chunk within `radius` chunks of the `focus` position from `source`, and
+    /// unloads chunks that are more than a chunk further than that
+    pub fn stream(&mut self, focus: [i32; 3], radius: u32, source: &mut dyn ChunkSource) {
+        let (center, _) = chunk_coord(focus);
+        let radius = radius as i32;
+        let distance = |coord: &[i32; 3]| {
+            let d = [
+                coord[0] - center[0],
+                coord[1] - center[1],
+                coord[2] - center[2],
+            ];
+            d[0] * d[0] + d[1] * d[1] + d[2] * d[2]
+        };
+
+        // a little slack before unloading so chunks on the edge don't thrash
+        let keep = (radius + 1) * (radius + 1);
+        let far: Vec<_> = self
+            .chunks
+            .keys()
+            .chain(self.pending.keys())
+            .filter(|coord| distance(coord) > keep)
+            .copied()
+            .collect();
+        for coord in far {
+            if let Some(data) = self.remove_chunk(coord) {
+                source.unload(coord, data);
+            }
+        }
+
+        for x in -radius..=radius {
+            for y in -radius..=radius {
+                for z in -radius..=radius {
+                    let coord = [center[0] + x, center[1] + y, center[2] + z];
+                    if distance(&coord) <= radius * radius && !self.is_loaded(coord) {
+                        if let Some(data) = source.load(coord) {
+                            self.insert_chunk(coord, data);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Uploads new chunks and edits to the gpu. Returns the chunks whose textures were created
+    /// or recreated, bind groups made from their textures have to be rebuilt.
+    ///
+    /// Chunks are meshed on worker threads, so new chunks show up and edits are re-meshed a few
+    /// updates later. Textures of edited chunks are uploaded right away.
+    pub fn update(&mut self, ctx: &crate::Context) -> Vec<[i32; 3]> {
+        let mut rebind = vec![];
+        let mut mesher = self.mesher.take().unwrap_or_default();
+
+        for (coord, meshed) in mesher.poll() {
+            if let Some(chunk) = self.chunks.get_mut(&coord) {
+                chunk.set_meshes(meshed.meshes(), ctx);
+            } else if let Some(mut data) = self.pending.remove(&coord) {
+                // uploaded as it is now, edits made since the job was queued are queued again
+                // below along with every other edited chunk
+                let dirty = data.take_dirty();
+                let palette_dirty = data.take_palette_dirty();
+                let origin = chunk_origin(coord);
+                let mut buffer =
+                    VoxelBuffer::from_meshes(data, self.options, origin, meshed.meshes(), ctx);
+                buffer.mesh_elsewhere();
+                if !self.emitters().is_empty() {
+                    buffer.set_emitters(self.emitters().clone());
+                }
+                if !self.ramps.is_empty() {
+                    buffer.set_ramps(self.ramps.clone());
+                }
+                if let Some(dirty) = dirty {
+                    buffer.data_mut().mark_dirty(dirty);
+                }
+                if palette_dirty {
+                    buffer.data_mut().palette_mut();
+                }
+                self.chunks.insert(coord, buffer);
+                rebind.push(coord);
+            }
+        }
+
+        self.queue_meshes(&mut mesher);
+        // only the textures and palettes are left to upload
+        for (coord, chunk) in &mut self.chunks {
+            if chunk.update(ctx) {
+                rebind.push(*coord);
+            }
+        }
+        self.mesher = Some(mesher);
+        rebind
+    }
+
+    // queues new chunks that aren't being meshed yet and every edited chunk. Edits to new
+    // chunks are taken here, edits to uploaded chunks are left for `VoxelBuffer::update`.
+    fn queue_meshes(&mut self, mesher: &mut Mesher<[i32; 3]>) {
+        let new: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(coord, data)| data.is_dirty() || !mesher.is_pending(coord))
+            .map(|(coord, _)| *coord)
+            .collect();
+        for coord in new {
+            let data = self.pending.get_mut(&coord).unwrap();
+            data.take_dirty();
+            data.take_palette_dirty();
+            self.queue_mesh(coord, mesher);
+        }
+
+        let dirty: Vec<_> = self
+            .chunks
+            .iter()
+            .filter(|(_, c)| c.data().is_dirty())
+            .map(|(coord, _)| *coord)
+            .collect();
+        for coord in dirty {
+            self.queue_mesh(coord, mesher);
+        }
+    }
+
+    // meshes a chunk as it is now in the background, with a copy of the voxels and light
+    // around it
+    fn queue_mesh(&self, coord: [i32; 3], mesher: &mut Mesher<[i32; 3]>) {
+        let data = self.chunk(coord).unwrap().clone();
+        let origin = chunk_origin(coord);
+        let outside = |pos: [i32; 3]| self.is_solid(origin, pos);
+        let light = |pos: [i32; 3]| self.light_at(origin, pos);
+        let around = Surroundings::capture(data.dims(), &outside, &light);
+        mesher.queue_chunk(coord, data, self.options, origin, around);
+    }
+
+    fn is_solid(&self, origin: [i32; 3], pos: [i32; 3]) -> bool {
+        self.get(origin[0] + pos[0], origin[1] + pos[1], origin[2] + pos[2])
+            .is_visible()
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = ([i32; 3], &VoxelBuffer)> {
+        self.chunks.iter().map(|(coord, chunk)| (*coord, chunk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Flat;
+
+    impl ChunkSource for Flat {
+        fn load(&mut self, coord: [i32; 3]) -> Option<VoxelData> {
+            if coord[1] != 0 {
+                return None;
+            }
+            let mut data = VoxelData::empty(CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE);
+            for x in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    data.set(x, 0, z, Color::new(0, 255, 0));
+                }
+            }
+            Some(data)
+        }
+    }
+
+    #[test]
+    fn world_coordinates() {
+        assert_eq!(chunk_coord([0, 31, 32]), ([0, 0, 1], [0, 31, 0]));
+        assert_eq!(chunk_coord([-1, -32, -33]), ([-1, -1, -2], [31, 0, 31]));
+
+        let mut world = VoxelWorld::new();
+        let red = Color::new(255, 0, 0);
+        world.set(-5, 40, 3, red);
+        assert!(world.is_loaded([-1, 1, 0]));
+        assert_eq!(world.get(-5, 40, 3), red);
+        assert_eq!(world.get(-5, 41, 3), Color::CLEAR);
+        // clearing voxels in empty space doesn't create chunks
+        world.set(500, 0, 0, Color::CLEAR);
+        assert!(!world.is_loaded([15, 0, 0]));
+    }
+
+    #[test]
+    fn streaming() {
+        let mut world = VoxelWorld::new();
+        world.stream([0, 0, 0], 1, &mut Flat);
+        assert!(world.is_loaded([0, 0, 0]));
+        assert!(world.is_loaded([1, 0, 0]));
+        assert!(!world.is_loaded([1, 0, 1]));
+        assert!(!world.is_loaded([0, 1, 0]));
+        assert!(world.get(-1, 0, 5).is_visible());
+
+        world.stream([32 * 5, 0, 0], 1, &mut Flat);
+        assert!(!world.is_loaded([0, 0, 0]));
+        assert!(world.is_loaded([5, 0, 0]));
+    }
+
+    #[test]
+    fn edits_dirty_neighbors() {
+        let mut world = VoxelWorld::new();
+        world.stream([0, 0, 0], 1, &mut Flat);
+        world.queue_meshes(&mut Mesher::new(1));
+        assert!(world.pending.values().all(|data| !data.is_dirty()));
+        world.set(31, 1, 0, Color::new(1, 1, 1));
+        assert!(world.pending[&[1, 0, 0]].is_dirty());
+        assert!(world.pending[&[0, 0, -1]].is_dirty());
+        assert!(!world.pending[&[0, 0, 1]].is_dirty());
+    }
+
+    #[test]
+    fn new_chunks_finish_meshing() {
+        let mut world = VoxelWorld::new();
+        world.insert_chunk([0, 0, 0], Flat.load([0, 0, 0]).unwrap());
+        let mut mesher = Mesher::new(1);
+        // a frame at a time, queuing a chunk again would cancel the job already running
+        let start = std::time::Instant::now();
+        let meshed = loop {
+            world.queue_meshes(&mut mesher);
+            if let Some((coord, meshed)) = mesher.poll().pop() {
+                break (coord, meshed);
+            }
+            assert!(
+                start.elapsed().as_secs() < 10,
+                "the chunk never finished meshing"
+            );
+            std::thread::yield_now();
+        };
+        assert_eq!(meshed.0, [0, 0, 0]);
+        assert!(meshed.1.vert_count() > 0);
+        assert_eq!(mesher.pending(), 0);
+    }
+}
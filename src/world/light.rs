@@ -0,0 +1,449 @@
+use super::{chunk_coord, CHUNK_SIZE};
+use crate::voxel_data::{Color, Emitters};
+use std::collections::{HashMap, VecDeque};
+
+/// Brightest a voxel gets, from the open sky or right next to an emitter. Light loses a level
+/// for every voxel it spreads, except sunlight falling straight down.
+pub const MAX_LIGHT: u8 = 15;
+
+const NEIGHBORS: [[i32; 3]; 6] = [
+    [1, 0, 0],
+    [-1, 0, 0],
+    [0, 1, 0],
+    [0, -1, 0],
+    [0, 0, 1],
+    [0, 0, -1],
+];
+const DOWN: usize = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Channel {
+    Sky,
+    Block,
+}
+
+/// How bright a light level looks, every level down is a fifth darker and 0 is black
+pub fn brightness(level: u8) -> f32 {
+    if level == 0 {
+        0.0
+    } else {
+        0.8f32.powi((MAX_LIGHT - level.min(MAX_LIGHT)) as i32)
+    }
+}
+
+/// The block light level an emitter of `strength` gives off, see `Emitters`. Strengths of 1 and
+/// up are as bright as light gets, weaker ones start at the level whose `brightness` matches.
+pub fn emission_level(strength: f32) -> u8 {
+    if strength >= 1.0 {
+        MAX_LIGHT
+    } else if strength > 0.0 {
+        let darker = (strength.ln() / 0.8f32.ln()).round();
+        (MAX_LIGHT as f32 - darker).max(1.0) as u8
+    } else {
+        0
+    }
+}
+
+/// Sky and block light of every voxel of the loaded chunks of a `VoxelWorld`, flood filled
+/// from the open sky and from emitting voxels.
+///
+/// Light only spreads through loaded chunks. Sunlight comes in through the top of a chunk
+/// with nothing loaded above it, so unloaded space above the world counts as open sky.
+#[derive(Default)]
+pub(crate) struct WorldLight {
+    // sky light in the high nibble and block light in the low one, indexed like `VoxelData`
+    chunks: HashMap<[i32; 3], Vec<u8>>,
+    emitters: Emitters,
+}
+
+fn index([x, y, z]: [u32; 3]) -> usize {
+    (x * CHUNK_SIZE * CHUNK_SIZE + y * CHUNK_SIZE + z) as usize
+}
+
+fn offset(pos: [i32; 3], by: [i32; 3]) -> [i32; 3] {
+    [pos[0] + by[0], pos[1] + by[1], pos[2] + by[2]]
+}
+
+// world positions of every voxel of a chunk for which `keep` returns true, given local ones
+fn cells(coord: [i32; 3], keep: impl Fn([u32; 3]) -> bool) -> Vec<[i32; 3]> {
+    let size = CHUNK_SIZE as i32;
+    let mut cells = vec![];
+    for x in 0..CHUNK_SIZE {
+        for y in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                if keep([x, y, z]) {
+                    cells.push([
+                        coord[0] * size + x as i32,
+                        coord[1] * size + y as i32,
+                        coord[2] * size + z as i32,
+                    ]);
+                }
+            }
+        }
+    }
+    cells
+}
+
+impl WorldLight {
+    /// Light level of a color, 0 for colors that don't emit
+    pub fn emission(&self, color: Color) -> u8 {
+        emission_level(self.emitters.get(color))
+    }
+
+    pub fn emitters(&self) -> &Emitters {
+        &self.emitters
+    }
+
+    pub fn set_emitters(&mut self, emitters: Emitters) {
+        self.emitters = emitters;
+    }
+
+    /// Sky and block light of a position, open sky for positions in chunks that aren't loaded
+    pub fn get(&self, pos: [i32; 3]) -> (u8, u8) {
+        let (coord, local) = chunk_coord(pos);
+        match self.chunks.get(&coord) {
+            Some(levels) => {
+                let packed = levels[index(local)];
+                (packed >> 4, packed & 0xF)
+            }
+            None => (MAX_LIGHT, 0),
+        }
+    }
+
+    fn is_loaded(&self, pos: [i32; 3]) -> bool {
+        self.chunks.contains_key(&chunk_coord(pos).0)
+    }
+
+    fn level(&self, channel: Channel, pos: [i32; 3]) -> u8 {
+        let (sky, block) = self.get(pos);
+        match channel {
+            Channel::Sky => sky,
+            Channel::Block => block,
+        }
+    }
+
+    fn set_level(&mut self, channel: Channel, pos: [i32; 3], level: u8) {
+        let (coord, local) = chunk_coord(pos);
+        let packed = &mut self.chunks.get_mut(&coord).unwrap()[index(local)];
+        *packed = match channel {
+            Channel::Sky => (*packed & 0xF) | (level << 4),
+            Channel::Block => (*packed & 0xF0) | level,
+        };
+    }
+
+    // the light a voxel makes itself, from an emitter or the open sky right above it
+    fn source(&self, channel: Channel, pos: [i32; 3], voxel: &dyn Fn([i32; 3]) -> Color) -> u8 {
+        let color = voxel(pos);
+        match channel {
+            Channel::Block => self.emission(color),
+            Channel::Sky if !color.is_visible() && !self.is_loaded(offset(pos, [0, 1, 0])) => {
+                MAX_LIGHT
+            }
+            Channel::Sky => 0,
+        }
+    }
+
+    // spreads light outwards from every queued position, adding the voxels it lit to `changed`
+    fn spread(
+        &mut self,
+        channel: Channel,
+        mut queue: VecDeque<[i32; 3]>,
+        voxel: &dyn Fn([i32; 3]) -> Color,
+        changed: &mut Vec<[i32; 3]>,
+    ) {
+        while let Some(pos) = queue.pop_front() {
+            let level = self.level(channel, pos);
+            if level == 0 {
+                continue;
+            }
+            for (dir, &by) in NEIGHBORS.iter().enumerate() {
+                let next = offset(pos, by);
+                if !self.is_loaded(next) || voxel(next).is_visible() {
+                    continue;
+                }
+                let reach = if channel == Channel::Sky && dir == DOWN && level == MAX_LIGHT {
+                    MAX_LIGHT
+                } else {
+                    level - 1
+                };
+                if self.level(channel, next) < reach {
+                    self.set_level(channel, next, reach);
+                    changed.push(next);
+                    queue.push_back(next);
+                }
+            }
+        }
+    }
+
+    // darkens every voxel lit through the given ones and lights them back up from whatever
+    // light is left around them
+    fn unspread(
+        &mut self,
+        channel: Channel,
+        start: &[[i32; 3]],
+        voxel: &dyn Fn([i32; 3]) -> Color,
+        changed: &mut Vec<[i32; 3]>,
+    ) {
+        let mut queue = VecDeque::new();
+        for &pos in start {
+            if self.is_loaded(pos) {
+                queue.push_back((pos, self.level(channel, pos)));
+                self.set_level(channel, pos, 0);
+                changed.push(pos);
+            }
+        }
+
+        let mut refill = VecDeque::new();
+        while let Some((pos, old)) = queue.pop_front() {
+            let source = self.source(channel, pos, voxel);
+            if source > 0 {
+                self.set_level(channel, pos, source);
+                refill.push_back(pos);
+            }
+            for (dir, &by) in NEIGHBORS.iter().enumerate() {
+                let next = offset(pos, by);
+                if !self.is_loaded(next) {
+                    continue;
+                }
+                let level = self.level(channel, next);
+                if level == 0 {
+                    continue;
+                }
+                let fed = channel == Channel::Sky && dir == DOWN && old == MAX_LIGHT;
+                if level < old || (fed && level == MAX_LIGHT) {
+                    self.set_level(channel, next, 0);
+                    changed.push(next);
+                    queue.push_back((next, level));
+                } else {
+                    refill.push_back(next);
+                }
+            }
+        }
+        self.spread(channel, refill, voxel, changed);
+    }
+
+    // the voxels of loaded chunks that touch a side of `coord`
+    fn borders(&self, coord: [i32; 3]) -> Vec<[i32; 3]> {
+        NEIGHBORS
+            .iter()
+            .map(|&by| offset(coord, by))
+            .filter(|other| self.chunks.contains_key(other))
+            .flat_map(|other| {
+                cells(other, |local| {
+                    (0..3).any(|i| match coord[i] - other[i] {
+                        1 => local[i] == CHUNK_SIZE - 1,
+                        -1 => local[i] == 0,
+                        _ => false,
+                    })
+                })
+            })
+            .collect()
+    }
+
+    /// Lights up a newly loaded chunk and the chunk below it loses its sky. Returns every voxel
+    /// whose light changed.
+    pub fn add_chunk(
+        &mut self,
+        coord: [i32; 3],
+        voxel: &dyn Fn([i32; 3]) -> Color,
+    ) -> Vec<[i32; 3]> {
+        self.chunks.insert(
+            coord,
+            vec![0; (CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as usize],
+        );
+        let mut changed = vec![];
+
+        // light already around the chunk flows in through its sides
+        let borders = self.borders(coord);
+
+        for &channel in &[Channel::Sky, Channel::Block] {
+            let mut queue: VecDeque<_> = borders.iter().copied().collect();
+            // only the top layer can see the sky, anywhere can hold an emitter
+            let seeds = cells(coord, |local| {
+                channel == Channel::Block || local[1] == CHUNK_SIZE - 1
+            });
+            for pos in seeds {
+                let source = self.source(channel, pos, voxel);
+                if source > 0 {
+                    self.set_level(channel, pos, source);
+                    changed.push(pos);
+                    queue.push_back(pos);
+                }
+            }
+            self.spread(channel, queue, voxel, &mut changed);
+        }
+
+        // the top of the chunk below was open sky until now
+        let below = offset(coord, [0, -1, 0]);
+        if self.chunks.contains_key(&below) {
+            let roof = cells(below, |local| local[1] == CHUNK_SIZE - 1);
+            self.unspread(Channel::Sky, &roof, voxel, &mut changed);
+        }
+        changed
+    }
+
+    /// Drops the light of a chunk that is being unloaded, `voxel` must no longer see it.
+    /// Returns every voxel of the remaining chunks whose light changed.
+    pub fn remove_chunk(
+        &mut self,
+        coord: [i32; 3],
+        voxel: &dyn Fn([i32; 3]) -> Color,
+    ) -> Vec<[i32; 3]> {
+        if self.chunks.remove(&coord).is_none() {
+            return vec![];
+        }
+        // anything the chunk lit around it goes dark and is lit again from what is left,
+        // which includes the sky now open above the chunk below
+        let borders = self.borders(coord);
+        let mut changed = vec![];
+        for &channel in &[Channel::Sky, Channel::Block] {
+            self.unspread(channel, &borders, voxel, &mut changed);
+        }
+        changed
+    }
+
+    /// Updates the light around a voxel after it was set, `voxel` must already see its new
+    /// color. Returns every voxel whose light changed.
+    pub fn voxel_changed(
+        &mut self,
+        pos: [i32; 3],
+        voxel: &dyn Fn([i32; 3]) -> Color,
+    ) -> Vec<[i32; 3]> {
+        let mut changed = vec![];
+        for &channel in &[Channel::Sky, Channel::Block] {
+            self.unspread(channel, &[pos], voxel, &mut changed);
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxel_data::VoxelData;
+    use crate::world::VoxelWorld;
+
+    fn lamp_world(lamp: Color, level: u8) -> VoxelWorld {
+        let mut emitters = Emitters::new();
+        emitters.set(lamp, brightness(level));
+        let mut world = VoxelWorld::new();
+        world.set_emitters(emitters);
+        world
+    }
+
+    fn floor() -> VoxelData {
+        let mut data = VoxelData::empty(CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE);
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                data.set(x, 0, z, Color::new(0, 255, 0));
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn sunlight_falls_until_covered() {
+        let mut world = VoxelWorld::new();
+        world.insert_chunk([0, 0, 0], floor());
+        world.insert_chunk([0, -1, 0], floor());
+        assert_eq!(world.light(5, 10, 5), (MAX_LIGHT, 0));
+        assert_eq!(world.light(5, 1, 5), (MAX_LIGHT, 0));
+        // the floor shades everything below it
+        assert_eq!(world.light(5, -5, 5).0, 0);
+
+        // a roof darkens the column under it, light creeps back in from the sides
+        for x in 4..=6 {
+            for z in 4..=6 {
+                world.set(x, 3, z, Color::new(1, 1, 1));
+            }
+        }
+        assert_eq!(world.light(5, 3, 5), (0, 0));
+        assert_eq!(world.light(5, 2, 5).0, MAX_LIGHT - 2);
+        assert_eq!(world.light(5, 4, 5).0, MAX_LIGHT);
+
+        world.set(5, 3, 5, Color::CLEAR);
+        assert_eq!(world.light(5, 1, 5).0, MAX_LIGHT);
+        assert_eq!(world.light(4, 2, 5).0, MAX_LIGHT - 1);
+    }
+
+    #[test]
+    fn emitters_fade_with_distance() {
+        let lamp = Color::new(255, 200, 0);
+        let mut world = lamp_world(lamp, 10);
+        world.insert_chunk([0, 0, 0], floor());
+        world.set(10, 1, 10, lamp);
+        assert_eq!(world.light(10, 1, 10).1, 10);
+        assert_eq!(world.light(11, 1, 10).1, 9);
+        assert_eq!(world.light(13, 2, 10).1, 6);
+        // light doesn't go through the floor, or into the chunk next door until it's loaded
+        assert_eq!(world.light(10, 0, 10).1, 0);
+
+        // a wall makes light walk around it
+        for y in 1..CHUNK_SIZE as i32 {
+            for z in 0..CHUNK_SIZE as i32 {
+                if z != 10 {
+                    world.set(12, y, z, Color::new(1, 1, 1));
+                }
+            }
+        }
+        world.set(12, 1, 10, Color::new(1, 1, 1));
+        assert_eq!(world.light(13, 2, 10).1, 6);
+        assert_eq!(world.light(13, 1, 10).1, 5);
+
+        world.set(10, 1, 10, Color::CLEAR);
+        for pos in &[[10, 1, 10], [11, 1, 10], [13, 2, 10]] {
+            assert_eq!(world.light(pos[0], pos[1], pos[2]).1, 0);
+        }
+    }
+
+    #[test]
+    fn strengths_map_to_levels() {
+        for level in 1..=MAX_LIGHT {
+            assert_eq!(emission_level(brightness(level)), level);
+        }
+        assert_eq!(emission_level(4.0), MAX_LIGHT);
+        assert_eq!(emission_level(1e-9), 1);
+        assert_eq!(emission_level(0.0), 0);
+    }
+
+    #[test]
+    fn edits_light_like_loading() {
+        let lamp = Color::new(255, 200, 0);
+        let stone = Color::new(1, 1, 1);
+        let mut edited = lamp_world(lamp, MAX_LIGHT);
+        for &coord in &[[0, 0, 0], [1, 0, 0], [0, 1, 0], [0, -1, 0]] {
+            edited.insert_chunk(coord, floor());
+        }
+        // a cave under the floor with a lamp in it, lit across the chunk border
+        for x in 20..40 {
+            for z in 0..8 {
+                edited.set(x, -3, z, stone);
+            }
+        }
+        edited.set(30, -2, 3, lamp);
+        edited.set(25, 16, 3, stone);
+        edited.set(30, -2, 3, Color::CLEAR);
+        edited.set(36, -2, 3, lamp);
+        edited.remove_chunk([0, 1, 0]);
+
+        let mut loaded = lamp_world(lamp, MAX_LIGHT);
+        let mut coords: Vec<_> = edited.pending.keys().copied().collect();
+        // loading order doesn't matter either
+        coords.sort_unstable();
+        for coord in coords.into_iter().rev() {
+            loaded.insert_chunk(coord, edited.pending[&coord].clone());
+        }
+        for x in -4..68 {
+            for y in -36..36 {
+                for z in -4..36 {
+                    assert_eq!(
+                        edited.light(x, y, z),
+                        loaded.light(x, y, z),
+                        "{:?}",
+                        [x, y, z]
+                    );
+                }
+            }
+        }
+    }
+}